@@ -0,0 +1,27 @@
+#[path = "src/shader_compiler.rs"]
+mod shader_compiler;
+
+const SHADER_DIR: &str = "shaders";
+
+//Compiles every shader in SHADER_DIR to <name>.spv in OUT_DIR, where RenderContext includes them from
+fn main() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+
+    for entry in std::fs::read_dir(SHADER_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let source = std::fs::read_to_string(&path).unwrap();
+        match shader_compiler::compile_glsl(&file_name, &source) {
+            Err(e) => {
+                panic!("Failed to compile shader:\n{}", e);
+            },
+            Ok(spv) => {
+                let out_path = std::path::Path::new(&out_dir).join(format!("{}.spv", file_name));
+                std::fs::write(out_path, spv).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,33 @@
+#version 450
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+layout(set = 0, binding = 0) uniform texture2D source;
+layout(set = 0, binding = 1) uniform sampler sourceSampler;
+
+//Layout must match BlurConstants in post.rs
+layout(push_constant) uniform constants {
+  //One texel along the blur direction, in uv units
+  vec2 direction;
+  //Only brightness above this is kept, so only the brightest parts glow
+  float threshold;
+};
+
+vec3 bright(vec2 uv) {
+  vec3 color = texture(sampler2D(source, sourceSampler), uv).rgb;
+  return max(color - vec3(threshold), vec3(0.0));
+}
+
+//9 tap gaussian blur along one axis
+void main() {
+  float weights[5] = float[5](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+  vec3 sum = bright(fragUV) * weights[0];
+  for (int i = 1; i < 5; i++) {
+    vec2 offset = direction * float(i);
+    sum += bright(fragUV + offset) * weights[i];
+    sum += bright(fragUV - offset) * weights[i];
+  }
+  outColor = vec4(sum, 1.0);
+}
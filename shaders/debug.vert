@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec2 inPos;
+layout(location = 1) in vec4 inColor;
+
+layout(location = 0) out vec4 fragColor;
+
+layout(push_constant) uniform constants {
+  mat4 transform;
+  vec4 color;
+};
+
+void main() {
+  gl_Position = transform * vec4(inPos, 0.0, 1.0);
+  fragColor = inColor * color;
+}
@@ -0,0 +1,10 @@
+#version 450
+
+layout(location = 0) out vec2 fragUV;
+
+//Draws one triangle covering the whole screen from 3 vertices with no vertex buffer
+void main() {
+  vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+  gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+  fragUV = uv;
+}
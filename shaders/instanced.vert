@@ -0,0 +1,18 @@
+#version 450
+
+layout(location = 0) in vec2 inPos;
+layout(location = 1) in vec2 instancePos;
+layout(location = 2) in vec2 instanceScale;
+layout(location = 3) in vec4 instanceColor;
+
+layout(location = 0) out vec4 fragColor;
+
+layout(push_constant) uniform constants {
+  mat4 transform;
+  vec4 color;
+};
+
+void main() {
+  gl_Position = transform * vec4(inPos * instanceScale + instancePos, 0.0, 1.0);
+  fragColor = instanceColor * color;
+}
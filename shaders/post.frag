@@ -0,0 +1,44 @@
+#version 450
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+layout(set = 0, binding = 0) uniform texture2D scene;
+layout(set = 0, binding = 1) uniform sampler sceneSampler;
+layout(set = 1, binding = 0) uniform texture2D bloom;
+layout(set = 1, binding = 1) uniform sampler bloomSampler;
+
+//Layout must match PostConstants in post.rs, an effect is off when its strength is 0
+layout(push_constant) uniform constants {
+  vec2 screenSize;
+  float bloomIntensity;
+  float scanlines;
+  float curvature;
+  float aberration;
+};
+
+void main() {
+  //Push the picture out towards the corners, like the bulge of a CRT
+  vec2 centered = fragUV * 2.0 - 1.0;
+  centered = centered * (1.0 + curvature * centered.yx * centered.yx);
+  vec2 uv = centered * 0.5 + 0.5;
+  if (uv.x < 0.0 || uv.x > 1.0 || uv.y < 0.0 || uv.y > 1.0) {
+    outColor = vec4(0.0, 0.0, 0.0, 1.0);
+    return;
+  }
+
+  //The red and blue channels drift apart towards the edges of the screen
+  vec2 shift = centered * aberration;
+  float r = texture(sampler2D(scene, sceneSampler), uv + shift).r;
+  float g = texture(sampler2D(scene, sceneSampler), uv).g;
+  float b = texture(sampler2D(scene, sceneSampler), uv - shift).b;
+  vec3 color = vec3(r, g, b);
+  color += texture(sampler2D(bloom, bloomSampler), uv).rgb * bloomIntensity;
+
+  //Darken every other row of pixels
+  float line = 0.5 + 0.5 * cos(uv.y * screenSize.y * 3.14159265);
+  color *= 1.0 - scanlines * line;
+
+  outColor = vec4(clamp(color, 0.0, 1.0), 1.0);
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+layout(set = 0, binding = 0) uniform texture2D spriteTexture;
+layout(set = 0, binding = 1) uniform sampler spriteSampler;
+
+layout(push_constant) uniform constants {
+  mat4 transform;
+  vec4 color;
+};
+
+void main() {
+    outColor = texture(sampler2D(spriteTexture, spriteSampler), fragUV) * color;
+}
@@ -0,0 +1,16 @@
+#version 450
+
+layout(location = 0) in vec2 inPos;
+layout(location = 1) in vec2 inUV;
+
+layout(location = 0) out vec2 fragUV;
+
+layout(push_constant) uniform constants {
+  mat4 transform;
+  vec4 color;
+};
+
+void main() {
+  gl_Position = transform * vec4(inPos, 0.0, 1.0);
+  fragUV = inUV;
+}
@@ -0,0 +1,14 @@
+#version 450
+
+layout(location = 0) in vec2 fragUV;
+layout(location = 1) in vec4 fragColor;
+
+layout(location = 0) out vec4 outColor;
+
+layout(set = 0, binding = 0) uniform texture2D fontAtlas;
+layout(set = 0, binding = 1) uniform sampler fontSampler;
+
+void main() {
+    float coverage = texture(sampler2D(fontAtlas, fontSampler), fragUV).r;
+    outColor = vec4(fragColor.rgb, fragColor.a * coverage);
+}
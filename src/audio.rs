@@ -0,0 +1,459 @@
+use std::sync::Arc;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+
+use crate::config::AudioSettings;
+use crate::music::MusicStream;
+
+const SAMPLE_RATE: i32 = 44100;
+//Samples per channel in each callback, small enough that effects line up with what's on screen
+const BUFFER_SAMPLES: u16 = 512;
+//Oldest voices are cut off once this many are playing
+const MAX_VOICES: usize = 16;
+//Fade in time in seconds, to avoid clicks at the start of a sound
+const ATTACK_TIME: f32 = 0.002;
+//Seconds the music takes to dip down when ducked and come back afterwards
+const DUCK_FADE_TIME: f32 = 0.1;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MusicTrack {
+    Menu,
+    Game
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SoundEffect {
+    PaddleHit,
+    WallHit,
+    Goal
+}
+
+const SOUND_EFFECTS: [SoundEffect; 3] = [SoundEffect::PaddleHit, SoundEffect::WallHit, SoundEffect::Goal];
+
+enum Waveform {
+    //Duty is the fraction of each cycle spent high
+    Square(f32),
+    //Frequency is how often the noise generator is clocked
+    Noise
+}
+
+//A tone sliding from start_freq to end_freq and fading out over its duration
+struct Blip {
+    waveform: Waveform,
+    start_freq: f32,
+    end_freq: f32,
+    duration: f32,
+    volume: f32
+}
+
+//Each effect is made from one or more blips played together
+fn blips(effect: SoundEffect) -> Vec<Blip> {
+    match effect {
+        SoundEffect::PaddleHit => {
+            vec![Blip { waveform: Waveform::Square(0.5), start_freq: 480.0, end_freq: 480.0, duration: 0.06, volume: 0.4 }]
+        },
+        SoundEffect::WallHit => {
+            vec![Blip { waveform: Waveform::Square(0.5), start_freq: 240.0, end_freq: 220.0, duration: 0.05, volume: 0.35 }]
+        },
+        SoundEffect::Goal => {
+            vec![Blip { waveform: Waveform::Square(0.25), start_freq: 520.0, end_freq: 130.0, duration: 0.5, volume: 0.3 },
+                 Blip { waveform: Waveform::Noise, start_freq: 8000.0, end_freq: 1000.0, duration: 0.35, volume: 0.3 }]
+        }
+    }
+}
+
+//Renders a sound effect as mono samples
+fn synthesize(effect: SoundEffect, sample_rate: f32) -> Vec<f32> {
+    let blips = blips(effect);
+    let length = blips.iter().map(|blip| (blip.duration * sample_rate) as usize).max().unwrap_or(0);
+    let mut samples = vec![0.0; length];
+
+    for blip in blips.iter() {
+        let count = (blip.duration * sample_rate) as usize;
+        let mut phase = 0.0;
+        //15 bit shift register like the NES noise channel, any non zero seed works
+        let mut lfsr: u16 = 1;
+        for (i, sample) in samples.iter_mut().take(count).enumerate() {
+            let t = i as f32 / count as f32;
+            //Sliding exponentially sounds like an even change in pitch
+            let freq = blip.start_freq * (blip.end_freq / blip.start_freq).powf(t);
+            phase += freq / sample_rate;
+            let value = match blip.waveform {
+                Waveform::Square(duty) => {
+                    if phase.fract() < duty { 1.0 } else { -1.0 }
+                },
+                Waveform::Noise => {
+                    while phase >= 1.0 {
+                        let bit = (lfsr ^ (lfsr >> 1)) & 1;
+                        lfsr = (lfsr >> 1) | (bit << 14);
+                        phase -= 1.0;
+                    }
+                    if lfsr & 1 == 0 { 1.0 } else { -1.0 }
+                }
+            };
+            phase = phase.fract();
+
+            let attack = (i as f32 / (ATTACK_TIME * sample_rate)).min(1.0);
+            *sample += value * blip.volume * attack * (1.0 - t);
+        }
+    }
+    samples
+}
+
+struct Voice {
+    samples: Arc<Vec<f32>>,
+    position: usize,
+    left: f32,
+    right: f32
+}
+
+//Music fades towards target, and is stopped once it has faded out
+struct MusicVoice {
+    stream: MusicStream,
+    gain: f32,
+    target: f32
+}
+
+fn approach(value: f32, target: f32, step: f32) -> f32 {
+    if value < target {
+        (value + step).min(target)
+    } else {
+        (value - step).max(target)
+    }
+}
+
+//Runs on SDL's audio thread, adding every playing voice into the output
+pub struct Mixer {
+    //Indexed by SoundEffect
+    sounds: Vec<Arc<Vec<f32>>>,
+    voices: Vec<Voice>,
+    music: Vec<MusicVoice>,
+    channels: usize,
+    volume: f32,
+    effects_volume: f32,
+    music_volume: f32,
+    //Change in music gain per frame while crossfading
+    fade_step: f32,
+    //Music is turned down to duck_volume for duck_frames more frames
+    duck: f32,
+    duck_frames: usize,
+    duck_volume: f32,
+    duck_step: f32
+}
+
+impl Mixer {
+    fn new(settings: &AudioSettings, sample_rate: f32, channels: usize) -> Mixer {
+        Mixer {
+            sounds: SOUND_EFFECTS.iter().map(|effect| Arc::new(synthesize(*effect, sample_rate))).collect(),
+            voices: Vec::with_capacity(MAX_VOICES),
+            music: Vec::new(),
+            channels,
+            volume: settings.volume,
+            effects_volume: settings.effects_volume,
+            music_volume: settings.music_volume,
+            fade_step: 1.0 / (settings.crossfade_time * sample_rate).max(1.0),
+            duck: 1.0,
+            duck_frames: 0,
+            duck_volume: settings.duck_volume,
+            duck_step: 1.0 / (DUCK_FADE_TIME * sample_rate)
+        }
+    }
+
+    //Pan goes from -1 (left) to 1 (right)
+    fn play(&mut self, effect: SoundEffect, volume: f32, pan: f32) {
+        if self.voices.len() == MAX_VOICES {
+            self.voices.remove(0);
+        }
+        //Goal jingles duck the music so they can be heard over it
+        if effect == SoundEffect::Goal {
+            self.duck_frames = self.duck_frames.max(self.sounds[effect as usize].len());
+        }
+        //Equal power panning keeps the loudness the same as a sound moves across
+        let angle = (pan.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+        self.voices.push(Voice {
+            samples: self.sounds[effect as usize].clone(),
+            position: 0,
+            left: angle.cos() * volume,
+            right: angle.sin() * volume
+        });
+    }
+
+    //Fades out whatever music is playing while the new stream fades in, None just fades out
+    fn play_music(&mut self, stream: Option<MusicStream>) {
+        for voice in self.music.iter_mut() {
+            voice.target = 0.0;
+        }
+        if let Some(stream) = stream {
+            self.music.push(MusicVoice {
+                stream,
+                gain: 0.0,
+                target: 1.0
+            });
+        }
+    }
+}
+
+impl AudioCallback for Mixer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = 0.0;
+        }
+
+        for voice in self.voices.iter_mut() {
+            for frame in out.chunks_mut(self.channels) {
+                let value = match voice.samples.get(voice.position) {
+                    None => {
+                        break;
+                    },
+                    Some(value) => {
+                        *value
+                    }
+                };
+                voice.position += 1;
+                let value = value * self.effects_volume;
+                if frame.len() == 1 {
+                    frame[0] += value * (voice.left + voice.right) * 0.5;
+                } else {
+                    frame[0] += value * voice.left;
+                    frame[1] += value * voice.right;
+                }
+            }
+        }
+        self.voices.retain(|voice| voice.position < voice.samples.len());
+
+        for frame in out.chunks_mut(self.channels) {
+            let duck_target = if self.duck_frames > 0 {
+                self.duck_frames -= 1;
+                self.duck_volume
+            } else {
+                1.0
+            };
+            self.duck = approach(self.duck, duck_target, self.duck_step);
+
+            for voice in self.music.iter_mut() {
+                voice.gain = approach(voice.gain, voice.target, self.fade_step);
+                //Nothing is played if the decoder has fallen behind, rather than waiting for it
+                if let Some((left, right)) = voice.stream.next_frame() {
+                    let gain = voice.gain * self.music_volume * self.duck;
+                    if frame.len() == 1 {
+                        frame[0] += (left + right) * 0.5 * gain;
+                    } else {
+                        frame[0] += left * gain;
+                        frame[1] += right * gain;
+                    }
+                }
+            }
+        }
+        //Dropping a stream also stops its decoding thread
+        self.music.retain(|voice| voice.target > 0.0 || voice.gain > 0.0);
+
+        for sample in out.iter_mut() {
+            *sample = (*sample * self.volume).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+struct QueuedSound {
+    effect: SoundEffect,
+    volume: f32,
+    pan: f32
+}
+
+//Sounds to start this frame, any system can add to it and they're handed to the mixer after the frame systems run
+#[derive(Default)]
+pub struct SoundQueue {
+    sounds: Vec<QueuedSound>
+}
+
+impl SoundQueue {
+    //Volume is from 0 to 1, pan from -1 (left) to 1 (right)
+    pub fn play(&mut self, effect: SoundEffect, volume: f32, pan: f32) {
+        self.sounds.push(QueuedSound {
+            effect,
+            volume,
+            pan
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.sounds.clear();
+    }
+}
+
+//Owns the SDL audio device, which has to stay on the main thread
+//Runs with SDL_AUDIODRIVER=dummy, where the mixer still runs but nothing is heard
+pub struct Audio {
+    device: AudioDevice<Mixer>,
+    settings: AudioSettings,
+    music: Option<MusicTrack>
+}
+
+impl Audio {
+    pub fn new(sdl_context: &sdl2::Sdl, settings: &AudioSettings) -> Result<Audio, String> {
+        let audio_subsystem = sdl_context.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(BUFFER_SAMPLES)
+        };
+
+        let device = audio_subsystem.open_playback(None, &desired, |spec| {
+            Mixer::new(settings, spec.freq as f32, spec.channels.max(1) as usize)
+        })?;
+        println!("Opened audio device using the {} driver", audio_subsystem.current_audio_driver());
+        device.resume();
+
+        Ok(Audio {
+            device,
+            settings: settings.clone(),
+            music: None
+        })
+    }
+
+    //Crossfades to the track, does nothing if it's already playing
+    pub fn play_music(&mut self, track: MusicTrack) {
+        if self.music == Some(track) {
+            return;
+        }
+        self.music = Some(track);
+
+        let settings = match track {
+            MusicTrack::Menu => &self.settings.menu_music,
+            MusicTrack::Game => &self.settings.game_music
+        };
+        //Tracks without a file just fade out the current music
+        let stream = match settings {
+            None => {
+                None
+            },
+            Some(settings) => {
+                match MusicStream::open(settings, self.device.spec().freq as u32) {
+                    Err(e) => {
+                        println!("Failed to play music {}", e);
+                        None
+                    },
+                    Ok(stream) => {
+                        Some(stream)
+                    }
+                }
+            }
+        };
+        self.device.lock().play_music(stream);
+    }
+
+    //Starts every queued sound and empties the queue
+    pub fn play_queued(&mut self, queue: &mut SoundQueue) {
+        if queue.sounds.is_empty() {
+            return;
+        }
+        let mut mixer = self.device.lock();
+        for sound in queue.sounds.drain(..) {
+            mixer.play(sound.effect, sound.volume, sound.pan);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_RATE: f32 = 44100.0;
+
+    fn test_mixer(channels: usize) -> Mixer {
+        let settings = AudioSettings {
+            volume: 1.0,
+            effects_volume: 1.0,
+            ..AudioSettings::default()
+        };
+        Mixer::new(&settings, TEST_RATE, channels)
+    }
+
+    #[test]
+    fn synthesize_lengths_and_levels() {
+        for effect in SOUND_EFFECTS.iter() {
+            let samples = synthesize(*effect, TEST_RATE);
+            let longest = blips(*effect).iter().map(|blip| blip.duration).fold(0.0, f32::max);
+            assert_eq!(samples.len(), (longest * TEST_RATE) as usize);
+            let loudest: f32 = blips(*effect).iter().map(|blip| blip.volume).sum();
+            assert!(samples.iter().all(|sample| sample.abs() <= loudest), "{:?} is louder than its blips", effect);
+            //Faded in and out, so there's no click at either end
+            assert_eq!(samples[0], 0.0);
+            assert!(samples.last().unwrap().abs() < 0.01);
+            assert!(samples.iter().any(|sample| sample.abs() > 0.1), "{:?} is silent", effect);
+        }
+    }
+
+    #[test]
+    fn panning() {
+        let mut out = vec![0.0; 256];
+        let mut mixer = test_mixer(2);
+        mixer.play(SoundEffect::PaddleHit, 1.0, -1.0);
+        mixer.callback(&mut out);
+        assert!(out.chunks(2).all(|frame| frame[1].abs() < 1e-6), "Panned left but heard on the right");
+        assert!(out.chunks(2).any(|frame| frame[0].abs() > 0.1));
+
+        let mut mixer = test_mixer(2);
+        mixer.play(SoundEffect::PaddleHit, 1.0, 1.0);
+        mixer.callback(&mut out);
+        assert!(out.chunks(2).all(|frame| frame[0].abs() < 1e-6), "Panned right but heard on the left");
+
+        let mut mixer = test_mixer(2);
+        mixer.play(SoundEffect::PaddleHit, 1.0, 0.0);
+        mixer.callback(&mut out);
+        assert!(out.chunks(2).all(|frame| (frame[0] - frame[1]).abs() < 1e-6));
+    }
+
+    #[test]
+    fn mono_output() {
+        let mut out = vec![0.0; 128];
+        let mut mixer = test_mixer(1);
+        mixer.play(SoundEffect::WallHit, 1.0, -1.0);
+        mixer.callback(&mut out);
+        assert!(out.iter().any(|sample| sample.abs() > 0.1), "Panned sounds are lost in mono");
+    }
+
+    #[test]
+    fn voice_limit() {
+        let mut mixer = test_mixer(2);
+        for _ in 0..MAX_VOICES + 5 {
+            mixer.play(SoundEffect::Goal, 1.0, 0.0);
+        }
+        assert_eq!(mixer.voices.len(), MAX_VOICES);
+    }
+
+    #[test]
+    fn finished_voices_are_removed() {
+        let mut mixer = test_mixer(2);
+        mixer.play(SoundEffect::WallHit, 1.0, 0.0);
+        let mut out = vec![0.0; synthesize(SoundEffect::WallHit, TEST_RATE).len() * 2];
+        mixer.callback(&mut out);
+        assert!(mixer.voices.is_empty());
+    }
+
+    #[test]
+    fn output_is_clamped() {
+        let mut out = vec![0.0; 1024];
+        let mut mixer = test_mixer(2);
+        for _ in 0..MAX_VOICES {
+            mixer.play(SoundEffect::PaddleHit, 10.0, 0.0);
+        }
+        mixer.callback(&mut out);
+        assert!(out.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        assert!(out.iter().any(|sample| sample.abs() == 1.0), "Nothing was loud enough to clamp");
+    }
+
+    //The dummy driver needs no sound card, so this runs anywhere SDL is installed
+    #[test]
+    fn open_dummy_device() {
+        std::env::set_var("SDL_AUDIODRIVER", "dummy");
+        let sdl_context = sdl2::init().unwrap();
+        let mut audio = Audio::new(&sdl_context, &AudioSettings::default()).unwrap();
+        let mut queue = SoundQueue::default();
+        queue.play(SoundEffect::Goal, 1.0, 0.5);
+        audio.play_queued(&mut queue);
+        assert!(queue.sounds.is_empty());
+        audio.play_music(MusicTrack::Game);
+    }
+}
@@ -0,0 +1,83 @@
+use specs::{World, Builder, Entity, RunNow};
+
+use crate::fy_math::{Vec2, Color, TransformComponent};
+use crate::mesh::Vertex;
+use crate::render::{RenderContext, RenderComponent, Instanced};
+use crate::camera::{ARENA_WIDTH, ARENA_HEIGHT};
+
+const WARMUP_FRAMES: u32 = 30;
+const BENCH_FRAMES: u32 = 300;
+
+const QUAD_SIZE: f32 = 0.06;
+
+const QUAD_VERTICES: [Vertex; 4] = [Vertex { position: Vec2{ x: -QUAD_SIZE, y: QUAD_SIZE}, uv: Vec2{ x: 0.0, y: 1.0} },
+                                    Vertex { position: Vec2{ x: QUAD_SIZE, y: QUAD_SIZE}, uv: Vec2{ x: 1.0, y: 1.0} },
+                                    Vertex { position: Vec2{ x: QUAD_SIZE, y: -QUAD_SIZE}, uv: Vec2{ x: 1.0, y: 0.0} },
+                                    Vertex { position: Vec2{ x: -QUAD_SIZE, y: -QUAD_SIZE}, uv: Vec2{ x: 0.0, y: 0.0} }];
+const QUAD_INDICES: [u32; 6] = [0,1,2,0,2,3];
+
+//Spreads count quads out in a grid covering the arena
+fn grid_position(idx: usize, count: usize) -> Vec2 {
+    let columns = (count as f32).sqrt().ceil() as usize;
+    let step_x = ARENA_WIDTH / columns as f32;
+    let step_y = ARENA_HEIGHT / columns as f32;
+    let x = (idx % columns) as f32 * step_x - ARENA_WIDTH / 2.0 + step_x / 2.0;
+    let y = (idx / columns) as f32 * step_y - ARENA_HEIGHT / 2.0 + step_y / 2.0;
+    Vec2::new(x, y)
+}
+
+fn grid_color(idx: usize, count: usize) -> Color {
+    let t = idx as f32 / count as f32;
+    Color { r: t, g: 1.0 - t, b: 0.5, a: 1.0 }
+}
+
+//Average time spent per frame in the renderer, in milliseconds
+fn time_frames(world: &mut World, renderer: &mut RenderContext) -> f64 {
+    for _ in 0..WARMUP_FRAMES {
+        renderer.run_now(&world.res);
+    }
+    let start = std::time::Instant::now();
+    for _ in 0..BENCH_FRAMES {
+        renderer.run_now(&world.res);
+    }
+    start.elapsed().as_secs_f64() * 1000.0 / BENCH_FRAMES as f64
+}
+
+//Compares per-entity command recording against instanced batches for the same number of quads
+pub fn run_render_benchmark(world: &mut World, renderer: &mut RenderContext, count: usize) {
+    println!("Benchmarking {} quads over {} frames", count, BENCH_FRAMES);
+
+    let start = std::time::Instant::now();
+    let mesh = renderer.create_mesh(&QUAD_VERTICES, &QUAD_INDICES);
+    let entities: Vec<Entity> = (0..count).map(|i| {
+        let transform = TransformComponent {
+            position: grid_position(i, count)
+        };
+        let model = RenderComponent::with_color(mesh, grid_color(i, count));
+        world.create_entity().with(transform).with(model).build()
+    }).collect();
+    let per_entity_setup = start.elapsed().as_secs_f64() * 1000.0;
+    let per_entity = time_frames(world, renderer);
+    world.delete_entities(&entities).unwrap();
+    world.maintain();
+
+    let start = std::time::Instant::now();
+    let entities: Vec<Entity> = (0..count).map(|i| {
+        let transform = TransformComponent {
+            position: grid_position(i, count)
+        };
+        let instanced = Instanced {
+            mesh,
+            scale: Vec2::new(1.0, 1.0),
+            color: grid_color(i, count)
+        };
+        world.create_entity().with(transform).with(instanced).build()
+    }).collect();
+    let instanced_setup = start.elapsed().as_secs_f64() * 1000.0;
+    let instanced = time_frames(world, renderer);
+    world.delete_entities(&entities).unwrap();
+    world.maintain();
+
+    println!("Per-entity (par_join): {:.3}ms per frame, {:.1}ms setup", per_entity, per_entity_setup);
+    println!("Instanced batches:     {:.3}ms per frame, {:.1}ms setup", instanced, instanced_setup);
+}
@@ -0,0 +1,79 @@
+use rand::{thread_rng, Rng};
+
+use crate::fy_math::{Vec2, Vec4, Mat4};
+
+//The playing field in world units, centered on the origin with +y pointing down the screen
+pub const ARENA_WIDTH: f32 = 16.0;
+pub const ARENA_HEIGHT: f32 = 12.0;
+
+//Largest distance the view is moved by a shake at full strength, in world units
+const MAX_SHAKE_OFFSET: f32 = 0.3;
+
+//Shake strength lost per second
+const SHAKE_DECAY: f32 = 2.0;
+
+//Largest rectangle with the arena's aspect ratio that fits in the window, centered with black bars around it
+//Returns the top left corner and size in pixels
+pub fn letterbox(window_width: u32, window_height: u32) -> (Vec2, Vec2) {
+    let scale = (window_width as f32 / ARENA_WIDTH).min(window_height as f32 / ARENA_HEIGHT);
+    let size = Vec2::new((ARENA_WIDTH * scale).round(), (ARENA_HEIGHT * scale).round());
+    let offset = Vec2::new(((window_width as f32 - size.x) / 2.0).floor(), ((window_height as f32 - size.y) / 2.0).floor());
+    (offset, size)
+}
+
+//Orthographic camera looking at the arena, game logic works purely in world units
+pub struct Camera {
+    pub center: Vec2,
+    //Values above 1 zoom in, showing less of the arena
+    pub zoom: f32,
+    window_size: Vec2,
+    shake: f32,
+    shake_offset: Vec2
+}
+
+impl Camera {
+    pub fn new(window_width: u32, window_height: u32) -> Camera {
+        Camera {
+            center: Vec2::new(0.0, 0.0),
+            zoom: 1.0,
+            window_size: Vec2::new(window_width as f32, window_height as f32),
+            shake: 0.0,
+            shake_offset: Vec2::new(0.0, 0.0)
+        }
+    }
+
+    //Adds to the current shake strength, which is clamped to 1 and decays over time
+    pub fn shake(&mut self, amount: f32) {
+        self.shake = (self.shake + amount).min(1.0);
+    }
+
+    //Decays the shake and picks a new random offset, should be called once per frame
+    pub fn update(&mut self, frame_time: f32) {
+        self.shake = (self.shake - SHAKE_DECAY * frame_time).max(0.0);
+        //Squaring the strength makes small shakes subtle and big ones violent
+        let strength = self.shake * self.shake * MAX_SHAKE_OFFSET;
+        let mut rng = thread_rng();
+        self.shake_offset = Vec2::new(rng.gen_range(-1.0, 1.0) * strength, rng.gen_range(-1.0, 1.0) * strength);
+    }
+
+    //Maps world units to clip space within the letterboxed viewport
+    pub fn view_projection(&self) -> Mat4 {
+        let center = self.center + self.shake_offset;
+        let scale_x = 2.0 * self.zoom / ARENA_WIDTH;
+        let scale_y = 2.0 * self.zoom / ARENA_HEIGHT;
+        Mat4 {
+            x: Vec4 { x: scale_x, y: 0.0, z: 0.0, w: 0.0 },
+            y: Vec4 { x: 0.0, y: scale_y, z: 0.0, w: 0.0 },
+            z: Vec4 { x: 0.0, y: 0.0, z: 1.0, w: 0.0 },
+            w: Vec4 { x: -center.x * scale_x, y: -center.y * scale_y, z: 0.0, w: 1.0 }
+        }
+    }
+
+    //Converts a world position to pixels from the top left of the window, for placing HUD elements
+    pub fn world_to_screen(&self, position: Vec2) -> Vec2 {
+        let (offset, size) = letterbox(self.window_size.x as u32, self.window_size.y as u32);
+        let relative = Vec2::new((position.x - self.center.x) * self.zoom / ARENA_WIDTH + 0.5,
+                                 (position.y - self.center.y) * self.zoom / ARENA_HEIGHT + 0.5);
+        Vec2::new(offset.x + relative.x * size.x, offset.y + relative.y * size.y)
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use crate::config::CaptureSettings;
+use crate::sprite;
+
+//Tightly packed RGBA8 pixels read back from the screen
+#[derive(Clone)]
+pub struct CapturedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+//Work for the writer thread, so encoding and disk writes never hold up a frame
+enum Job {
+    Screenshot(String, CapturedImage),
+    StartRecording(Recording),
+    //The frame is written this many times, to keep the recording at a fixed rate when the game runs slower
+    Frame(CapturedImage, u32),
+    StopRecording
+}
+
+enum Recording {
+    //Every frame is saved as a numbered PNG in the folder
+    Frames {
+        directory: String,
+        next_frame: u32
+    },
+    //Raw frames are piped to the encoder's stdin, it is started once the frame size is known
+    Encoder {
+        command: String,
+        fps: u32,
+        process: Option<std::process::Child>
+    }
+}
+
+//Frame the renderer was asked to capture, which arrives a frame later
+struct PendingCapture {
+    screenshot: bool,
+    repeats: u32
+}
+
+//Handles the screenshot and recording hotkeys, deciding which frames to capture and handing them to the writer thread
+pub struct Capture {
+    settings: CaptureSettings,
+    jobs: Sender<Job>,
+    worker: std::thread::JoinHandle<()>,
+    recording: bool,
+    //Recorded frames owed since the last capture, at settings.record_fps
+    frames_due: f32,
+    screenshot_requested: bool,
+    pending: VecDeque<PendingCapture>
+}
+
+//Seconds since the unix epoch, used to give captures unique names
+pub fn timestamp() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+impl Capture {
+    pub fn new(settings: &CaptureSettings) -> Capture {
+        let (jobs, receiver) = channel();
+        let worker = std::thread::spawn(move || run_worker(receiver));
+        Capture {
+            settings: settings.clone(),
+            jobs,
+            worker,
+            recording: false,
+            frames_due: 0.0,
+            screenshot_requested: false,
+            pending: VecDeque::new()
+        }
+    }
+
+    //Saves the next frame as a PNG
+    pub fn screenshot(&mut self) {
+        self.screenshot_requested = true;
+    }
+
+    pub fn toggle_recording(&mut self) {
+        if self.recording {
+            println!("Stopped recording");
+            self.jobs.send(Job::StopRecording).unwrap();
+            self.recording = false;
+            return;
+        }
+
+        let recording = match &self.settings.encoder {
+            None => {
+                let directory = format!("{}/recording-{}", self.settings.directory, timestamp());
+                if let Err(e) = std::fs::create_dir_all(&directory) {
+                    println!("Failed to create {}: {}", directory, e);
+                    return;
+                }
+                println!("Recording frames to {}", directory);
+                Recording::Frames {
+                    directory,
+                    next_frame: 0
+                }
+            },
+            Some(command) => {
+                println!("Recording with {}", command);
+                Recording::Encoder {
+                    command: command.clone(),
+                    fps: self.settings.record_fps,
+                    process: None
+                }
+            }
+        };
+        self.jobs.send(Job::StartRecording(recording)).unwrap();
+        self.recording = true;
+        //Capture the first frame straight away
+        self.frames_due = 1.0;
+    }
+
+    //Called once per frame, returns true if the renderer should capture this frame
+    pub fn update(&mut self, frame_time: f32) -> bool {
+        let repeats = if self.recording {
+            self.frames_due += frame_time * self.settings.record_fps as f32;
+            let repeats = self.frames_due.floor();
+            self.frames_due -= repeats;
+            repeats as u32
+        } else {
+            0
+        };
+        let screenshot = self.screenshot_requested;
+        self.screenshot_requested = false;
+
+        if !screenshot && repeats == 0 {
+            return false;
+        }
+        self.pending.push_back(PendingCapture {
+            screenshot,
+            repeats
+        });
+        true
+    }
+
+    //Passes a frame captured by the renderer on to the writer, frames arrive in the order they were requested
+    pub fn frame_captured(&mut self, image: CapturedImage) {
+        let pending = match self.pending.pop_front() {
+            None => {
+                return;
+            },
+            Some(pending) => {
+                pending
+            }
+        };
+
+        if pending.screenshot {
+            if let Err(e) = std::fs::create_dir_all(&self.settings.directory) {
+                println!("Failed to create {}: {}", self.settings.directory, e);
+            } else {
+                //Several screenshots can be taken in the same second, so the name is made unique with a counter
+                let stem = format!("{}/screenshot-{}", self.settings.directory, timestamp());
+                let mut path = format!("{}.png", stem);
+                let mut count = 1;
+                while std::path::Path::new(&path).exists() {
+                    count += 1;
+                    path = format!("{}-{}.png", stem, count);
+                }
+                self.jobs.send(Job::Screenshot(path, image.clone())).unwrap();
+            }
+        }
+        //Frames requested before recording stopped are dropped
+        if pending.repeats > 0 && self.recording {
+            self.jobs.send(Job::Frame(image, pending.repeats)).unwrap();
+        }
+    }
+
+    //Finishes any recording and waits for everything to be written, should be called before exiting
+    pub fn finish(self) {
+        drop(self.jobs);
+        self.worker.join().unwrap();
+    }
+}
+
+impl Recording {
+    //Returns false if writing failed, which ends the recording
+    fn write(&mut self, image: &CapturedImage, repeats: u32) -> bool {
+        match self {
+            Recording::Frames { directory, next_frame } => {
+                for _ in 0..repeats {
+                    let path = format!("{}/frame-{:06}.png", directory, next_frame);
+                    if let Err(e) = sprite::save_png(&path, image.width, image.height, &image.pixels) {
+                        println!("{}", e);
+                        return false;
+                    }
+                    *next_frame += 1;
+                }
+                true
+            },
+            Recording::Encoder { command, fps, process } => {
+                if process.is_none() {
+                    //Arguments are split on whitespace, {width}, {height} and {fps} are filled in first
+                    let command = command.replace("{width}", &image.width.to_string())
+                                         .replace("{height}", &image.height.to_string())
+                                         .replace("{fps}", &fps.to_string());
+                    let mut args = command.split_whitespace();
+                    let program = match args.next() {
+                        None => {
+                            println!("Encoder command is empty");
+                            return false;
+                        },
+                        Some(program) => {
+                            program
+                        }
+                    };
+                    match std::process::Command::new(program).args(args).stdin(std::process::Stdio::piped()).spawn() {
+                        Err(e) => {
+                            println!("Failed to start encoder {}: {}", program, e);
+                            return false;
+                        },
+                        Ok(child) => {
+                            *process = Some(child);
+                        }
+                    }
+                }
+
+                let stdin = process.as_mut().unwrap().stdin.as_mut().unwrap();
+                for _ in 0..repeats {
+                    if let Err(e) = stdin.write_all(&image.pixels) {
+                        println!("Failed to write to encoder: {}", e);
+                        return false;
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    fn finish(self) {
+        match self {
+            Recording::Frames { directory, next_frame } => {
+                println!("Saved {} frames to {}", next_frame, directory);
+            },
+            Recording::Encoder { process, .. } => {
+                //Closing stdin tells the encoder there are no more frames
+                if let Some(mut process) = process {
+                    drop(process.stdin.take());
+                    if let Err(e) = process.wait() {
+                        println!("Encoder failed: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn run_worker(jobs: Receiver<Job>) {
+    let mut recording: Option<Recording> = None;
+    for job in jobs.iter() {
+        match job {
+            Job::Screenshot(path, image) => {
+                match sprite::save_png(&path, image.width, image.height, &image.pixels) {
+                    Err(e) => {
+                        println!("{}", e);
+                    },
+                    Ok(()) => {
+                        println!("Saved screenshot {}", path);
+                    }
+                }
+            },
+            Job::StartRecording(new_recording) => {
+                if let Some(old) = recording.replace(new_recording) {
+                    old.finish();
+                }
+            },
+            Job::Frame(image, repeats) => {
+                let failed = match recording.as_mut() {
+                    None => false,
+                    Some(recording) => !recording.write(&image, repeats)
+                };
+                if failed {
+                    println!("Recording stopped");
+                    recording.take().unwrap().finish();
+                }
+            },
+            Job::StopRecording => {
+                if let Some(recording) = recording.take() {
+                    recording.finish();
+                }
+            }
+        }
+    }
+
+    if let Some(recording) = recording.take() {
+        recording.finish();
+    }
+}
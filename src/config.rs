@@ -0,0 +1,441 @@
+use serde::{Deserialize, Serialize};
+
+const SETTINGS_PATH: &str = "settings.ron";
+
+//Maps to the Vulkan present modes, unsupported modes fall back to FIFO
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+pub enum VsyncMode {
+    On,         //FIFO
+    Off,        //IMMEDIATE, or MAILBOX if tearing isn't supported
+    Mailbox,    //MAILBOX
+    Adaptive    //FIFO_RELAXED
+}
+
+//What drives a player's paddle when no controller has joined that slot
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+pub enum Fallback {
+    Ai,
+    Keyboard    //Up/Down for player 1, W/S for player 2
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct VideoSettings {
+    pub vsync: VsyncMode,
+    pub frame_limit: Option<u32>,
+    pub show_fps: bool,
+    //Samples per pixel for anti-aliasing, 1 turns it off. Lowered to the most the GPU supports
+    pub msaa: u32,
+    //Width and height in pixels, the arena is letterboxed if the aspect ratio isn't 4:3
+    pub window_size: (u32, u32)
+}
+
+impl Default for VideoSettings {
+    fn default() -> VideoSettings {
+        VideoSettings {
+            vsync: VsyncMode::On,
+            frame_limit: None,
+            show_fps: false,
+            msaa: 4,
+            window_size: (640, 480)
+        }
+    }
+}
+
+//Paths to PNG files, anything left as None is drawn as a plain colored quad
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SkinSettings {
+    pub ball: Option<String>,
+    pub paddle: Option<String>,
+    pub background: Option<String>
+}
+
+//Full screen effects applied to the finished frame before it's presented
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PostSettings {
+    pub bloom: bool,
+    pub scanlines: bool,
+    pub crt_curvature: bool,
+    pub chromatic_aberration: bool
+}
+
+impl Default for PostSettings {
+    fn default() -> PostSettings {
+        PostSettings {
+            bloom: true,
+            scanlines: true,
+            crt_curvature: true,
+            chromatic_aberration: true
+        }
+    }
+}
+
+//Screenshots are taken with F12, recording is toggled with F9
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct CaptureSettings {
+    //Folder screenshots and recorded frames are saved in
+    pub directory: String,
+    //Frames per second written while recording, independent of the game's frame rate
+    pub record_fps: u32,
+    //Command raw RGBA frames are piped into while recording, frames are saved as PNGs instead if None
+    //{width}, {height} and {fps} are replaced with the frame size and rate
+    pub encoder: Option<String>
+}
+
+impl Default for CaptureSettings {
+    fn default() -> CaptureSettings {
+        CaptureSettings {
+            directory: "captures".to_string(),
+            record_fps: 30,
+            encoder: None
+        }
+    }
+}
+
+//A WAV or OGG file streamed as background music
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TrackSettings {
+    pub path: String,
+    //Frames to jump back to when the track loops, and to loop at instead of the end of the file
+    //Read from LOOPSTART and LOOPLENGTH tags in OGG files if not set
+    pub loop_start: Option<u64>,
+    pub loop_end: Option<u64>
+}
+
+//Volumes go from 0 (silent) to 1 (full volume)
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub enabled: bool,
+    pub volume: f32,
+    pub effects_volume: f32,
+    pub music_volume: f32,
+    //Seconds to fade between music tracks
+    pub crossfade_time: f32,
+    //Music volume while a goal jingle plays, as a fraction of music_volume
+    pub duck_volume: f32,
+    pub menu_music: Option<TrackSettings>,
+    pub game_music: Option<TrackSettings>
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            enabled: true,
+            volume: 0.8,
+            effects_volume: 1.0,
+            music_volume: 0.6,
+            crossfade_time: 1.5,
+            duck_volume: 0.3,
+            menu_music: None,
+            game_music: None
+        }
+    }
+}
+
+//Controllers join by pressing A or Start, and take the first slot without a controller
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PlayerSettings {
+    //Used for empty slots and when a player's controller is unplugged
+    pub fallback: Fallback
+}
+
+impl Default for PlayerSettings {
+    fn default() -> PlayerSettings {
+        PlayerSettings {
+            fallback: Fallback::Ai
+        }
+    }
+}
+
+//Matches played on a pong-server or watched, delays are in seconds
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct OnlineSettings {
+    //How far behind the server the match is shown, so there's a newer state to interpolate towards
+    pub interpolation_delay: f32,
+    //How far behind spectators watch, a longer buffer hides more of a bad connection
+    pub spectator_delay: f32
+}
+
+impl Default for OnlineSettings {
+    fn default() -> OnlineSettings {
+        OnlineSettings {
+            interpolation_delay: 0.1,
+            spectator_delay: 2.0
+        }
+    }
+}
+
+//Every match is recorded so it can be watched again with --replay
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReplaySettings {
+    pub record: bool,
+    //Folder replays are saved in when the game closes
+    pub directory: String
+}
+
+impl Default for ReplaySettings {
+    fn default() -> ReplaySettings {
+        ReplaySettings {
+            record: true,
+            directory: "replays".to_string()
+        }
+    }
+}
+
+//Controller vibration on hits, goals and wins
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct HapticsSettings {
+    pub enabled: bool,
+    //Strength for each player from 0 (off) to 1, indexed by player
+    pub intensity: Vec<f32>
+}
+
+impl Default for HapticsSettings {
+    fn default() -> HapticsSettings {
+        HapticsSettings {
+            enabled: true,
+            intensity: vec![1.0, 1.0]
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub video: VideoSettings,
+    pub post: PostSettings,
+    pub audio: AudioSettings,
+    pub players: PlayerSettings,
+    pub online: OnlineSettings,
+    pub haptics: HapticsSettings,
+    pub capture: CaptureSettings,
+    pub replays: ReplaySettings,
+    pub skins: SkinSettings
+}
+
+//Options that only apply to a single run, passed on the command line
+#[derive(Default)]
+pub struct CommandLine {
+    //Number of quads to draw in the render benchmark, runs the benchmark instead of the game if set
+    pub bench_render: Option<usize>,
+    //Recompile shaders when their sources change, needs the shader-hot-reload feature
+    pub watch_shaders: bool,
+    //Start recording as soon as the game starts
+    pub record: bool,
+    //Wait for another player to join an online match on this port
+    pub host: Option<u16>,
+    //Address of a host to play an online match against
+    pub join: Option<String>,
+    //Ticks of input delay when hosting, the joining player uses the host's
+    pub input_delay: Option<u32>,
+    //Most ticks to predict ahead of the other player when hosting, 0 plays in lockstep
+    pub max_rollback: Option<u32>,
+    //Seconds to wait for another player to join when hosting
+    pub host_timeout: Option<u32>,
+    //Address of a pong-server to play on
+    pub server: Option<String>,
+    //Address of a pong-server or netplay host to watch a match on
+    pub spectate: Option<String>,
+    //Room to join on the server, players in the same room play each other
+    pub room: Option<String>,
+    //Name other players see for this game in their lobby when hosting
+    pub name: Option<String>,
+    //Pick a game on the local network to join before the match starts
+    pub lobby: bool,
+    //Seconds to listen for games on the local network and print them, runs instead of the game if set
+    pub discover: Option<u32>,
+    //Replay file to watch instead of playing
+    pub replay: Option<String>,
+    //Replay file, or folder of them, to check still plays out the same, runs instead of the game if set
+    pub verify_replay: Option<String>
+}
+
+const DEFAULT_BENCH_QUADS: usize = 2000;
+const DEFAULT_DISCOVER_SECONDS: u32 = 3;
+
+impl CommandLine {
+    pub fn parse() -> CommandLine {
+        let mut options = CommandLine::default();
+        let mut args = std::env::args().skip(1).peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bench-render" => {
+                    let count = match args.peek().and_then(|count| count.parse().ok()) {
+                        None => DEFAULT_BENCH_QUADS,
+                        Some(count) => {
+                            args.next();
+                            count
+                        }
+                    };
+                    options.bench_render = Some(count);
+                },
+                "--watch-shaders" => {
+                    options.watch_shaders = true;
+                },
+                "--record" => {
+                    options.record = true;
+                },
+                "--host" => {
+                    let port = match args.peek().and_then(|port| port.parse().ok()) {
+                        None => crate::netplay::DEFAULT_PORT,
+                        Some(port) => {
+                            args.next();
+                            port
+                        }
+                    };
+                    options.host = Some(port);
+                },
+                "--join" => {
+                    match args.next() {
+                        None => {
+                            println!("--join needs an address to connect to");
+                        },
+                        Some(address) => {
+                            options.join = Some(address);
+                        }
+                    }
+                },
+                "--input-delay" => {
+                    match args.next().and_then(|delay| delay.parse().ok()) {
+                        None => {
+                            println!("--input-delay needs a number of ticks");
+                        },
+                        Some(delay) => {
+                            options.input_delay = Some(delay);
+                        }
+                    }
+                },
+                "--rollback" => {
+                    match args.next().and_then(|ticks| ticks.parse().ok()) {
+                        None => {
+                            println!("--rollback needs a number of ticks");
+                        },
+                        Some(ticks) => {
+                            options.max_rollback = Some(ticks);
+                        }
+                    }
+                },
+                "--host-timeout" => {
+                    match args.next().and_then(|seconds| seconds.parse().ok()) {
+                        None => {
+                            println!("--host-timeout needs a number of seconds");
+                        },
+                        Some(seconds) => {
+                            options.host_timeout = Some(seconds);
+                        }
+                    }
+                },
+                "--server" => {
+                    match args.next() {
+                        None => {
+                            println!("--server needs an address to connect to");
+                        },
+                        Some(address) => {
+                            options.server = Some(address);
+                        }
+                    }
+                },
+                "--spectate" => {
+                    match args.next() {
+                        None => {
+                            println!("--spectate needs an address to connect to");
+                        },
+                        Some(address) => {
+                            options.spectate = Some(address);
+                        }
+                    }
+                },
+                "--room" => {
+                    match args.next() {
+                        None => {
+                            println!("--room needs a room name");
+                        },
+                        Some(room) => {
+                            options.room = Some(room);
+                        }
+                    }
+                },
+                "--name" => {
+                    match args.next() {
+                        None => {
+                            println!("--name needs a name to announce");
+                        },
+                        Some(name) => {
+                            options.name = Some(name);
+                        }
+                    }
+                },
+                "--replay" => {
+                    match args.next() {
+                        None => {
+                            println!("--replay needs a replay file to play");
+                        },
+                        Some(path) => {
+                            options.replay = Some(path);
+                        }
+                    }
+                },
+                "--verify-replay" => {
+                    match args.next() {
+                        None => {
+                            println!("--verify-replay needs a replay file or folder to check");
+                        },
+                        Some(path) => {
+                            options.verify_replay = Some(path);
+                        }
+                    }
+                },
+                "--lobby" => {
+                    options.lobby = true;
+                },
+                "--discover" => {
+                    let seconds = match args.peek().and_then(|seconds| seconds.parse().ok()) {
+                        None => DEFAULT_DISCOVER_SECONDS,
+                        Some(seconds) => {
+                            args.next();
+                            seconds
+                        }
+                    };
+                    options.discover = Some(seconds);
+                },
+                _ => {
+                    println!("Ignoring unknown argument {}", arg);
+                }
+            }
+        }
+        options
+    }
+}
+
+impl Settings {
+    //Missing or malformed settings files aren't fatal, the defaults are used instead
+    pub fn load() -> Settings {
+        let contents = match std::fs::read_to_string(SETTINGS_PATH) {
+            Err(_) => {
+                return Settings::default();
+            },
+            Ok(contents) => {
+                contents
+            }
+        };
+
+        match ron::de::from_str(&contents) {
+            Err(e) => {
+                println!("Failed to parse {}: {}, using default settings", SETTINGS_PATH, e);
+                Settings::default()
+            },
+            Ok(settings) => {
+                settings
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use specs::{System, Write, ReadStorage};
+
+use crate::fy_math::{Vec2, Color, TransformComponent};
+use crate::physics::PhysicsComponent;
+
+//Lines drawn past this many in a frame are dropped
+pub const MAX_DEBUG_VERTICES: usize = 65536;
+
+//Arrow heads are this fraction of the arrow's length, angled back from the tip
+const ARROW_HEAD_SIZE: f32 = 0.25;
+const ARROW_HEAD_ANGLE: f32 = 2.6;
+
+//Velocities are drawn as the distance travelled in this many seconds
+const VELOCITY_SCALE: f32 = 0.25;
+//Length of the collision normal arrows in world units
+const MTV_LENGTH: f32 = 0.6;
+
+const BOX_COLOR: Color = Color { r: 0.2, g: 1.0, b: 0.2, a: 1.0 };
+const COLLIDING_BOX_COLOR: Color = Color { r: 1.0, g: 0.2, b: 0.2, a: 1.0 };
+const VELOCITY_COLOR: Color = Color { r: 1.0, g: 1.0, b: 0.2, a: 1.0 };
+const MTV_COLOR: Color = Color { r: 1.0, g: 0.2, b: 1.0, a: 1.0 };
+
+//Layout must match the vertex inputs in debug.vert
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DebugVertex {
+    pub position: Vec2,
+    pub color: Color
+}
+
+//Lines in world units drawn on top of the scene for this frame only, any system can add to it
+//Nothing is collected while disabled, so callers don't need to check
+#[derive(Default)]
+pub struct DebugDraw {
+    pub enabled: bool,
+    vertices: Vec<DebugVertex>
+}
+
+impl DebugDraw {
+    pub fn line(&mut self, start: Vec2, end: Vec2, color: Color) {
+        if !self.enabled || self.vertices.len() + 2 > MAX_DEBUG_VERTICES {
+            return;
+        }
+        self.vertices.push(DebugVertex { position: start, color });
+        self.vertices.push(DebugVertex { position: end, color });
+    }
+
+    //Outline of the box between two opposite corners
+    pub fn rect(&mut self, min: Vec2, max: Vec2, color: Color) {
+        let top_right = Vec2::new(max.x, min.y);
+        let bot_left = Vec2::new(min.x, max.y);
+        self.line(min, top_right, color);
+        self.line(top_right, max, color);
+        self.line(max, bot_left, color);
+        self.line(bot_left, min, color);
+    }
+
+    //Line from origin to origin + vector with a head at the end
+    pub fn arrow(&mut self, origin: Vec2, vector: Vec2, color: Color) {
+        let tip = origin + vector;
+        self.line(origin, tip, color);
+
+        let length = (vector.x * vector.x + vector.y * vector.y).sqrt();
+        if length <= 0.0 {
+            return;
+        }
+        let angle = vector.y.atan2(vector.x);
+        let head = length * ARROW_HEAD_SIZE;
+        for side in [-1.0, 1.0].iter() {
+            let head_angle = angle + side * ARROW_HEAD_ANGLE;
+            self.line(tip, tip + Vec2::new(head_angle.cos() * head, head_angle.sin() * head), color);
+        }
+    }
+
+    pub fn vertices(&self) -> &[DebugVertex] {
+        &self.vertices
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+}
+
+//Shows every collider's bounding box, its velocity and the normals of its current collisions
+pub struct DrawPhysicsDebug;
+
+impl<'a> System<'a> for DrawPhysicsDebug {
+    type SystemData = (ReadStorage<'a, PhysicsComponent>, ReadStorage<'a, TransformComponent>, Write<'a, DebugDraw>);
+
+    fn run(&mut self, (physics_storage, transform_storage, mut debug_draw): Self::SystemData) {
+        use specs::Join;
+
+        if !debug_draw.enabled {
+            return;
+        }
+
+        for (physics, transform) in (&physics_storage, &transform_storage).join() {
+            let (min, max) = physics.bounds(transform.position);
+            let color = if physics.collided_objects.is_empty() { BOX_COLOR } else { COLLIDING_BOX_COLOR };
+            debug_draw.rect(min, max, color);
+
+            debug_draw.arrow(transform.position, physics.velocity * VELOCITY_SCALE, VELOCITY_COLOR);
+            for collision in physics.collided_objects.iter() {
+                debug_draw.arrow(transform.position, collision.mtv * MTV_LENGTH, MTV_COLOR);
+            }
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//Finding games on the local network without typing addresses
+//Netplay hosts and pong-servers broadcast a beacon every second, and lobbies list the ones they hear
+
+use std::io::Cursor;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+//Beacons from anything else on the network are ignored
+const MAGIC: u32 = 0x4e41_4c50;
+//Only one socket can listen on a port, so listeners take the first free port in this range and beacons go to all of them
+//That way several lobbies on one machine, or on loopback, all hear every game
+pub const DISCOVERY_PORT: u16 = 7790;
+const DISCOVERY_PORTS: u16 = 8;
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+//Games drop off the list once their beacons have stopped for this long
+const GAME_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_NAME: usize = 32;
+const MAX_PACKET_SIZE: usize = 64;
+
+const NETPLAY: u8 = 0;
+const SERVER: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GameMode {
+    //A player hosting a two player netplay match, spectators watch on the next port up
+    Netplay,
+    //A pong-server, players are put in the default room
+    Server
+}
+
+#[derive(Clone, Debug)]
+pub struct Beacon {
+    //Random for each game, the same game heard through both broadcast and loopback is only listed once
+    pub id: u32,
+    pub name: String,
+    pub mode: GameMode,
+    //Protocol version of the mode, games with a different one can't be joined
+    pub version: u16,
+    pub port: u16,
+    pub players: u16,
+    //0 for servers, which open rooms as they're needed
+    pub max_players: u16
+}
+
+impl Beacon {
+    pub fn new(name: &str, mode: GameMode, version: u16, port: u16, players: u16, max_players: u16) -> Beacon {
+        Beacon {
+            id: rand::random(),
+            name: name.to_string(),
+            mode,
+            version,
+            port,
+            players,
+            max_players
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.max_players != 0 && self.players >= self.max_players
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+        packet.write_u32::<LittleEndian>(MAGIC).unwrap();
+        packet.write_u32::<LittleEndian>(self.id).unwrap();
+        packet.write_u8(match self.mode { GameMode::Netplay => NETPLAY, GameMode::Server => SERVER }).unwrap();
+        packet.write_u16::<LittleEndian>(self.version).unwrap();
+        packet.write_u16::<LittleEndian>(self.port).unwrap();
+        packet.write_u16::<LittleEndian>(self.players).unwrap();
+        packet.write_u16::<LittleEndian>(self.max_players).unwrap();
+        let name = truncate(&self.name, MAX_NAME);
+        packet.write_u8(name.len() as u8).unwrap();
+        packet.extend_from_slice(name.as_bytes());
+        packet
+    }
+
+    fn decode(packet: &[u8]) -> Option<Beacon> {
+        let mut reader = Cursor::new(packet);
+        if reader.read_u32::<LittleEndian>().ok()? != MAGIC {
+            return None;
+        }
+        let id = reader.read_u32::<LittleEndian>().ok()?;
+        let mode = match reader.read_u8().ok()? {
+            NETPLAY => GameMode::Netplay,
+            SERVER => GameMode::Server,
+            _ => {
+                return None;
+            }
+        };
+        let version = reader.read_u16::<LittleEndian>().ok()?;
+        let port = reader.read_u16::<LittleEndian>().ok()?;
+        let players = reader.read_u16::<LittleEndian>().ok()?;
+        let max_players = reader.read_u16::<LittleEndian>().ok()?;
+        let length = reader.read_u8().ok()? as usize;
+        let start = reader.position() as usize;
+        let name = std::str::from_utf8(packet.get(start..start + length)?).ok()?.to_string();
+        Some(Beacon {
+            id,
+            name,
+            mode,
+            version,
+            port,
+            players,
+            max_players
+        })
+    }
+}
+
+//Cuts a string to at most max bytes without splitting a character
+pub fn truncate(string: &str, max: usize) -> &str {
+    let mut end = string.len().min(max);
+    while !string.is_char_boundary(end) {
+        end -= 1;
+    }
+    &string[..end]
+}
+
+//Who's hosting, shown in other players' lobbies when no name is given
+pub fn default_name() -> String {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "Pong".to_string())
+}
+
+//Broadcasts a beacon every second on its own thread, so it keeps going while a host is blocked waiting for a player
+//Stops when dropped
+pub struct Announcer {
+    beacon: Arc<Mutex<Beacon>>,
+    running: Arc<AtomicBool>
+}
+
+impl Announcer {
+    pub fn start(beacon: Beacon) -> Result<Announcer, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).map_err(|e| e.to_string())?;
+        socket.set_broadcast(true).map_err(|e| e.to_string())?;
+        println!("Announcing {} on the local network", beacon.name);
+
+        let beacon = Arc::new(Mutex::new(beacon));
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_beacon = beacon.clone();
+        let thread_running = running.clone();
+        std::thread::spawn(move || {
+            while thread_running.load(Ordering::Relaxed) {
+                let packet = thread_beacon.lock().unwrap().encode();
+                //Machines without a network can't broadcast, but loopback still works for testing
+                for port in DISCOVERY_PORT..DISCOVERY_PORT + DISCOVERY_PORTS {
+                    let _ = socket.send_to(&packet, (Ipv4Addr::BROADCAST, port));
+                    let _ = socket.send_to(&packet, (Ipv4Addr::LOCALHOST, port));
+                }
+                std::thread::sleep(BEACON_INTERVAL);
+            }
+        });
+        Ok(Announcer {
+            beacon,
+            running
+        })
+    }
+
+    pub fn set_players(&self, players: u16) {
+        self.beacon.lock().unwrap().players = players;
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DiscoveredGame {
+    pub beacon: Beacon,
+    //Where the game's beacon came from, with the port it's played on
+    pub address: SocketAddr,
+    last_heard: Instant
+}
+
+//Listens for beacons and keeps the list of games that are still sending them
+pub struct Discovery {
+    socket: UdpSocket,
+    //Sorted by name
+    games: Vec<DiscoveredGame>
+}
+
+impl Discovery {
+    pub fn new() -> Result<Discovery, String> {
+        let socket = (DISCOVERY_PORT..DISCOVERY_PORT + DISCOVERY_PORTS).find_map(|port| UdpSocket::bind(("0.0.0.0", port)).ok())
+            .ok_or(format!("Ports {} to {} are all in use, close another lobby", DISCOVERY_PORT, DISCOVERY_PORT + DISCOVERY_PORTS - 1))?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(Discovery {
+            socket,
+            games: Vec::new()
+        })
+    }
+
+    pub fn games(&self) -> &[DiscoveredGame] {
+        &self.games
+    }
+
+    //Called every frame, receives beacons and forgets games that have gone quiet
+    pub fn update(&mut self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while let Ok((size, from)) = self.socket.recv_from(&mut buffer) {
+            let beacon = match Beacon::decode(&buffer[..size]) {
+                None => {
+                    continue;
+                },
+                Some(beacon) => {
+                    beacon
+                }
+            };
+            let address = SocketAddr::new(from.ip(), beacon.port);
+            match self.games.iter_mut().find(|game| game.beacon.id == beacon.id) {
+                None => {
+                    self.games.push(DiscoveredGame {
+                        beacon,
+                        address,
+                        last_heard: Instant::now()
+                    });
+                },
+                Some(game) => {
+                    //Keeps whichever address was heard first, so the listed game doesn't flip between them
+                    game.beacon = beacon;
+                    game.last_heard = Instant::now();
+                }
+            }
+        }
+        self.games.retain(|game| game.last_heard.elapsed() < GAME_TIMEOUT);
+        self.games.sort_by(|a, b| a.beacon.name.cmp(&b.beacon.name).then(a.address.cmp(&b.address)));
+    }
+}
+
+//Listens for seconds and prints every game heard, for checking discovery without opening the lobby
+pub fn print_games(seconds: u32) -> Result<(), String> {
+    let mut discovery = Discovery::new()?;
+    println!("Listening for games on port {}", discovery.socket.local_addr().map_err(|e| e.to_string())?.port());
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(seconds as u64) {
+        discovery.update();
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    if discovery.games().is_empty() {
+        println!("No games found");
+    }
+    for game in discovery.games() {
+        let beacon = &game.beacon;
+        println!("{} {:?} at {}, {}/{} players, version {}", beacon.name, beacon.mode, game.address, beacon.players, beacon.max_players, beacon.version);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_beacon() -> Beacon {
+        Beacon::new("Living room", GameMode::Server, 7, 7780, 3, 0)
+    }
+
+    #[test]
+    fn round_trip() {
+        let beacon = test_beacon();
+        let decoded = Beacon::decode(&beacon.encode()).unwrap();
+        assert_eq!(decoded.id, beacon.id);
+        assert_eq!(decoded.name, beacon.name);
+        assert_eq!(decoded.mode, beacon.mode);
+        assert_eq!(decoded.version, beacon.version);
+        assert_eq!(decoded.port, beacon.port);
+        assert_eq!(decoded.players, beacon.players);
+        assert_eq!(decoded.max_players, beacon.max_players);
+    }
+
+    #[test]
+    fn long_names_are_cut_without_splitting_characters() {
+        //Each ü is two bytes, so MAX_NAME falls in the middle of one
+        let name = "ü".repeat(MAX_NAME);
+        let beacon = Beacon::new(&format!("x{}", name), GameMode::Netplay, 2, 7777, 1, 2);
+        let packet = beacon.encode();
+        assert!(packet.len() <= MAX_PACKET_SIZE);
+        let decoded = Beacon::decode(&packet).unwrap();
+        assert!(decoded.name.len() <= MAX_NAME);
+        assert_eq!(decoded.name, format!("x{}", "ü".repeat((MAX_NAME - 1) / 2)));
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let packet = test_beacon().encode();
+        for length in 0..packet.len() {
+            assert!(Beacon::decode(&packet[..length]).is_none(), "Decoded the first {} of {} bytes", length, packet.len());
+        }
+    }
+
+    #[test]
+    fn foreign_packets() {
+        let mut packet = test_beacon().encode();
+        packet[0] ^= 0xff;
+        assert!(Beacon::decode(&packet).is_none());
+        assert!(Beacon::decode(&[]).is_none());
+        assert!(Beacon::decode(b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn unknown_mode() {
+        let mut packet = test_beacon().encode();
+        packet[8] = 9;
+        assert!(Beacon::decode(&packet).is_none());
+    }
+
+    #[test]
+    fn bad_name() {
+        let mut packet = test_beacon().encode();
+        //Not UTF-8
+        let last = packet.len() - 1;
+        packet[last] = 0xff;
+        assert!(Beacon::decode(&packet).is_none());
+
+        //Longer than the rest of the packet
+        let mut packet = test_beacon().encode();
+        packet[17] = u8::MAX;
+        assert!(Beacon::decode(&packet).is_none());
+    }
+
+    #[test]
+    fn garbage_is_never_accepted() {
+        let mut rng = crate::game::GameRng::new(1);
+        for _ in 0..1000 {
+            let length = rng.range(0.0, MAX_PACKET_SIZE as f32) as usize;
+            let packet: Vec<u8> = (0..length).map(|_| rng.range(0.0, 256.0) as u8).collect();
+            assert!(Beacon::decode(&packet).is_none());
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use crate::fy_math::Vec2;
+
+//Things that happened during a simulation tick, published on an EventChannel<GameEvent> resource
+//Presentation systems (particles, camera shake) each keep their own reader, so nothing is missed
+//when several ticks run in one frame
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GameEvent {
+    //The ball bounced off something, velocity is the ball's velocity after the bounce
+    //paddle is the player_idx of the paddle that was hit, None for walls
+    BallHit {
+        position: Vec2,
+        velocity: Vec2,
+        paddle: Option<u32>
+    },
+    //The ball left the arena, position is where it crossed the edge
+    Goal {
+        scorer: u32,
+        position: Vec2
+    },
+    //A player reached the winning score, sent after the Goal that won it. The score is reset for the next match
+    MatchWon {
+        winner: u32
+    }
+}
@@ -2,13 +2,15 @@ use specs::{Component, DenseVecStorage};
 use specs_derive::{Component};
 use std::ops;
 
-#[derive(Default, Copy, Clone, Debug)]
+#[repr(C)]
+#[derive(Default, Copy, Clone, Debug, PartialEq)]
 pub struct Vec2 {
     pub x: f32,
     pub y: f32
 }
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct Vec4 {
     pub x: f32,
     pub y: f32,
@@ -17,6 +19,7 @@ pub struct Vec4 {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct Mat4 {
     pub x: Vec4,
     pub y: Vec4,
@@ -24,6 +27,19 @@ pub struct Mat4 {
     pub w: Vec4
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Color {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32
+}
+
+impl Color {
+    pub const WHITE: Color = Color { r: 1.0, g: 1.0, b: 1.0, a: 1.0 };
+}
+
 impl Vec2 {
     pub fn new(x: f32, y: f32) -> Vec2 {
         Vec2 {
@@ -56,6 +72,55 @@ impl Vec2 {
     }
 }
 
+impl Mat4 {
+    pub fn identity() -> Mat4 {
+        Mat4 {
+            x: Vec4 { x: 1.0, y: 0.0, z: 0.0, w: 0.0 },
+            y: Vec4 { x: 0.0, y: 1.0, z: 0.0, w: 0.0 },
+            z: Vec4 { x: 0.0, y: 0.0, z: 1.0, w: 0.0 },
+            w: Vec4 { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }
+        }
+    }
+
+    fn row(&self, idx: usize) -> Vec4 {
+        let pick = |v: &Vec4| [v.x, v.y, v.z, v.w][idx];
+        Vec4 { x: pick(&self.x), y: pick(&self.y), z: pick(&self.z), w: pick(&self.w) }
+    }
+}
+
+impl Vec4 {
+    pub fn dot(&self, other: &Vec4) -> f32 {
+        (self.x * other.x) + (self.y * other.y) + (self.z * other.z) + (self.w * other.w)
+    }
+}
+
+//Matrices are stored as columns, so a * b applies b first
+impl ops::Mul<Mat4> for Mat4 {
+    type Output = Mat4;
+
+    fn mul(self, _rhs: Mat4) -> Mat4 {
+        let rows = [self.row(0), self.row(1), self.row(2), self.row(3)];
+        let column = |c: &Vec4| Vec4 { x: rows[0].dot(c), y: rows[1].dot(c), z: rows[2].dot(c), w: rows[3].dot(c) };
+        Mat4 {
+            x: column(&_rhs.x),
+            y: column(&_rhs.y),
+            z: column(&_rhs.z),
+            w: column(&_rhs.w)
+        }
+    }
+}
+
+impl ops::Add<Vec2> for Vec2 {
+    type Output = Vec2;
+
+    fn add(self, _rhs: Vec2) -> Vec2 {
+        Vec2 {
+            x: self.x + _rhs.x,
+            y: self.y + _rhs.y
+        }
+    }
+}
+
 impl ops::Sub<Vec2> for Vec2 {
     type Output = Vec2;
 
@@ -86,7 +151,7 @@ impl ops::Mul<Vec2> for f32 {
     }
 }
 
-#[derive(Component, Default)]
+#[derive(Component, Default, Clone)]
 #[storage(DenseVecStorage)]
 pub struct TransformComponent {
     pub position: Vec2
@@ -0,0 +1,450 @@
+use specs::{Component, VecStorage, Entity, World, Builder, System, Read, Write, ReadStorage, WriteStorage, DispatcherBuilder};
+use specs_derive::{Component};
+use shrev::EventChannel;
+
+use crate::fy_math::{Vec2, TransformComponent};
+use crate::physics::{PhysicsComponent, PhysicsSystem};
+use crate::camera::{ARENA_WIDTH, ARENA_HEIGHT};
+use crate::mesh::MeshData;
+use crate::events::GameEvent;
+use crate::profiler::{Profiler, Profiled};
+
+//The deterministic part of the game: paddles, ball, walls and scoring
+//Given the same seed and the same inputs every tick, every copy of the world plays out identically
+
+pub const PLAYER_COUNT: usize = 2;
+
+const BOUNCE_OFFSET: f32 = 15.0;
+
+//Gameplay distances and speeds are in world units, the arena is ARENA_WIDTH x ARENA_HEIGHT
+pub const BALL_SPEED: f32 = 4.0;
+//Fastest the AI can move its stick, in axis units per second, slow enough that it can be beaten
+const AI_SPEED: f32 = 1.2;
+const PADDLE_X: f32 = 7.2;
+const PADDLE_TRAVEL: f32 = ARENA_HEIGHT / 2.0;
+const WALL_Y: f32 = 5.4;
+//The ball has to leave the screen completely before a point is scored
+const SCORE_X: f32 = 10.4;
+
+//First to this many points wins the match
+const WINNING_SCORE: u32 = 11;
+
+//The simulation runs at a fixed rate, independent of how fast frames are presented
+pub const TICK_RATE: f32 = 0.01;
+
+pub const BALL_MESH: &str = "meshes/ball.ron";
+pub const PADDLE_MESH: &str = "meshes/paddle.ron";
+pub const WALL_MESH: &str = "meshes/wall.ron";
+//Colliders are built from copies of the meshes compiled in, so the simulation runs without the meshes folder, like on a server
+const PADDLE_COLLIDER: &str = include_str!("../meshes/paddle.ron");
+const BALL_COLLIDER: &str = include_str!("../meshes/ball.ron");
+const WALL_COLLIDER: &str = include_str!("../meshes/wall.ron");
+
+//Sticks are sent and recorded with this precision, so every peer sees exactly the same value
+const AXIS_STEPS: f32 = 32767.0;
+
+//Input for one player for the current tick, indexed by Paddle.player_idx
+#[derive(Default, Clone)]
+pub struct ControllerState {
+    pub left_axis_x: f32,
+    pub left_axis_y: f32,
+    //The paddle is moved by UpdateAi instead
+    pub ai: bool
+}
+
+#[derive(Default, Clone)]
+pub struct Controllers(pub std::vec::Vec<ControllerState>);
+
+//One player's input for one tick, in the form it's sent over the network
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerInput {
+    pub axis_y: i16,
+    pub ai: bool
+}
+
+impl PlayerInput {
+    pub fn from_state(state: &ControllerState) -> PlayerInput {
+        PlayerInput {
+            axis_y: (state.left_axis_y.clamp(-1.0, 1.0) * AXIS_STEPS) as i16,
+            ai: state.ai
+        }
+    }
+
+    //AI players keep the stick position UpdateAi gave them
+    fn apply(&self, state: &mut ControllerState) {
+        state.ai = self.ai;
+        if !self.ai {
+            state.left_axis_x = 0.0;
+            state.left_axis_y = self.axis_y as f32 / AXIS_STEPS;
+        }
+    }
+}
+
+//Sets every player's input for the next tick, indexed by player
+pub fn apply_inputs(world: &mut World, inputs: &[PlayerInput]) {
+    let mut controllers = world.write_resource::<Controllers>();
+    for (input, state) in inputs.iter().zip(controllers.0.iter_mut()) {
+        input.apply(state);
+    }
+}
+
+//Small deterministic random number generator (splitmix64), so every peer gets the same bounces from the same seed
+#[derive(Copy, Clone, Default)]
+pub struct GameRng {
+    state: u64
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng {
+            state: seed
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    //Uniform between min and max
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        let t = (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+        min + (max - min) * t
+    }
+}
+
+#[derive(Component, Clone)]
+#[storage(VecStorage)]
+pub struct Ball {
+    left_paddle: Entity,
+    right_paddle: Entity
+}
+
+impl Ball {
+    fn new(left_paddle: Entity, right_paddle: Entity) -> Ball {
+        Ball {
+            left_paddle,
+            right_paddle
+        }
+    }
+}
+
+#[derive(Component, Clone)]
+#[storage(VecStorage)]
+pub struct Paddle {
+    pub player_idx: u32
+}
+
+#[derive(Default)]
+pub struct DeltaTime(pub f32);
+
+//Points scored, indexed by Paddle.player_idx
+#[derive(Default, Clone)]
+pub struct Score(pub [u32; 2]);
+
+//Set while ticks that already ran are run again after a rollback, so their sounds and effects don't go off twice
+#[derive(Default)]
+pub struct Resimulating(pub bool);
+
+//Events from the last tick that ran, written even while resimulating so rollback can keep each tick's own events
+#[derive(Default)]
+pub struct TickEvents(pub Vec<GameEvent>);
+
+pub struct UpdateBall;
+
+impl<'a> System<'a> for UpdateBall {
+    type SystemData = (ReadStorage<'a, Ball>, ReadStorage<'a, Paddle>, WriteStorage<'a, TransformComponent>, WriteStorage<'a, PhysicsComponent>, Read<'a, DeltaTime>, Write<'a, Score>, Write<'a, GameRng>, Read<'a, Resimulating>, Write<'a, TickEvents>, Write<'a, EventChannel<GameEvent>>);
+
+    fn run(&mut self, (ball_storage, paddle_storage, mut transform_storage, mut physics_storage, deltatime, mut score, mut rng, resimulating, mut tick_events, mut event_channel): Self::SystemData) {
+        use specs::Join;
+        let deltatime = deltatime.0;
+        let mut events = Vec::new();
+        for (ball, t, phys_c) in (&ball_storage, &mut transform_storage, &mut physics_storage).join() {
+            //Check for collision against paddles
+            for other_collider in phys_c.collided_objects.iter() {
+                let other_entity = other_collider.other;
+                let mtv = other_collider.mtv;
+                if other_entity == ball.left_paddle {
+                    let angle = rng.range(-1.0 * BOUNCE_OFFSET, 1.0 * BOUNCE_OFFSET);
+                    let y_offset = angle.to_radians().sin() * BALL_SPEED;
+                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
+                    phys_c.velocity.y += y_offset;
+                } else if other_entity == ball.right_paddle {
+                    let angle = rng.range(-1.0 * BOUNCE_OFFSET, 1.0 * BOUNCE_OFFSET);
+                    let y_offset = angle.to_radians().sin() * BALL_SPEED;
+                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
+                    phys_c.velocity.y += y_offset;
+                } else {
+                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
+                }
+                events.push(GameEvent::BallHit {
+                    position: t.position,
+                    velocity: phys_c.velocity,
+                    paddle: paddle_storage.get(other_entity).map(|paddle| paddle.player_idx)
+                });
+            }
+            t.position.x = t.position.x + phys_c.velocity.x * deltatime;
+            t.position.y = t.position.y + phys_c.velocity.y * deltatime;
+
+            //Check for score conditions
+            let mut scorer = None;
+            if t.position.x > SCORE_X {
+                scorer = Some(1);
+            } else if t.position.x < -SCORE_X {
+                scorer = Some(0);
+            }
+
+            if let Some(scorer) = scorer {
+                score.0[scorer as usize] += 1;
+                //Effects go off where the ball left the visible arena, not where it is now
+                let edge = Vec2::new(t.position.x.clamp(-ARENA_WIDTH / 2.0, ARENA_WIDTH / 2.0),
+                                     t.position.y.clamp(-ARENA_HEIGHT / 2.0, ARENA_HEIGHT / 2.0));
+                events.push(GameEvent::Goal {
+                    scorer,
+                    position: edge
+                });
+                if score.0[scorer as usize] >= WINNING_SCORE {
+                    events.push(GameEvent::MatchWon {
+                        winner: scorer
+                    });
+                    score.0 = [0, 0];
+                }
+                t.position = Vec2::new(0.0, 0.0);
+                let angle = rng.range(0.0, 360.0);
+                let x = angle.to_radians().cos();
+                let y = angle.to_radians().sin();
+                phys_c.velocity = BALL_SPEED * Vec2::new(x, y);
+            }
+        }
+        tick_events.0.clear();
+        tick_events.0.extend_from_slice(&events);
+        if !resimulating.0 {
+            event_channel.iter_write(events);
+        }
+    }
+}
+
+//Moves the stick of AI players towards the ball, runs before UpdatePaddles
+pub struct UpdateAi;
+
+impl<'a> System<'a> for UpdateAi {
+    type SystemData = (ReadStorage<'a, Ball>, ReadStorage<'a, TransformComponent>, Read<'a, DeltaTime>, Write<'a, Controllers>);
+
+    fn run(&mut self, (ball_storage, transform_storage, deltatime, mut controllers): Self::SystemData) {
+        use specs::Join;
+
+        let ball_y = match (&ball_storage, &transform_storage).join().next() {
+            None => {
+                return;
+            },
+            Some((_, transform)) => {
+                transform.position.y
+            }
+        };
+        let target = (ball_y / PADDLE_TRAVEL).clamp(-1.0, 1.0);
+        let max_step = AI_SPEED * deltatime.0;
+        for state in controllers.0.iter_mut().filter(|state| state.ai) {
+            state.left_axis_y += (target - state.left_axis_y).clamp(-max_step, max_step);
+        }
+    }
+}
+
+pub struct UpdatePaddles;
+
+impl<'a> System<'a> for UpdatePaddles {
+    type SystemData = (ReadStorage<'a, Paddle>, WriteStorage<'a, TransformComponent>, Read<'a, Controllers>);
+
+    fn run(&mut self, (paddle_storage, mut transform_storage, controller_storage): Self::SystemData) {
+        use specs::Join;
+
+        for (paddle, t) in (&paddle_storage, &mut transform_storage).join() {
+            let position = if paddle.player_idx < controller_storage.0.len() as u32 {
+                controller_storage.0[paddle.player_idx as usize].left_axis_y
+            } else {
+                0.0
+            };
+            t.position.y = position * PADDLE_TRAVEL;
+        }
+    }
+}
+
+
+//Simulation entities, so the client can attach models and effects to them
+pub struct GameEntities {
+    //Indexed by Paddle.player_idx
+    pub paddles: [Entity; PLAYER_COUNT],
+    pub ball: Entity,
+    pub walls: [Entity; 2]
+}
+
+//Registers the simulation's components and resources, seed decides every random bounce
+pub fn init(world: &mut World, seed: u64) {
+    world.register::<PhysicsComponent>();
+    world.register::<Ball>();
+    world.register::<Paddle>();
+    world.register::<TransformComponent>();
+
+    world.add_resource(DeltaTime(TICK_RATE));
+    world.add_resource(Score::default());
+    world.add_resource(GameRng::new(seed));
+    world.add_resource(Controllers((0..PLAYER_COUNT).map(|_| ControllerState::default()).collect()));
+    world.add_resource(Resimulating(false));
+    world.add_resource(TickEvents::default());
+    world.add_resource(EventChannel::<GameEvent>::new());
+}
+
+//Entities are always created in the same order, so their ids match on every peer
+pub fn create_entities(world: &mut World) -> GameEntities {
+    let paddle_mesh = MeshData::parse(PADDLE_MESH, PADDLE_COLLIDER).unwrap();
+    let ball_mesh = MeshData::parse(BALL_MESH, BALL_COLLIDER).unwrap();
+    let wall_mesh = MeshData::parse(WALL_MESH, WALL_COLLIDER).unwrap();
+
+    //Player 1 defends the right side of the screen
+    let paddles = [Vec2::new(PADDLE_X, 0.0), Vec2::new(-PADDLE_X, 0.0)];
+    let mut paddle_entities = Vec::with_capacity(PLAYER_COUNT);
+    for (idx, position) in paddles.iter().enumerate() {
+        let transform = TransformComponent {
+            position: *position
+        };
+        let physics = PhysicsComponent::new(&paddle_mesh.vertices);
+        let paddle = Paddle {
+            player_idx: idx as u32
+        };
+        paddle_entities.push(world.create_entity().with(transform).with(paddle).with(physics).build());
+    }
+
+    let ball = {
+        let transform = TransformComponent {
+            position: Vec2::new(0.0, 0.0)
+        };
+        let physics = PhysicsComponent::with_velocity(&ball_mesh.vertices, Vec2::new(BALL_SPEED, 0.0));
+        let ball = Ball::new(paddle_entities[1], paddle_entities[0]);
+        world.create_entity().with(ball).with(transform).with(physics).build()
+    };
+
+    let mut wall = |y: f32| {
+        let transform = TransformComponent {
+            position: Vec2::new(0.0, y)
+        };
+        let physics = PhysicsComponent::new(&wall_mesh.vertices);
+        world.create_entity().with(transform).with(physics).build()
+    };
+    let walls = [wall(-WALL_Y), wall(WALL_Y)];
+
+    GameEntities {
+        paddles: [paddle_entities[0], paddle_entities[1]],
+        ball,
+        walls
+    }
+}
+
+//Systems that run every simulation tick, each timed by the Profiler resource
+pub fn add_systems<'a, 'b>(builder: DispatcherBuilder<'a, 'b>) -> DispatcherBuilder<'a, 'b> {
+    builder
+        .with(Profiled::new("physics", PhysicsSystem), "physics", &[])
+        .with(Profiled::new("ball", UpdateBall), "ball", &["physics"])
+        .with(Profiled::new("ai", UpdateAi), "ai", &["ball"])
+        .with(Profiled::new("paddles", UpdatePaddles), "paddles", &["ai"])
+}
+
+//A world with just the simulation in it, for running matches without a window
+pub fn create_headless(seed: u64) -> World {
+    let mut world = World::new();
+    init(&mut world, seed);
+    world.add_resource(Profiler::default());
+    create_entities(&mut world);
+    world
+}
+
+//Hash of everything that affects the rest of the match, peers compare these to check they haven't diverged
+pub fn checksum(world: &World) -> u32 {
+    use specs::Join;
+
+    //FNV-1a
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut add = |value: u32| {
+        for byte in value.to_le_bytes().iter() {
+            hash ^= *byte as u32;
+            hash = hash.wrapping_mul(0x0100_0193);
+        }
+    };
+
+    let transforms = world.read_storage::<TransformComponent>();
+    let physics = world.read_storage::<PhysicsComponent>();
+    for (transform, physics) in (&transforms, &physics).join() {
+        add(transform.position.x.to_bits());
+        add(transform.position.y.to_bits());
+        add(physics.velocity.x.to_bits());
+        add(physics.velocity.y.to_bits());
+    }
+    for score in world.read_resource::<Score>().0.iter() {
+        add(*score);
+    }
+    //The AI moves its stick gradually, so where it is carries over into the next tick
+    for state in world.read_resource::<Controllers>().0.iter() {
+        add(state.left_axis_x.to_bits());
+        add(state.left_axis_y.to_bits());
+        add(state.ai as u32);
+    }
+    let rng = world.read_resource::<GameRng>().state;
+    add(rng as u32);
+    add((rng >> 32) as u32);
+    hash
+}
+
+//Copy of every component of one kind, with the entity it belongs to
+pub type Components<T> = Vec<(Entity, T)>;
+
+fn save_storage<T: Component + Clone>(world: &World) -> Components<T> {
+    use specs::Join;
+    let entities = world.entities();
+    let storage = world.read_storage::<T>();
+    (&entities, &storage).join().map(|(entity, component)| (entity, component.clone())).collect()
+}
+
+fn restore_storage<T: Component + Clone>(world: &World, components: &Components<T>) {
+    let mut storage = world.write_storage::<T>();
+    storage.clear();
+    for (entity, component) in components.iter() {
+        storage.insert(*entity, component.clone()).unwrap();
+    }
+}
+
+//Everything the simulation needs to carry on from a tick, the world can be put back to it after ticks were predicted wrong
+//Entities are never created or deleted during a match, so they're saved by id
+#[derive(Clone)]
+pub struct Snapshot {
+    pub transforms: Components<TransformComponent>,
+    pub physics: Components<PhysicsComponent>,
+    pub balls: Components<Ball>,
+    pub paddles: Components<Paddle>,
+    pub score: Score,
+    rng: GameRng,
+    //AI players' sticks carry over from tick to tick
+    controllers: Controllers
+}
+
+pub fn save(world: &World) -> Snapshot {
+    Snapshot {
+        transforms: save_storage(world),
+        physics: save_storage(world),
+        balls: save_storage(world),
+        paddles: save_storage(world),
+        score: world.read_resource::<Score>().clone(),
+        rng: *world.read_resource::<GameRng>(),
+        controllers: world.read_resource::<Controllers>().clone()
+    }
+}
+
+pub fn restore(world: &mut World, snapshot: &Snapshot) {
+    restore_storage::<TransformComponent>(world, &snapshot.transforms);
+    restore_storage::<PhysicsComponent>(world, &snapshot.physics);
+    restore_storage::<Ball>(world, &snapshot.balls);
+    restore_storage::<Paddle>(world, &snapshot.paddles);
+    *world.write_resource::<Score>() = snapshot.score.clone();
+    *world.write_resource::<GameRng>() = snapshot.rng;
+    *world.write_resource::<Controllers>() = snapshot.controllers.clone();
+}
@@ -0,0 +1,77 @@
+use crate::config::HapticsSettings;
+use crate::players::Players;
+
+//One burst of vibration, strengths go from 0 to 1
+struct Rumble {
+    player: u32,
+    //The low frequency motor is the heavy thump, the high frequency motor the buzz
+    low: f32,
+    high: f32,
+    duration_ms: u32
+}
+
+//Rumbles to start this frame, any system can add to it and they're sent to the controllers after the frame systems run
+#[derive(Default)]
+pub struct RumbleQueue {
+    rumbles: Vec<Rumble>
+}
+
+impl RumbleQueue {
+    pub fn rumble(&mut self, player: u32, low: f32, high: f32, duration_ms: u32) {
+        self.rumbles.push(Rumble {
+            player,
+            low,
+            high,
+            duration_ms
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.rumbles.clear();
+    }
+}
+
+//Sends rumbles to the controllers, which have to stay on the main thread
+pub struct Haptics {
+    settings: HapticsSettings,
+    //Instance ids of controllers that failed to rumble once, so they aren't asked again
+    unsupported: Vec<i32>
+}
+
+impl Haptics {
+    pub fn new(settings: &HapticsSettings) -> Haptics {
+        Haptics {
+            settings: settings.clone(),
+            unsupported: Vec::new()
+        }
+    }
+
+    //Rumbles for players without a controller are dropped
+    pub fn apply(&mut self, players: &mut Players, queue: &mut RumbleQueue) {
+        if !self.settings.enabled {
+            queue.clear();
+            return;
+        }
+
+        for rumble in queue.rumbles.drain(..) {
+            let idx = rumble.player as usize;
+            let controller = match players.controller_mut(idx) {
+                None => {
+                    continue;
+                },
+                Some(controller) => {
+                    controller
+                }
+            };
+            if self.unsupported.contains(&controller.instance_id()) {
+                continue;
+            }
+            let intensity = self.settings.intensity.get(idx).cloned().unwrap_or(1.0);
+            let strength = |value: f32| ((value * intensity).clamp(0.0, 1.0) * 65535.0) as u16;
+            if let Err(e) = controller.set_rumble(strength(rumble.low), strength(rumble.high), rumble.duration_ms) {
+                println!("{} doesn't support rumble: {}", controller.name(), e);
+                self.unsupported.push(controller.instance_id());
+            }
+        }
+    }
+}
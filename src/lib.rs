@@ -0,0 +1,19 @@
+//The simulation and networking shared by the game and pong-server
+//Nothing here needs SDL or Vulkan, so it builds and tests without the client feature
+
+pub mod fy_math;
+pub mod mesh;
+pub mod physics;
+pub mod events;
+pub mod camera;
+pub mod font;
+pub mod text;
+pub mod profiler;
+pub mod game;
+pub mod protocol;
+pub mod netplay;
+pub mod rollback;
+pub mod replay;
+pub mod discovery;
+pub mod spectate;
+pub mod remote;
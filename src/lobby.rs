@@ -0,0 +1,174 @@
+use std::net::SocketAddr;
+
+use sdl2::EventPump;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use specs::{World, RunNow};
+
+use crate::camera::{Camera, ARENA_HEIGHT};
+use crate::discovery::{Discovery, DiscoveredGame, GameMode};
+use crate::fy_math::{Vec2, Color};
+use crate::netplay::{self, NetSession};
+use crate::players::Players;
+use crate::protocol;
+use crate::remote::RemoteMatch;
+use crate::render::RenderContext;
+use crate::rollback::Rollback;
+use crate::text::{self, TextQueue};
+use crate::timing::FrameLimiter;
+
+const TITLE_SCALE: f32 = 2.0;
+const ROW_WIDTH: f32 = 480.0;
+const ROW_HEIGHT: f32 = 28.0;
+const ROW_GAP: f32 = 4.0;
+const ROW_PADDING: f32 = 8.0;
+//Most games listed, anything past this is off the bottom of the arena anyway
+const MAX_ROWS: usize = 12;
+
+const ROW_COLOR: Color = Color { r: 0.15, g: 0.15, b: 0.15, a: 0.85 };
+const HOVER_COLOR: Color = Color { r: 0.3, g: 0.3, b: 0.3, a: 0.9 };
+const DISABLED_COLOR: Color = Color { r: 0.5, g: 0.5, b: 0.5, a: 1.0 };
+const MESSAGE_COLOR: Color = Color { r: 0.95, g: 0.4, b: 0.35, a: 1.0 };
+
+//A game picked in the lobby, connected and ready to play or watch
+pub enum Connection {
+    Netplay(Box<Rollback>),
+    Remote(RemoteMatch)
+}
+
+//Lists the games announced on the local network, clicking one joins it
+pub struct Lobby {
+    discovery: Discovery,
+    mouse: (i32, i32),
+    //Shown under the list, such as why the last game couldn't be joined
+    pub message: Option<String>
+}
+
+//Games from other versions of pong are listed, but can't be joined
+fn is_compatible(game: &DiscoveredGame) -> bool {
+    let version = match game.beacon.mode {
+        GameMode::Netplay => netplay::PROTOCOL_VERSION,
+        GameMode::Server => protocol::PROTOCOL_VERSION
+    };
+    game.beacon.version == version
+}
+
+fn status(game: &DiscoveredGame) -> String {
+    let beacon = &game.beacon;
+    if !is_compatible(game) {
+        return "Different version".to_string();
+    }
+    match beacon.mode {
+        GameMode::Netplay if beacon.is_full() => "Playing, click to watch".to_string(),
+        GameMode::Netplay => format!("Netplay {}/{}", beacon.players, beacon.max_players),
+        GameMode::Server => format!("Server, {} playing", beacon.players)
+    }
+}
+
+//Joins a netplay host or server, full netplay matches are watched through the host's spectator port instead
+pub fn connect(game: &DiscoveredGame, room: &str, interpolation_delay: u32, spectator_delay: u32) -> Result<Connection, String> {
+    let address = game.address;
+    match game.beacon.mode {
+        GameMode::Netplay if game.beacon.is_full() => {
+            let spectator_address = SocketAddr::new(address.ip(), address.port().wrapping_add(1));
+            RemoteMatch::watch(&spectator_address.to_string(), room, spectator_delay).map(Connection::Remote)
+        },
+        GameMode::Netplay => {
+            NetSession::join(&address.to_string()).map(|session| Connection::Netplay(Box::new(Rollback::new(session))))
+        },
+        GameMode::Server => {
+            RemoteMatch::join(&address.to_string(), room, interpolation_delay).map(Connection::Remote)
+        }
+    }
+}
+
+impl Lobby {
+    pub fn new() -> Result<Lobby, String> {
+        Ok(Lobby {
+            discovery: Discovery::new()?,
+            mouse: (0, 0),
+            message: None
+        })
+    }
+
+    //Top left corner and size of the idx-th game's row, in pixels
+    fn row_rect(camera: &Camera, idx: usize) -> (Vec2, Vec2) {
+        let top = camera.world_to_screen(Vec2::new(0.0, -ARENA_HEIGHT / 2.0));
+        let position = Vec2::new(top.x - ROW_WIDTH / 2.0, top.y + 96.0 + idx as f32 * (ROW_HEIGHT + ROW_GAP));
+        (position, Vec2::new(ROW_WIDTH, ROW_HEIGHT))
+    }
+
+    fn row_at(&self, camera: &Camera, x: i32, y: i32) -> Option<usize> {
+        let count = self.discovery.games().len().min(MAX_ROWS);
+        (0..count).find(|idx| {
+            let (position, size) = Lobby::row_rect(camera, *idx);
+            let (x, y) = (x as f32, y as f32);
+            x >= position.x && x < position.x + size.x && y >= position.y && y < position.y + size.y
+        })
+    }
+
+    fn draw(&self, camera: &Camera, text_queue: &mut TextQueue) {
+        let top = camera.world_to_screen(Vec2::new(0.0, -ARENA_HEIGHT / 2.0));
+        text_queue.text_centered("LAN GAMES", Vec2::new(top.x, top.y + 40.0), TITLE_SCALE, Color::WHITE);
+
+        let games = self.discovery.games();
+        let hovered = self.row_at(camera, self.mouse.0, self.mouse.1);
+        let glyph_offset = (ROW_HEIGHT - crate::font::GLYPH_HEIGHT as f32) / 2.0;
+        for (idx, game) in games.iter().take(MAX_ROWS).enumerate() {
+            let (position, size) = Lobby::row_rect(camera, idx);
+            let compatible = is_compatible(game);
+            let background = if hovered == Some(idx) && compatible { HOVER_COLOR } else { ROW_COLOR };
+            let color = if compatible { Color::WHITE } else { DISABLED_COLOR };
+            text_queue.rect(position, size, background);
+            text_queue.text(&game.beacon.name, Vec2::new(position.x + ROW_PADDING, position.y + glyph_offset), 1.0, color);
+            let status = status(game);
+            let status_x = position.x + size.x - ROW_PADDING - text::text_width(&status, 1.0);
+            text_queue.text(&status, Vec2::new(status_x, position.y + glyph_offset), 1.0, color);
+        }
+
+        let (below, _) = Lobby::row_rect(camera, games.len().clamp(1, MAX_ROWS));
+        if games.is_empty() {
+            let (first, _) = Lobby::row_rect(camera, 0);
+            text_queue.text_centered("Looking for games...", Vec2::new(top.x, first.y + glyph_offset), 1.0, DISABLED_COLOR);
+        }
+        if let Some(message) = self.message.as_ref() {
+            text_queue.text_centered(message, Vec2::new(top.x, below.y + ROW_GAP), 1.0, MESSAGE_COLOR);
+        }
+        text_queue.text_centered("Click a game to join, Esc to quit", Vec2::new(top.x, below.y + ROW_HEIGHT + ROW_GAP), 1.0, DISABLED_COLOR);
+    }
+
+    //Shows the lobby until a game is clicked, returns None if the window is closed
+    pub fn run(&mut self, events: &mut EventPump, players: &mut Players, world: &mut World, renderer: &mut RenderContext, frame_limiter: &mut FrameLimiter) -> Option<DiscoveredGame> {
+        loop {
+            for event in events.poll_iter() {
+                //Controllers plugged in while the lobby is open should still work once the match starts
+                players.handle_event(&event);
+                match event {
+                    Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                        return None;
+                    },
+                    Event::MouseMotion { x, y, .. } => {
+                        self.mouse = (x, y);
+                    },
+                    Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => {
+                        let clicked = self.row_at(&world.read_resource::<Camera>(), x, y).map(|idx| self.discovery.games()[idx].clone());
+                        if let Some(game) = clicked.filter(is_compatible) {
+                            //Connecting blocks until the game answers, so the message is drawn first
+                            self.message = Some(format!("Joining {}...", game.beacon.name));
+                            self.draw(&world.read_resource::<Camera>(), &mut world.write_resource::<TextQueue>());
+                            renderer.run_now(&world.res);
+                            return Some(game);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            self.discovery.update();
+
+            self.draw(&world.read_resource::<Camera>(), &mut world.write_resource::<TextQueue>());
+            renderer.run_now(&world.res);
+            frame_limiter.wait();
+        }
+    }
+}
@@ -1,280 +1,955 @@
-use specs::{Component, VecStorage, Entity, World, Builder, System, Read, ReadStorage, WriteStorage, DispatcherBuilder};
-use specs_derive::{Component};
+use specs::{World, System, Read, Write, DispatcherBuilder, RunNow, ReadExpect, WriteExpect};
 
-use rand::{thread_rng, Rng};
+use shrev::{EventChannel, ReaderId};
 
 mod render;
-use render::{RenderComponent, Vertex};
-mod fy_math;
-use fy_math::{Vec2,TransformComponent};
-mod physics;
-use physics::{PhysicsComponent, PhysicsSystem};
+use render::{RenderComponent, Instanced};
+use pong_deluxe::fy_math;
+use fy_math::{Vec2, Color};
+use pong_deluxe::physics;
+mod config;
+use config::{Settings, CommandLine, VsyncMode};
+mod timing;
+use timing::{FrameLimiter, FrameTime};
+use pong_deluxe::font;
+use pong_deluxe::text;
+use text::TextQueue;
+mod sprite;
+use sprite::{Sprite, TextureHandle};
+mod bench;
+use pong_deluxe::camera;
+use camera::{Camera, ARENA_WIDTH, ARENA_HEIGHT};
+mod upload;
+use pong_deluxe::mesh;
+use mesh::MeshData;
+use pong_deluxe::events;
+use events::GameEvent;
+mod particles;
+use particles::{Particles, ParticleEmitter, ParticleStyle, UpdateParticles};
+mod post;
+mod capture;
+use capture::Capture;
+use pong_deluxe::profiler;
+use profiler::{Profiler, Profiled, DrawProfiler};
+mod debug_draw;
+use debug_draw::{DebugDraw, DrawPhysicsDebug};
+mod audio;
+use audio::{Audio, MusicTrack, SoundEffect, SoundQueue};
+mod music;
+mod haptics;
+use haptics::{Haptics, RumbleQueue};
+mod players;
+use players::{Players, SlotLabels, SlotEvent};
+use pong_deluxe::game;
+use pong_deluxe::netplay;
+use netplay::NetSession;
+use pong_deluxe::rollback;
+use rollback::Rollback;
+use pong_deluxe::protocol;
+use pong_deluxe::remote;
+use remote::RemoteMatch;
+use pong_deluxe::spectate;
+use spectate::SpectatorHost;
+use pong_deluxe::discovery;
+use discovery::{Announcer, Beacon, GameMode};
+mod lobby;
+use lobby::{Lobby, Connection};
+use pong_deluxe::replay;
+use replay::{Replay, Playback};
+use game::{Controllers, ControllerState, PlayerInput, Score, GameRng, PLAYER_COUNT, BALL_SPEED, TICK_RATE, BALL_MESH, PADDLE_MESH, WALL_MESH};
+#[cfg(feature = "shader-hot-reload")]
+mod shader_compiler;
+#[cfg(feature = "shader-hot-reload")]
+mod shader_reload;
+
+const SCORE_TEXT_SCALE: f32 = 3.0;
+
+//Camera shake strength added when a point is scored
+const SCORE_SHAKE: f32 = 0.6;
+
+//Upper bound on simulated time per frame, so a long stall doesn't cause a burst of catch-up ticks
+const MAX_FRAME_TIME: f32 = 0.25;
+
+//Hotkeys for saving a screenshot and starting or stopping a recording
+const SCREENSHOT_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::F12;
+const RECORD_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::F9;
+//Stops the simulation and switches to the menu music
+const PAUSE_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::Escape;
+//Shows collision boxes, velocities and collision normals
+const DEBUG_DRAW_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::F2;
+//Hotkeys for showing the profiler overlay and saving the recent frames as a Chrome trace
+const PROFILER_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::F3;
+const TRACE_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::F4;
+//Replay controls, stepping runs one tick while paused
+const STEP_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::Period;
+const SLOWER_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::LeftBracket;
+const FASTER_KEY: sdl2::keyboard::Keycode = sdl2::keyboard::Keycode::RightBracket;
+
+const PARTICLE_MESH: &str = "meshes/quad.ron";
+
+const PLAYER_COLORS: [Color; 2] = [Color { r: 0.9, g: 0.25, b: 0.2, a: 1.0 },
+                                   Color { r: 0.2, g: 0.5, b: 0.95, a: 1.0 }];
+
+const WALL_COLOR: Color = Color { r: 0.6, g: 0.6, b: 0.6, a: 1.0 };
+
+//Faint trail left behind the ball
+const TRAIL_RATE: f32 = 60.0;
+const TRAIL_STYLE: ParticleStyle = ParticleStyle {
+    lifetime: 0.4,
+    direction: 0.0,
+    spread: std::f32::consts::PI,
+    min_speed: 0.0,
+    max_speed: 0.3,
+    drag: 0.0,
+    start_color: Color { r: 1.0, g: 1.0, b: 1.0, a: 0.4 },
+    end_color: Color { r: 1.0, g: 1.0, b: 1.0, a: 0.0 },
+    start_size: 0.3,
+    end_size: 0.05
+};
+
+//Sprayed along the ball's new direction on every bounce, colored by whatever was hit
+const SPARK_COUNT: usize = 16;
+const SPARK_STYLE: ParticleStyle = ParticleStyle {
+    lifetime: 0.35,
+    direction: 0.0,
+    spread: 0.9,
+    min_speed: 2.0,
+    max_speed: 7.0,
+    drag: 4.0,
+    start_color: Color::WHITE,
+    end_color: Color::WHITE,
+    start_size: 0.15,
+    end_size: 0.0
+};
+
+//Bursts out in every direction from where the ball left the arena
+const EXPLOSION_COUNT: usize = 150;
+const EXPLOSION_STYLE: ParticleStyle = ParticleStyle {
+    lifetime: 1.2,
+    direction: 0.0,
+    spread: std::f32::consts::PI,
+    min_speed: 1.0,
+    max_speed: 10.0,
+    drag: 2.5,
+    start_color: Color::WHITE,
+    end_color: Color::WHITE,
+    start_size: 0.35,
+    end_size: 0.0
+};
 
-const AXIS_MAX: f32 = 32768.0;
+#[derive(Default)]
+struct TotalTime(f32);
+
+#[derive(Default)]
+struct Paused(bool);
+
+//People watching the online match, shown under the arena when there are any
+#[derive(Default)]
+struct Spectators(u32);
 
-const BOUNCE_OFFSET: f32 = 15.0;
+//Position and speed of the replay being watched, None when playing
+#[derive(Default)]
+struct ReplayStatus(Option<String>);
 
-const BALL_VERTICES: [Vertex; 4] = [Vertex { position: Vec2{ x: -0.05, y: 0.05} },
-                               Vertex { position: Vec2{ x: 0.05, y: 0.05}  },
-                               Vertex { position: Vec2{ x: 0.05, y: -0.05} },
-                               Vertex { position: Vec2{ x: -0.05, y: -0.05} }];
+struct DrawScore;
 
-const PADDLE_VERTICES: [Vertex; 4] = [Vertex { position: Vec2{ x: -0.07, y: 0.2} },
-                               Vertex { position: Vec2{ x: 0.07, y: 0.2}  },
-                               Vertex { position: Vec2{ x: 0.07, y: -0.2} },
-                               Vertex { position: Vec2{ x: -0.07, y: -0.2} }];
+impl<'a> System<'a> for DrawScore {
+    type SystemData = (Read<'a, Score>, ReadExpect<'a, Camera>, Write<'a, TextQueue>);
 
-const WALL_VERTICES: [Vertex; 4] = [Vertex { position: Vec2{ x: -1.0, y: 0.05} },
-                               Vertex { position: Vec2{ x: 1.0, y: 0.05}  },
-                               Vertex { position: Vec2{ x: 1.0, y: -0.05} },
-                               Vertex { position: Vec2{ x: -1.0, y: -0.05} }];
+    fn run(&mut self, (score, camera, mut text_queue): Self::SystemData) {
+        //Scores sit just inside the top of the arena, halfway between the center line and each paddle
+        let left = camera.world_to_screen(Vec2::new(-ARENA_WIDTH / 4.0, -ARENA_HEIGHT / 2.0));
+        let right = camera.world_to_screen(Vec2::new(ARENA_WIDTH / 4.0, -ARENA_HEIGHT / 2.0));
+        //Player 2 defends the left side of the screen
+        text_queue.text_centered(&score.0[1].to_string(), Vec2::new(left.x, left.y + 24.0), SCORE_TEXT_SCALE, PLAYER_COLORS[1]);
+        text_queue.text_centered(&score.0[0].to_string(), Vec2::new(right.x, right.y + 24.0), SCORE_TEXT_SCALE, PLAYER_COLORS[0]);
+    }
+}
 
+//Who is playing each paddle, under their score
+struct DrawPlayers;
 
-const INDICES: [u32; 6] = [0,1,2,0,2,3];
+impl<'a> System<'a> for DrawPlayers {
+    type SystemData = (Read<'a, SlotLabels>, ReadExpect<'a, Camera>, Write<'a, TextQueue>);
 
-#[derive(Component)]
-#[storage(VecStorage)]
-struct Ball {
-    left_paddle: Entity,
-    right_paddle: Entity
+    fn run(&mut self, (labels, camera, mut text_queue): Self::SystemData) {
+        //Player 2 defends the left side of the screen
+        for (idx, label) in labels.0.iter().enumerate() {
+            let x = if idx == 0 { ARENA_WIDTH / 4.0 } else { -ARENA_WIDTH / 4.0 };
+            let position = camera.world_to_screen(Vec2::new(x, ARENA_HEIGHT / 2.0));
+            text_queue.text_centered(label, Vec2::new(position.x, position.y - 24.0), 1.0, PLAYER_COLORS[idx % PLAYER_COLORS.len()]);
+        }
+    }
 }
 
-impl Ball {
-    fn new(left_paddle: Entity, right_paddle: Entity) -> Ball {
-        Ball {
-            left_paddle,
-            right_paddle
+struct DrawSpectators;
+
+impl<'a> System<'a> for DrawSpectators {
+    type SystemData = (Read<'a, Spectators>, ReadExpect<'a, Camera>, Write<'a, TextQueue>);
+
+    fn run(&mut self, (spectators, camera, mut text_queue): Self::SystemData) {
+        if spectators.0 == 0 {
+            return;
         }
+        let position = camera.world_to_screen(Vec2::new(0.0, ARENA_HEIGHT / 2.0));
+        let text = if spectators.0 == 1 { "1 watching".to_string() } else { format!("{} watching", spectators.0) };
+        text_queue.text_centered(&text, Vec2::new(position.x, position.y - 24.0), 1.0, WALL_COLOR);
     }
 }
 
-#[derive(Component)]
-#[storage(VecStorage)]
-struct Paddle {
-    player_idx: u32
+struct DrawReplayStatus;
+
+impl<'a> System<'a> for DrawReplayStatus {
+    type SystemData = (Read<'a, ReplayStatus>, ReadExpect<'a, Camera>, Write<'a, TextQueue>);
+
+    fn run(&mut self, (status, camera, mut text_queue): Self::SystemData) {
+        if let Some(status) = status.0.as_ref() {
+            let position = camera.world_to_screen(Vec2::new(0.0, ARENA_HEIGHT / 2.0));
+            text_queue.text_centered(status, Vec2::new(position.x, position.y - 24.0), 1.0, Color::WHITE);
+        }
+    }
 }
 
-#[derive(Default)]
-struct DeltaTime(f32);
+struct DrawPause;
 
-#[derive(Default)]
-struct TotalTime(f32);
+impl<'a> System<'a> for DrawPause {
+    type SystemData = (Read<'a, Paused>, ReadExpect<'a, Camera>, Write<'a, TextQueue>);
 
-struct ControllerState {
-    left_axis_x: f32,
-    left_axis_y: f32
+    fn run(&mut self, (paused, camera, mut text_queue): Self::SystemData) {
+        if !paused.0 {
+            return;
+        }
+        let center = camera.world_to_screen(Vec2::new(0.0, 0.0));
+        text_queue.text_centered("PAUSED", center, SCORE_TEXT_SCALE, Color::WHITE);
+    }
 }
 
-#[derive(Default)]
-struct Controllers(std::vec::Vec<ControllerState>);
-
-struct UpdateBall;
-
-impl<'a> System<'a> for UpdateBall {
-    type SystemData = (ReadStorage<'a, Ball>, WriteStorage<'a, TransformComponent>, WriteStorage<'a, PhysicsComponent>, Read<'a, DeltaTime>);
-
-    fn run(&mut self, (ball_storage, mut transform_storage, mut physics_storage, deltatime): Self::SystemData) {
-        use specs::Join;
-        let deltatime = deltatime.0;
-        for (ball, t, phys_c) in (&ball_storage, &mut transform_storage, &mut physics_storage).join() {
-            //Check for collision against paddles
-            for other_collider in phys_c.collided_objects.iter() {
-                let other_entity = other_collider.other;
-                let mtv = other_collider.mtv;
-                if other_entity == ball.left_paddle {
-                    let mut rng = thread_rng();
-                    let angle = rng.gen_range(-1.0 * BOUNCE_OFFSET, 1.0 * BOUNCE_OFFSET);
-                    let y_offset = angle.to_radians().sin();
-                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
-                    phys_c.velocity.y += y_offset;
-                } else if other_entity == ball.right_paddle {
-                    let mut rng = thread_rng();
-                    let angle = rng.gen_range(-1.0 * BOUNCE_OFFSET, 1.0 * BOUNCE_OFFSET);
-                    let y_offset = angle.to_radians().sin();
-                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
-                    phys_c.velocity.y += y_offset;
-                } else {
-                    phys_c.velocity = phys_c.velocity.reflect(&mtv);
-                }
-            }
-            t.position.x = t.position.x + phys_c.velocity.x * deltatime;
-            t.position.y = t.position.y + phys_c.velocity.y * deltatime;
-
-            //Check for score conditions
-            let mut reset = false;
-            if t.position.x > 1.3 {
-                println!("Player 2 has scored!");
-                reset = true;
-            } else if t.position.x < -1.3 {
-                println!("Player 1 has scored!");
-                reset = true;
-            }
+//Camera shake is purely visual, so it runs on real frame time rather than the simulation tick
+struct UpdateCamera {
+    reader: ReaderId<GameEvent>
+}
+
+impl<'a> System<'a> for UpdateCamera {
+    type SystemData = (Read<'a, FrameTime>, Read<'a, EventChannel<GameEvent>>, WriteExpect<'a, Camera>);
 
-            if reset {
-                t.position = Vec2::new(0.0, 0.0);
-                let mut rng = thread_rng();
-                let angle: f32 = rng.gen_range(0.0, 360.0);
-                let x = angle.to_radians().cos();
-                let y = angle.to_radians().sin();
-                phys_c.velocity = 0.5 * Vec2::new(x, y);
+    fn run(&mut self, (frame_time, events, mut camera): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            if let GameEvent::Goal { .. } = event {
+                camera.shake(SCORE_SHAKE);
             }
         }
+        camera.update(frame_time.0);
     }
 }
 
-struct UpdatePaddles;
+//Sparks when the ball bounces and an explosion in the scorer's color on a goal
+struct SpawnEffects {
+    reader: ReaderId<GameEvent>
+}
 
-impl<'a> System<'a> for UpdatePaddles {
-    type SystemData = (ReadStorage<'a, Paddle>, WriteStorage<'a, TransformComponent>, Read<'a, Controllers>);
+impl<'a> System<'a> for SpawnEffects {
+    type SystemData = (Read<'a, EventChannel<GameEvent>>, WriteExpect<'a, Particles>);
+
+    fn run(&mut self, (events, mut particles): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::BallHit { position, velocity, paddle } => {
+                    let color = match paddle {
+                        None => WALL_COLOR,
+                        Some(idx) => PLAYER_COLORS[idx as usize]
+                    };
+                    let style = ParticleStyle {
+                        direction: velocity.y.atan2(velocity.x),
+                        start_color: color,
+                        end_color: Color { a: 0.0, ..color },
+                        ..SPARK_STYLE
+                    };
+                    particles.burst(position, SPARK_COUNT, &style);
+                },
+                GameEvent::Goal { scorer, position } => {
+                    let color = PLAYER_COLORS[scorer as usize];
+                    let style = ParticleStyle {
+                        start_color: Color::WHITE,
+                        end_color: Color { a: 0.0, ..color },
+                        ..EXPLOSION_STYLE
+                    };
+                    particles.burst(position, EXPLOSION_COUNT, &style);
+                },
+                GameEvent::MatchWon { .. } => {}
+            }
+        }
+    }
+}
 
-    fn run(&mut self, (paddle_storage, mut transform_storage, controller_storage): Self::SystemData) {
-        use specs::Join;
+//Blips for bounces and goals, panned to where they happened
+struct PlaySounds {
+    reader: ReaderId<GameEvent>
+}
+
+impl<'a> System<'a> for PlaySounds {
+    type SystemData = (Read<'a, EventChannel<GameEvent>>, Write<'a, SoundQueue>);
 
-        for (paddle, t) in (&paddle_storage, &mut transform_storage).join() {
-            let position = if paddle.player_idx < controller_storage.0.len() as u32 {
-                controller_storage.0[paddle.player_idx as usize].left_axis_y
-            } else {
-                0.0
+    fn run(&mut self, (events, mut sounds): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            let (effect, position) = match *event {
+                GameEvent::BallHit { position, paddle: Some(_), .. } => (SoundEffect::PaddleHit, position),
+                GameEvent::BallHit { position, paddle: None, .. } => (SoundEffect::WallHit, position),
+                GameEvent::Goal { position, .. } => (SoundEffect::Goal, position),
+                GameEvent::MatchWon { .. } => continue
             };
-            t.position.y = position;
+            let pan = position.x / (ARENA_WIDTH / 2.0);
+            sounds.play(effect, 1.0, pan);
         }
     }
 }
 
+//A thump for the player that hit the ball, harder the faster it's going, and longer ones for goals and wins
+struct PlayRumble {
+    reader: ReaderId<GameEvent>
+}
+
+impl<'a> System<'a> for PlayRumble {
+    type SystemData = (Read<'a, EventChannel<GameEvent>>, Write<'a, RumbleQueue>);
+
+    fn run(&mut self, (events, mut rumble): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            match *event {
+                GameEvent::BallHit { velocity, paddle: Some(player), .. } => {
+                    let speed = (velocity.x * velocity.x + velocity.y * velocity.y).sqrt();
+                    let strength = (speed / (BALL_SPEED * 1.5)).min(1.0);
+                    rumble.rumble(player, strength, strength * 0.5, 80);
+                },
+                GameEvent::BallHit { paddle: None, .. } => {},
+                GameEvent::Goal { scorer, .. } => {
+                    rumble.rumble(1 - scorer, 1.0, 0.3, 400);
+                },
+                GameEvent::MatchWon { winner } => {
+                    rumble.rumble(winner, 0.6, 1.0, 1000);
+                }
+            }
+        }
+    }
+}
+
+struct DrawFps {
+    average_frame_time: f32
+}
+
+impl<'a> System<'a> for DrawFps {
+    type SystemData = (Read<'a, FrameTime>, Write<'a, TextQueue>);
+
+    fn run(&mut self, (frame_time, mut text_queue): Self::SystemData) {
+        //Smooth out the frame time so the counter is readable
+        self.average_frame_time = self.average_frame_time * 0.95 + frame_time.0 * 0.05;
+        let fps = if self.average_frame_time > 0.0 {
+            1.0 / self.average_frame_time
+        } else {
+            0.0
+        };
+        let text = format!("{:.0} FPS {:.2}ms", fps, self.average_frame_time * 1000.0);
+        let size = Vec2::new(text::text_width(&text, 1.0) + 8.0, 20.0);
+        text_queue.rect(Vec2::new(0.0, 0.0), size, Color { r: 0.0, g: 0.0, b: 0.0, a: 0.5 });
+        text_queue.text(&text, Vec2::new(4.0, 2.0), 1.0, Color::WHITE);
+    }
+}
+
 fn main() {
+    let mut settings = Settings::load();
+    let options = CommandLine::parse();
+
+    //Vsync would cap the benchmark at the display's refresh rate
+    if options.bench_render.is_some() {
+        settings.video.vsync = VsyncMode::Off;
+        settings.video.frame_limit = None;
+    }
+
+    if let Some(seconds) = options.discover {
+        if let Err(e) = discovery::print_games(seconds) {
+            println!("Failed to listen for games: {}", e);
+        }
+        return;
+    }
+
+    if let Some(path) = options.verify_replay.as_ref() {
+        if let Err(e) = replay::run_verify(path) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    //Replays are watched rather than played, their inputs take the place of the controllers
+    let mut playback = match options.replay.as_ref() {
+        None => {
+            None
+        },
+        Some(path) => {
+            match Replay::load(path) {
+                Err(e) => {
+                    println!("Failed to load replay: {}", e);
+                    return;
+                },
+                Ok(replay) => {
+                    Some(Playback::new(replay))
+                }
+            }
+        }
+    };
+
+    //Hosts are listed in lobbies on the local network, and stay listed once the match starts so people can find it to watch
+    let announcer = match options.host {
+        None => {
+            None
+        },
+        Some(port) => {
+            let name = options.name.clone().unwrap_or_else(discovery::default_name);
+            match Announcer::start(Beacon::new(&name, GameMode::Netplay, netplay::PROTOCOL_VERSION, port, 1, PLAYER_COUNT as u16)) {
+                Err(e) => {
+                    println!("Failed to announce on the local network: {}", e);
+                    None
+                },
+                Ok(announcer) => {
+                    Some(announcer)
+                }
+            }
+        }
+    };
+
+    //Online matches connect before the window opens, both players need the host's seed to start
+    let input_delay = options.input_delay.unwrap_or(netplay::DEFAULT_INPUT_DELAY);
+    let max_rollback = options.max_rollback.unwrap_or(netplay::DEFAULT_MAX_ROLLBACK);
+    let host_timeout = options.host_timeout.map_or(netplay::DEFAULT_HOST_TIMEOUT, |seconds| std::time::Duration::from_secs(seconds as u64));
+    let connection = match (options.host, &options.join) {
+        (Some(port), _) => Some(NetSession::host(port, input_delay, max_rollback, host_timeout)),
+        (None, Some(address)) => Some(NetSession::join(address)),
+        (None, None) => None
+    };
+    let mut online = match connection {
+        None => {
+            None
+        },
+        Some(Err(e)) => {
+            println!("Failed to start online match: {}", e);
+            return;
+        },
+        Some(Ok(session)) => {
+            Some(Rollback::new(session))
+        }
+    };
+    if let Some(announcer) = announcer.as_ref() {
+        announcer.set_players(PLAYER_COUNT as u16);
+    }
+
+    //The host lets people watch on the port after the netplay one, spectating never holds up the match so failing to listen isn't fatal
+    let mut spectator_host = match (options.host, online.is_some()) {
+        (Some(port), true) => {
+            match SpectatorHost::new(port.wrapping_add(1)) {
+                Err(e) => {
+                    println!("{}", e);
+                    None
+                },
+                Ok(host) => {
+                    Some(host)
+                }
+            }
+        },
+        _ => None
+    };
+
+    //Matches on a dedicated server are simulated there, this world only shows the states it sends
+    //Spectators see matches the same way, just further behind
+    let room = options.room.as_deref().unwrap_or(protocol::DEFAULT_ROOM);
+    let delay_ticks = |seconds: f32| (seconds / TICK_RATE).round().max(1.0) as u32;
+    let interpolation_delay = delay_ticks(settings.online.interpolation_delay);
+    let spectator_delay = delay_ticks(settings.online.spectator_delay);
+    let connection = match (&options.server, &options.spectate) {
+        (Some(address), _) => Some(RemoteMatch::join(address, room, interpolation_delay)),
+        (None, Some(address)) => Some(RemoteMatch::watch(address, room, spectator_delay)),
+        (None, None) => None
+    };
+    let mut remote = match connection {
+        None => {
+            None
+        },
+        Some(Err(e)) => {
+            println!("Failed to connect to server: {}", e);
+            return;
+        },
+        Some(Ok(remote)) => {
+            Some(remote)
+        }
+    };
+
     let mut world = World::new();
-    world.register::<PhysicsComponent>();
-    world.register::<Ball>();
-    world.register::<Paddle>();
+    let seed = match (online.as_ref(), playback.as_ref()) {
+        (Some(online), _) => online.session.seed,
+        (None, Some(playback)) => playback.seed(),
+        (None, None) => rand::random()
+    };
+    game::init(&mut world, seed);
     world.register::<RenderComponent>();
-    world.register::<TransformComponent>();
+    world.register::<Sprite>();
+    world.register::<Instanced>();
+    world.register::<ParticleEmitter>();
 
     let sdl_context = sdl2::init().unwrap();
 
-    //Print off information about connected controllers
+    //Controllers are opened as they're connected, including the ones already plugged in
     let controller_system = sdl_context.game_controller().unwrap();
-
-    let num_sticks = controller_system.num_joysticks().unwrap();
-    println!("{} game controllers are connected", num_sticks);
-
-    let mut controllers = Vec::new();
-    let mut controller_data = Vec::new();
-    for i in 0..num_sticks {
-        let name = controller_system.name_for_index(i).unwrap();
-        println!("{}", name);
-        if controller_system.is_game_controller(i) {
-            let mut c = controller_system.open(i).unwrap();
-            c.set_rumble(0xffff, 0xffff, 300).unwrap();
-            controllers.push(c);
-            let c_data = ControllerState {
-                left_axis_x: 0.0,
-                left_axis_y: 0.0
-            };
-            controller_data.push(c_data);
+    let mut players = Players::new(controller_system, PLAYER_COUNT, settings.players.fallback);
+    //Read from the local controllers every frame, and copied into the world as PlayerInputs every tick
+    let mut local_controllers = Controllers((0..PLAYER_COUNT).map(|_| ControllerState::default()).collect());
+
+    //The game still runs without sound if there's no audio device
+    let mut audio = if settings.audio.enabled {
+        match Audio::new(&sdl_context, &settings.audio) {
+            Err(e) => {
+                println!("Failed to open audio device: {}", e);
+                None
+            },
+            Ok(audio) => {
+                Some(audio)
+            }
         }
-    }
+    } else {
+        None
+    };
+
     let video_context = sdl_context.video().unwrap();
     let mut events = sdl_context.event_pump().unwrap();
-    let window = video_context.window("Pong2", 640, 480).vulkan().build().unwrap();
+    let (window_width, window_height) = settings.video.window_size;
+    let window = video_context.window("Pong2", window_width, window_height).vulkan().build().unwrap();
 
-    world.add_resource(DeltaTime(0.01));
     world.add_resource(TotalTime(0.0));
-    world.add_resource(Controllers(controller_data));
+    world.add_resource(Paused(false));
+    world.add_resource(FrameTime(0.0));
+    world.add_resource(TextQueue::default());
+    world.add_resource(Camera::new(window_width, window_height));
+    world.add_resource(Profiler::default());
+    world.add_resource(DebugDraw::default());
+    world.add_resource(SoundQueue::default());
+    world.add_resource(RumbleQueue::default());
+    world.add_resource(SlotLabels::default());
+    world.add_resource(Spectators::default());
+    world.add_resource(ReplayStatus::default());
 
     let num_threads = num_cpus::get();
     let thread_pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
     let thread_pool = std::sync::Arc::new(thread_pool);
 
-    let mut renderer = render::RenderContext::new(&window, 640, 480, thread_pool.clone(), num_threads);
+    let mut renderer = render::RenderContext::new(&window, window_width, window_height, &settings.video, &settings.post, thread_pool.clone(), num_threads);
 
-     let paddle1 = {
-        let transform = TransformComponent {
-            position: Vec2::new(0.9, 0.0)
-        };
-        let physics = PhysicsComponent::new(&PADDLE_VERTICES);
-        let paddle = Paddle {
-            player_idx: 0
-        };
+    let particle_mesh = MeshData::load(PARTICLE_MESH).unwrap();
+    world.add_resource(Particles::new(renderer.load_mesh(&particle_mesh)));
+
+    if let Some(count) = options.bench_render {
+        bench::run_render_benchmark(&mut world, &mut renderer, count);
+        return;
+    }
 
-        let model = RenderComponent::new(&mut renderer, &PADDLE_VERTICES, &INDICES);
-        world.create_entity().with(transform).with(paddle).with(model).with(physics).build()
+    //Skins are optional, anything that fails to load is drawn as a plain colored quad instead
+    let mut load_skin = |path: &Option<String>| -> Option<TextureHandle> {
+        match path {
+            None => None,
+            Some(path) => match renderer.load_texture(path) {
+                Err(e) => {
+                    println!("{}", e);
+                    None
+                },
+                Ok(texture) => {
+                    Some(texture)
+                }
+            }
+        }
     };
+    let ball_skin = load_skin(&settings.skins.ball);
+    let paddle_skin = load_skin(&settings.skins.paddle);
+    let background = load_skin(&settings.skins.background);
+    if let Some(background) = background {
+        renderer.set_background(background);
+    }
 
-    let paddle2 = {
-        let transform = TransformComponent {
-            position: Vec2::new(-0.9, 0.0)
-        };
-        let physics = PhysicsComponent::new(&PADDLE_VERTICES);
+    let paddle_mesh = MeshData::load(PADDLE_MESH).unwrap();
+    let ball_mesh = MeshData::load(BALL_MESH).unwrap();
+    let wall_mesh = MeshData::load(WALL_MESH).unwrap();
+    let paddle_handle = renderer.load_mesh(&paddle_mesh);
+    let ball_handle = renderer.load_mesh(&ball_mesh);
+    let wall_handle = renderer.load_mesh(&wall_mesh);
+
+    let entities = game::create_entities(&mut world);
+    {
+        let mut models = world.write_storage::<RenderComponent>();
+        let mut sprites = world.write_storage::<Sprite>();
+        for (idx, paddle) in entities.paddles.iter().enumerate() {
+            models.insert(*paddle, RenderComponent::with_color(paddle_handle, PLAYER_COLORS[idx])).unwrap();
+            if let Some(texture) = paddle_skin {
+                sprites.insert(*paddle, Sprite { texture }).unwrap();
+            }
+        }
 
-        let paddle = Paddle {
-            player_idx: 1
-        };
-        let model = RenderComponent::new(&mut renderer, &PADDLE_VERTICES, &INDICES);
-        world.create_entity().with(transform).with(paddle).with(model).with(physics).build()
-    };
+        models.insert(entities.ball, RenderComponent::new(ball_handle)).unwrap();
+        if let Some(texture) = ball_skin {
+            sprites.insert(entities.ball, Sprite { texture }).unwrap();
+        }
+        world.write_storage::<ParticleEmitter>().insert(entities.ball, ParticleEmitter::new(TRAIL_STYLE, TRAIL_RATE)).unwrap();
+
+        for wall in entities.walls.iter() {
+            models.insert(*wall, RenderComponent::with_color(wall_handle, WALL_COLOR)).unwrap();
+        }
+    }
+
+    let mut dispatcher = game::add_systems(DispatcherBuilder::new())
+        .with_pool(thread_pool.clone())
+        .build();
 
-    let _ball = {
-        let transform = TransformComponent {
-            position: Vec2::new(0.0, 0.0)
+    //Systems that run once per frame rather than once per simulation tick, such as the HUD
+    let mut frame_dispatcher = {
+        let mut events = world.write_resource::<EventChannel<GameEvent>>();
+        let update_camera = UpdateCamera {
+            reader: events.register_reader()
+        };
+        let spawn_effects = SpawnEffects {
+            reader: events.register_reader()
+        };
+        let play_sounds = PlaySounds {
+            reader: events.register_reader()
+        };
+        let play_rumble = PlayRumble {
+            reader: events.register_reader()
         };
-        let physics = PhysicsComponent::with_velocity(&BALL_VERTICES, Vec2::new(0.5, 0.0));
-        let model = RenderComponent::new(&mut renderer, &BALL_VERTICES, &INDICES);
-        let ball = Ball::new(paddle2, paddle1);
-        world.create_entity().with(model).with(ball).with(transform).with(physics).build();
+        drop(events);
+        let mut builder = DispatcherBuilder::new()
+            .with(Profiled::new("update_camera", update_camera), "update_camera", &[])
+            .with(Profiled::new("update_particles", UpdateParticles), "update_particles", &[])
+            .with(Profiled::new("spawn_effects", spawn_effects), "spawn_effects", &["update_particles"])
+            .with(Profiled::new("play_sounds", play_sounds), "play_sounds", &[])
+            .with(Profiled::new("play_rumble", play_rumble), "play_rumble", &[])
+            .with(Profiled::new("draw_score", DrawScore), "draw_score", &["update_camera"])
+            .with(Profiled::new("draw_pause", DrawPause), "draw_pause", &["update_camera"])
+            .with(Profiled::new("draw_players", DrawPlayers), "draw_players", &["update_camera"])
+            .with(Profiled::new("draw_spectators", DrawSpectators), "draw_spectators", &["update_camera"])
+            .with(Profiled::new("draw_replay_status", DrawReplayStatus), "draw_replay_status", &["update_camera"])
+            .with(DrawProfiler, "draw_profiler", &[])
+            .with(Profiled::new("draw_physics_debug", DrawPhysicsDebug), "draw_physics_debug", &["update_camera"]);
+        if settings.video.show_fps {
+            builder.add(Profiled::new("draw_fps", DrawFps { average_frame_time: 0.0 }), "draw_fps", &[]);
+        }
+        builder.with_pool(thread_pool).build()
     };
 
-    let _top_wall = {
-        let transform = TransformComponent {
-            position: Vec2::new(0.0, -0.9)
-        };
-        let physics = PhysicsComponent::new(&WALL_VERTICES);
-        let model = RenderComponent::new(&mut renderer, &WALL_VERTICES, &INDICES);
-        world.create_entity().with(transform).with(physics).with(model).build()
+    #[cfg(feature = "shader-hot-reload")]
+    let mut shader_watcher = if options.watch_shaders {
+        Some(shader_reload::ShaderWatcher::new())
+    } else {
+        None
     };
+    #[cfg(not(feature = "shader-hot-reload"))]
+    {
+        if options.watch_shaders {
+            println!("Shader hot reloading is disabled, rebuild with --features shader-hot-reload to use --watch-shaders");
+        }
+    }
 
-    let _bot_wall = {
-        let transform = TransformComponent {
-            position: Vec2::new(0.0, 0.9)
+    let mut frame_limiter = FrameLimiter::new(settings.video.frame_limit);
+
+    //Games picked in the lobby connect after the window opens, so the world is reseeded with the netplay host's seed
+    if options.lobby && online.is_none() && remote.is_none() && playback.is_none() {
+        let mut lobby = match Lobby::new() {
+            Err(e) => {
+                println!("Failed to open the lobby: {}", e);
+                return;
+            },
+            Ok(lobby) => {
+                lobby
+            }
         };
-        let physics = PhysicsComponent::new(&WALL_VERTICES);
-        let model = RenderComponent::new(&mut renderer, &WALL_VERTICES, &INDICES);
-        world.create_entity().with(transform).with(physics).with(model).build()
+        loop {
+            let game = match lobby.run(&mut events, &mut players, &mut world, &mut renderer, &mut frame_limiter) {
+                None => {
+                    return;
+                },
+                Some(game) => {
+                    game
+                }
+            };
+            match lobby::connect(&game, room, interpolation_delay, spectator_delay) {
+                Err(e) => {
+                    lobby.message = Some(format!("Failed to join {}: {}", game.beacon.name, e));
+                },
+                Ok(Connection::Netplay(rollback)) => {
+                    world.add_resource(GameRng::new(rollback.session.seed));
+                    online = Some(*rollback);
+                    break;
+                },
+                Ok(Connection::Remote(connected)) => {
+                    remote = Some(connected);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut haptics = Haptics::new(&settings.haptics);
+    let mut capture = Capture::new(&settings.capture);
+    if options.record {
+        capture.toggle_recording();
+    }
+
+    //A separate handle, so frames can be timed while the World is borrowed by the dispatchers
+    let profiler = world.read_resource::<Profiler>().clone();
+
+    //Matches on a server are simulated there, so there are no inputs here to record
+    let mut recording = if settings.replays.record && playback.is_none() && remote.is_none() {
+        Some(Replay::new(online.as_ref().map_or(seed, |online| online.session.seed)))
+    } else {
+        None
     };
+    if let Some(online) = online.as_mut() {
+        online.replay = recording.take();
+    }
 
-    let mut dispatcher = DispatcherBuilder::new()
-        .with(PhysicsSystem, "physics", &[])
-        .with(UpdateBall, "ball", &["physics"])
-        .with(UpdatePaddles, "paddles", &["physics"])
-        .with(renderer, "rendering", &["ball", "paddles"])
-        .with_pool(thread_pool)
-        .build();
+    let mut last_frame = std::time::Instant::now();
+    let mut accumulator = 0.0;
 
     'mainloop: loop {
+        let scope = profiler.scope("events");
         for event in events.poll_iter() {
+            match players.handle_event(&event) {
+                None => {},
+                Some(SlotEvent::Disconnected) => {
+                    world.write_resource::<Paused>().0 = true;
+                },
+                Some(SlotEvent::PausePressed) => {
+                    let mut paused = world.write_resource::<Paused>();
+                    paused.0 = !paused.0;
+                }
+            }
             match event {
                 sdl2::event::Event::Quit {..} => {
                     break 'mainloop
                 },
+                sdl2::event::Event::KeyDown { keycode: Some(SCREENSHOT_KEY), repeat: false, .. } => {
+                    capture.screenshot();
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(RECORD_KEY), repeat: false, .. } => {
+                    capture.toggle_recording();
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(PAUSE_KEY), repeat: false, .. } => {
+                    let mut paused = world.write_resource::<Paused>();
+                    paused.0 = !paused.0;
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(STEP_KEY), .. } => {
+                    if let Some(playback) = playback.as_mut() {
+                        playback.step();
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(SLOWER_KEY), repeat: false, .. } => {
+                    if let Some(playback) = playback.as_mut() {
+                        playback.slower();
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(FASTER_KEY), repeat: false, .. } => {
+                    if let Some(playback) = playback.as_mut() {
+                        playback.faster();
+                    }
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(DEBUG_DRAW_KEY), repeat: false, .. } => {
+                    let mut debug_draw = world.write_resource::<DebugDraw>();
+                    debug_draw.enabled = !debug_draw.enabled;
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(PROFILER_KEY), repeat: false, .. } => {
+                    profiler.toggle_overlay();
+                },
+                sdl2::event::Event::KeyDown { keycode: Some(TRACE_KEY), repeat: false, .. } => {
+                    let path = format!("{}/trace-{}.json", settings.capture.directory, capture::timestamp());
+                    let result = std::fs::create_dir_all(&settings.capture.directory).map_err(|e| e.to_string()).and_then(|_| profiler.export_trace(&path));
+                    match result {
+                        Err(e) => {
+                            println!("Failed to export trace: {}", e);
+                        },
+                        Ok(()) => {
+                            println!("Saved trace {}", path);
+                        }
+                    }
+                },
                 _ => {}
             }
         }
-        let mut controller_data = world.write_resource::<Controllers>();
-        for (i, controller) in controllers.iter().enumerate() {
-            let x = controller.axis(sdl2::controller::Axis::LeftX);
-            let y = controller.axis(sdl2::controller::Axis::LeftY);
-            let x = x as f32 / AXIS_MAX;
-            let y = y as f32 / AXIS_MAX;
-            controller_data.0[i].left_axis_x = x;
-            controller_data.0[i].left_axis_y = y;
-        }
-        drop(controller_data);
-        let mut time = world.write_resource::<TotalTime>();
-        let dt = world.read_resource::<DeltaTime>();
-        time.0 += dt.0;
-        drop(time);
-        drop(dt);
-        dispatcher.dispatch(&mut world.res);
-        world.maintain();
+        let now = std::time::Instant::now();
+        let frame_time = now.duration_since(last_frame);
+        last_frame = now;
+        players.update(&events.keyboard_state(), frame_time.as_secs_f32(), &mut local_controllers);
+        let mut labels = players.labels();
+        let online_player = match (online.as_ref(), remote.as_ref()) {
+            (Some(online), _) => Some(online.session.local_player()),
+            (None, Some(remote)) => remote.player,
+            (None, None) => None
+        };
+        let watching = remote.as_ref().is_some_and(|remote| remote.player.is_none());
+        if let Some(online_player) = online_player {
+            //Online, only the first local slot plays and the other side is the remote player
+            let local_label = labels[0].split_off(3);
+            labels = (0..PLAYER_COUNT).map(|idx| {
+                if idx == online_player { format!("P{} {}", idx + 1, local_label) } else { format!("P{} Online", idx + 1) }
+            }).collect();
+        } else if watching {
+            labels = (0..PLAYER_COUNT).map(|idx| format!("P{} Online", idx + 1)).collect();
+        } else if playback.is_some() {
+            labels = (0..PLAYER_COUNT).map(|idx| format!("P{} Replay", idx + 1)).collect();
+        }
+        world.write_resource::<SlotLabels>().0 = labels;
+        drop(scope);
+
+        let speed = playback.as_ref().map_or(1.0, |playback| playback.speed());
+        accumulator += frame_time.as_secs_f32().min(MAX_FRAME_TIME) * speed;
+        world.write_resource::<FrameTime>().0 = frame_time.as_secs_f32();
+
+        //Run as many simulation ticks as have elapsed since the last frame
+        let scope = profiler.scope("simulation");
+        let dt = TICK_RATE;
+        //The other player keeps playing, so online matches can't be paused
+        let paused = world.read_resource::<Paused>().0 && online_player.is_none() && !watching;
+        if paused {
+            accumulator = 0.0;
+            if playback.as_mut().is_some_and(|playback| playback.take_step()) {
+                accumulator = dt;
+            }
+        }
+        if let Some(remote) = remote.as_mut() {
+            //The server runs the match, the world is just moved to match the states it sends
+            accumulator = 0.0;
+            world.write_resource::<TotalTime>().0 += frame_time.as_secs_f32();
+            remote.send_input(PlayerInput::from_state(&local_controllers.0[0]));
+            if let Err(e) = remote.update(&mut world) {
+                println!("{}", e);
+                break 'mainloop;
+            }
+        }
+        while accumulator >= dt {
+            match online.as_mut() {
+                None => {
+                    let inputs: Vec<PlayerInput> = match playback.as_mut() {
+                        None => {
+                            local_controllers.0.iter().map(PlayerInput::from_state).collect()
+                        },
+                        Some(playback) => {
+                            match playback.next_inputs() {
+                                //The replay has ended, the last tick stays on screen
+                                None => {
+                                    accumulator = 0.0;
+                                    break;
+                                },
+                                Some(inputs) => {
+                                    inputs
+                                }
+                            }
+                        }
+                    };
+                    game::apply_inputs(&mut world, &inputs);
+                    dispatcher.dispatch(&mut world.res);
+                    world.maintain();
+                    if let Some(playback) = playback.as_mut() {
+                        playback.check(&world);
+                    }
+                    if let Some(recording) = recording.as_mut() {
+                        recording.record(&inputs, || game::checksum(&world));
+                    }
+                },
+                Some(online) => {
+                    match online.advance(&mut world, &mut dispatcher, PlayerInput::from_state(&local_controllers.0[0])) {
+                        Err(e) => {
+                            println!("{}", e);
+                            break 'mainloop;
+                        },
+                        //Too far ahead of the other player, wait for their inputs to catch up
+                        Ok(false) => {
+                            accumulator = accumulator.min(MAX_FRAME_TIME);
+                            break;
+                        },
+                        Ok(true) => {}
+                    }
+                }
+            }
+            world.write_resource::<TotalTime>().0 += dt;
+            accumulator -= dt;
+        }
+        world.write_resource::<ReplayStatus>().0 = playback.as_ref().map(|playback| playback.status(paused));
+        if let (Some(host), Some(online)) = (spectator_host.as_mut(), online.as_mut()) {
+            host.update(online);
+            online.session.set_spectator_count(host.spectator_count());
+        }
+        world.write_resource::<Spectators>().0 = match (online.as_ref(), remote.as_ref()) {
+            (Some(online), _) => online.session.spectator_count(),
+            (None, Some(remote)) => remote.spectators,
+            (None, None) => 0
+        };
+        //AI players are moved by the simulation, so taking over from one starts where its paddle is
+        for (local, state) in local_controllers.0.iter_mut().zip(world.read_resource::<Controllers>().0.iter()) {
+            if local.ai {
+                local.left_axis_y = state.left_axis_y;
+            }
+        }
+        drop(scope);
+
+        #[cfg(feature = "shader-hot-reload")]
+        {
+            if let Some(code) = shader_watcher.as_mut().and_then(|watcher| watcher.poll()) {
+                renderer.reload_shaders(&code);
+            }
+        }
+
+        if capture.update(frame_time.as_secs_f32()) {
+            renderer.request_capture();
+        }
+
+        let scope = profiler.scope("frame systems");
+        frame_dispatcher.dispatch(&world.res);
+        let mut sounds = world.write_resource::<SoundQueue>();
+        match audio.as_mut() {
+            None => {
+                sounds.clear();
+            },
+            Some(audio) => {
+                audio.play_queued(&mut sounds);
+                audio.play_music(if paused { MusicTrack::Menu } else { MusicTrack::Game });
+            }
+        }
+        drop(sounds);
+        haptics.apply(&mut players, &mut world.write_resource::<RumbleQueue>());
+        drop(scope);
+
+        let scope = profiler.scope("render");
+        renderer.run_now(&world.res);
+        drop(scope);
+        if let Some(image) = renderer.take_capture() {
+            capture.frame_captured(image);
+        }
+
+        let scope = profiler.scope("frame limiter");
+        frame_limiter.wait();
+        drop(scope);
+        profiler.end_frame();
+    }
+
+    if let Some(online) = online.as_mut() {
+        online.session.disconnect();
+    }
+    if let Some(remote) = remote.as_mut() {
+        remote.disconnect();
+    }
+    let recording = online.as_mut().and_then(|online| online.replay.take()).or(recording);
+    if let Some(recording) = recording.filter(|recording| recording.ticks() > 0) {
+        let path = format!("{}/replay-{}.{}", settings.replays.directory, capture::timestamp(), replay::EXTENSION);
+        let result = std::fs::create_dir_all(&settings.replays.directory).map_err(|e| e.to_string()).and_then(|_| recording.save(&path));
+        match result {
+            Err(e) => {
+                println!("Failed to save replay: {}", e);
+            },
+            Ok(()) => {
+                println!("Saved replay {}", path);
+            }
+        }
     }
+    capture.finish();
     
 }
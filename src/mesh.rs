@@ -0,0 +1,145 @@
+use serde::Deserialize;
+
+use crate::fy_math::Vec2;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Vertex {
+    pub position: Vec2,
+    pub uv: Vec2
+}
+
+//CPU side copy of a mesh, used for building collision boxes as well as uploading to the GPU
+pub struct MeshData {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>
+}
+
+#[derive(Deserialize)]
+struct RonVertex {
+    position: (f32, f32),
+    uv: (f32, f32)
+}
+
+#[derive(Deserialize)]
+struct RonMesh {
+    vertices: Vec<RonVertex>,
+    indices: Vec<u32>
+}
+
+impl MeshData {
+    //Loads a .ron or .obj mesh, picked by the file extension
+    pub fn load(path: &str) -> Result<MeshData, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        MeshData::parse(path, &contents)
+    }
+
+    //Reads a mesh that's already in memory, path only picks the format and names the mesh in errors
+    pub fn parse(path: &str, contents: &str) -> Result<MeshData, String> {
+        let mesh = if path.ends_with(".ron") {
+            MeshData::from_ron(contents)
+        } else if path.ends_with(".obj") {
+            MeshData::from_obj(contents)
+        } else {
+            Err("unknown mesh format".to_string())
+        };
+        let mesh = mesh.map_err(|e| format!("Failed to load {}: {}", path, e))?;
+
+        if mesh.vertices.is_empty() || mesh.indices.len() % 3 != 0 {
+            return Err(format!("Failed to load {}: mesh must have vertices and whole triangles", path));
+        }
+        if let Some(idx) = mesh.indices.iter().find(|idx| **idx as usize >= mesh.vertices.len()) {
+            return Err(format!("Failed to load {}: index {} is out of range", path, idx));
+        }
+        Ok(mesh)
+    }
+
+    //(vertices: [(position: (x, y), uv: (u, v)), ...], indices: [...])
+    fn from_ron(contents: &str) -> Result<MeshData, String> {
+        let mesh: RonMesh = ron::de::from_str(contents).map_err(|e| e.to_string())?;
+        let vertices = mesh.vertices.iter().map(|v| Vertex {
+            position: Vec2::new(v.position.0, v.position.1),
+            uv: Vec2::new(v.uv.0, v.uv.1)
+        }).collect();
+        Ok(MeshData {
+            vertices,
+            indices: mesh.indices
+        })
+    }
+
+    //Only positions, texture coordinates and faces are read, z coordinates and normals are ignored
+    //Faces with more than 3 vertices are split into a triangle fan
+    fn from_obj(contents: &str) -> Result<MeshData, String> {
+        let mut positions = Vec::new();
+        let mut uvs = Vec::new();
+        let mut mesh = MeshData {
+            vertices: Vec::new(),
+            indices: Vec::new()
+        };
+        //Each distinct position/uv pair used by a face becomes one vertex
+        let mut vertex_lookup = std::collections::HashMap::new();
+
+        //OBJ indices start at 1, negative indices count back from the last element
+        let resolve = |idx: &str, len: usize, line_num: usize| -> Result<usize, String> {
+            let idx: i64 = idx.parse().map_err(|_| format!("line {}: bad index {}", line_num, idx))?;
+            let resolved = if idx < 0 { len as i64 + idx } else { idx - 1 };
+            if resolved < 0 || resolved >= len as i64 {
+                return Err(format!("line {}: index {} is out of range", line_num, idx));
+            }
+            Ok(resolved as usize)
+        };
+
+        for (line_num, line) in contents.lines().enumerate() {
+            let line_num = line_num + 1;
+            let mut parts = line.split_whitespace();
+            let floats = |parts: std::str::SplitWhitespace| -> Result<Vec<f32>, String> {
+                parts.map(|p| p.parse().map_err(|_| format!("line {}: bad number {}", line_num, p))).collect()
+            };
+            match parts.next() {
+                Some("v") => {
+                    let values = floats(parts)?;
+                    if values.len() < 2 {
+                        return Err(format!("line {}: vertex needs at least 2 coordinates", line_num));
+                    }
+                    positions.push(Vec2::new(values[0], values[1]));
+                },
+                Some("vt") => {
+                    let values = floats(parts)?;
+                    if values.len() < 2 {
+                        return Err(format!("line {}: texture coordinate needs 2 values", line_num));
+                    }
+                    //OBJ puts v = 0 at the bottom of the image, but +y points down the screen
+                    uvs.push(Vec2::new(values[0], 1.0 - values[1]));
+                },
+                Some("f") => {
+                    let mut face = Vec::new();
+                    for corner in parts {
+                        let mut refs = corner.split('/');
+                        let position = resolve(refs.next().unwrap_or(""), positions.len(), line_num)?;
+                        let uv = match refs.next() {
+                            None | Some("") => None,
+                            Some(uv) => Some(resolve(uv, uvs.len(), line_num)?)
+                        };
+                        let idx = *vertex_lookup.entry((position, uv)).or_insert_with(|| {
+                            mesh.vertices.push(Vertex {
+                                position: positions[position],
+                                uv: uv.map(|uv| uvs[uv]).unwrap_or(Vec2::new(0.0, 0.0))
+                            });
+                            mesh.vertices.len() as u32 - 1
+                        });
+                        face.push(idx);
+                    }
+                    if face.len() < 3 {
+                        return Err(format!("line {}: face needs at least 3 vertices", line_num));
+                    }
+                    for i in 1..face.len() - 1 {
+                        mesh.indices.extend_from_slice(&[face[0], face[i], face[i + 1]]);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Ok(mesh)
+    }
+}
@@ -0,0 +1,352 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use lewton::inside_ogg::OggStreamReader;
+
+use crate::config::TrackSettings;
+
+//Decoded blocks buffered ahead of the mixer, a few tenths of a second
+const BUFFERED_BLOCKS: usize = 8;
+//Frames read at a time from WAV files, OGG files are read a packet at a time
+const WAV_BLOCK_FRAMES: usize = 2048;
+
+//Reads a music file a piece at a time as interleaved samples
+trait Decoder: Send {
+    fn channels(&self) -> usize;
+    fn sample_rate(&self) -> u32;
+    //Returns None at the end of the file
+    fn read(&mut self) -> Result<Option<Vec<f32>>, String>;
+    //The next read starts at this frame
+    fn seek(&mut self, frame: u64) -> Result<(), String>;
+    //Loop start and end frames stored in the file, if any
+    fn loop_points(&self) -> (Option<u64>, Option<u64>) {
+        (None, None)
+    }
+}
+
+struct WavDecoder {
+    reader: hound::WavReader<BufReader<File>>
+}
+
+impl Decoder for WavDecoder {
+    fn channels(&self) -> usize {
+        self.reader.spec().channels as usize
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.reader.spec().sample_rate
+    }
+
+    fn read(&mut self) -> Result<Option<Vec<f32>>, String> {
+        let spec = self.reader.spec();
+        let count = WAV_BLOCK_FRAMES * spec.channels as usize;
+        let samples: Result<Vec<f32>, hound::Error> = match spec.sample_format {
+            hound::SampleFormat::Float => {
+                self.reader.samples::<f32>().take(count).collect()
+            },
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                self.reader.samples::<i32>().take(count).map(|sample| sample.map(|sample| sample as f32 * scale)).collect()
+            }
+        };
+
+        match samples {
+            Err(e) => {
+                Err(e.to_string())
+            },
+            Ok(samples) => {
+                if samples.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(samples))
+                }
+            }
+        }
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.reader.seek(frame as u32).map_err(|e| e.to_string())
+    }
+}
+
+struct OggDecoder {
+    path: String,
+    reader: OggStreamReader<BufReader<File>>,
+    //Frame the next packet starts at, unknown after seeking until the end of a page is reached
+    position: Option<u64>,
+    //Samples decoded since seeking, while the position is still unknown
+    pending: Vec<f32>,
+    //Frames before this are dropped, OGG files can only seek to the start of a page
+    skip_to: u64
+}
+
+fn open_ogg(path: &str) -> Result<OggStreamReader<BufReader<File>>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    OggStreamReader::new(BufReader::new(file)).map_err(|e| e.to_string())
+}
+
+impl Decoder for OggDecoder {
+    fn channels(&self) -> usize {
+        self.reader.ident_hdr.audio_channels as usize
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.reader.ident_hdr.audio_sample_rate
+    }
+
+    fn read(&mut self) -> Result<Option<Vec<f32>>, String> {
+        let channels = self.channels();
+        loop {
+            let packet = match self.reader.read_dec_packet_itl().map_err(|e| e.to_string())? {
+                None => {
+                    return Ok(None);
+                },
+                Some(packet) => {
+                    packet
+                }
+            };
+            let mut samples: Vec<f32> = packet.iter().map(|sample| *sample as f32 / 32768.0).collect();
+
+            let start = match self.position {
+                Some(position) => {
+                    position
+                },
+                None => {
+                    self.pending.append(&mut samples);
+                    let end = match self.reader.get_last_absgp() {
+                        None => {
+                            continue;
+                        },
+                        Some(end) => {
+                            end
+                        }
+                    };
+                    samples = std::mem::take(&mut self.pending);
+                    let start = end.saturating_sub((samples.len() / channels) as u64);
+                    //The page started after the frame we wanted, so read up to it from the beginning instead
+                    if start > self.skip_to {
+                        self.reader = open_ogg(&self.path)?;
+                        self.position = Some(0);
+                        continue;
+                    }
+                    start
+                }
+            };
+            let end = start + (samples.len() / channels) as u64;
+            self.position = Some(end);
+
+            if end <= self.skip_to {
+                continue;
+            }
+            if start < self.skip_to {
+                samples.drain(..(self.skip_to - start) as usize * channels);
+            }
+            return Ok(Some(samples));
+        }
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), String> {
+        self.pending.clear();
+        self.skip_to = frame;
+        if frame == 0 {
+            self.reader = open_ogg(&self.path)?;
+            self.position = Some(0);
+            return Ok(());
+        }
+        self.reader.seek_absgp_pg(frame).map_err(|e| e.to_string())?;
+        self.position = None;
+        Ok(())
+    }
+
+    //Uses the LOOPSTART and LOOPLENGTH comments many games tag their music with
+    fn loop_points(&self) -> (Option<u64>, Option<u64>) {
+        let tag = |name: &str| {
+            self.reader.comment_hdr.comment_list.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.trim().parse::<u64>().ok())
+        };
+        let start = tag("LOOPSTART");
+        let end = tag("LOOPLENGTH").map(|length| start.unwrap_or(0) + length);
+        (start, end)
+    }
+}
+
+fn open(path: &str) -> Result<Box<dyn Decoder>, String> {
+    let extension = std::path::Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "wav" => {
+            let reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+            Ok(Box::new(WavDecoder {
+                reader
+            }))
+        },
+        "ogg" => {
+            Ok(Box::new(OggDecoder {
+                path: path.to_string(),
+                reader: open_ogg(path)?,
+                position: Some(0),
+                pending: Vec::new(),
+                skip_to: 0
+            }))
+        },
+        _ => {
+            Err("only .wav and .ogg files are supported".to_string())
+        }
+    }
+}
+
+//Converts between sample rates by interpolating between neighbouring frames, good enough for music
+struct Resampler {
+    //Input frames per output frame
+    step: f64,
+    //Position of the next output frame, relative to the start of the next input block
+    time: f64,
+    //Last frame of the previous block, to interpolate across blocks
+    previous: [f32; 2]
+}
+
+impl Resampler {
+    fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler {
+            step: input_rate as f64 / output_rate as f64,
+            time: 0.0,
+            previous: [0.0; 2]
+        }
+    }
+
+    //Takes and returns interleaved stereo
+    fn process(&mut self, input: Vec<f32>) -> Vec<f32> {
+        if self.step == 1.0 {
+            return input;
+        }
+        let frames = (input.len() / 2) as isize;
+        if frames == 0 {
+            return input;
+        }
+        let previous = self.previous;
+        let frame = |idx: isize, channel: usize| {
+            if idx < 0 { previous[channel] } else { input[idx as usize * 2 + channel] }
+        };
+
+        let mut output = Vec::with_capacity((frames as f64 / self.step) as usize * 2 + 2);
+        while self.time < (frames - 1) as f64 {
+            let idx = self.time.floor() as isize;
+            let t = (self.time - idx as f64) as f32;
+            for channel in 0..2 {
+                let a = frame(idx, channel);
+                let b = frame(idx + 1, channel);
+                output.push(a + (b - a) * t);
+            }
+            self.time += self.step;
+        }
+        self.time -= frames as f64;
+        self.previous = [frame(frames - 1, 0), frame(frames - 1, 1)];
+        output
+    }
+}
+
+//Interleaved stereo decoded at the mixer's sample rate, fed from a decoding thread
+pub struct MusicStream {
+    blocks: Receiver<Vec<f32>>,
+    block: Vec<f32>,
+    position: usize
+}
+
+impl MusicStream {
+    //Starts decoding the track on its own thread, which stops when the stream is dropped
+    pub fn open(track: &TrackSettings, sample_rate: u32) -> Result<MusicStream, String> {
+        let decoder = open(&track.path).map_err(|e| format!("{}: {}", track.path, e))?;
+        let (loop_start, loop_end) = decoder.loop_points();
+        let loop_start = track.loop_start.or(loop_start).unwrap_or(0);
+        let loop_end = track.loop_end.or(loop_end);
+
+        let (sender, blocks) = sync_channel(BUFFERED_BLOCKS);
+        let path = track.path.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = run_decoder(decoder, loop_start, loop_end, sample_rate, sender) {
+                println!("Stopped playing {}: {}", path, e);
+            }
+        });
+
+        Ok(MusicStream {
+            blocks,
+            block: Vec::new(),
+            position: 0
+        })
+    }
+
+    //Returns None if the decoder has fallen behind, which plays as silence
+    pub fn next_frame(&mut self) -> Option<(f32, f32)> {
+        if self.position >= self.block.len() {
+            self.block = self.blocks.try_recv().ok()?;
+            self.position = 0;
+        }
+        let frame = (self.block[self.position], self.block[self.position + 1]);
+        self.position += 2;
+        Some(frame)
+    }
+}
+
+//Decodes and loops the track until the mixer drops its end of the channel
+fn run_decoder(mut decoder: Box<dyn Decoder>, loop_start: u64, loop_end: Option<u64>, sample_rate: u32, sender: SyncSender<Vec<f32>>) -> Result<(), String> {
+    let channels = decoder.channels();
+    if channels == 0 {
+        return Err("file has no channels".to_string());
+    }
+    let mut resampler = Resampler::new(decoder.sample_rate(), sample_rate);
+    let mut position: u64 = 0;
+    //Stops a track with nothing between its loop points from looping forever
+    let mut played_since_loop = false;
+
+    loop {
+        let mut samples = match decoder.read()? {
+            None => {
+                if !played_since_loop {
+                    return Err("nothing to play after the loop start".to_string());
+                }
+                decoder.seek(loop_start)?;
+                position = loop_start;
+                played_since_loop = false;
+                continue;
+            },
+            Some(samples) => {
+                samples
+            }
+        };
+
+        let mut frames = (samples.len() / channels) as u64;
+        let looped = match loop_end {
+            Some(end) if position + frames >= end => {
+                frames = end.saturating_sub(position);
+                samples.truncate(frames as usize * channels);
+                true
+            },
+            _ => false
+        };
+        position += frames;
+
+        //Mono is played on both sides, anything past the first two channels is dropped
+        let mut stereo = Vec::with_capacity(frames as usize * 2);
+        for frame in samples.chunks(channels) {
+            stereo.push(frame[0]);
+            stereo.push(if channels > 1 { frame[1] } else { frame[0] });
+        }
+        let block = resampler.process(stereo);
+        if !block.is_empty() {
+            played_since_loop = true;
+            if sender.send(block).is_err() {
+                return Ok(());
+            }
+        }
+
+        if looped {
+            if !played_since_loop {
+                return Err("nothing to play between the loop points".to_string());
+            }
+            decoder.seek(loop_start)?;
+            position = loop_start;
+            played_since_loop = false;
+        }
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::game::{PlayerInput, GameRng};
+
+//Peers only talk to each other if these match
+const MAGIC: u32 = 0x474e_4f50;
+pub const PROTOCOL_VERSION: u16 = 4;
+
+pub const DEFAULT_PORT: u16 = 7777;
+//Ticks between pressing a button and it taking effect, hides the round trip to the other player
+pub const DEFAULT_INPUT_DELAY: u32 = 3;
+//Ticks the game can run ahead of the other player's inputs by predicting them, 0 waits for every input like lockstep
+pub const DEFAULT_MAX_ROLLBACK: u32 = 10;
+
+//The match ends if nothing arrives from the other player for this long
+const TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+//How long a host waits for another player to join
+pub const DEFAULT_HOST_TIMEOUT: Duration = Duration::from_secs(120);
+//Unacknowledged inputs are sent again this often while waiting
+const RESEND_INTERVAL: Duration = Duration::from_millis(20);
+const HELLO_INTERVAL: Duration = Duration::from_millis(200);
+//Every this many ticks both peers swap a checksum of their world
+const CHECKSUM_INTERVAL: u32 = 30;
+//Local checksums kept until the other peer's arrive
+const CHECKSUM_HISTORY: usize = 64;
+const MAX_INPUTS_PER_PACKET: usize = 128;
+const MAX_PACKET_SIZE: usize = 1024;
+
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+const INPUTS: u8 = 2;
+const BYE: u8 = 3;
+
+const INPUT_AI: u8 = 1;
+
+//Somewhere to send and receive whole packets, without blocking
+pub trait Link {
+    fn send(&mut self, packet: &[u8]);
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+pub struct UdpLink {
+    socket: UdpSocket,
+    peer: SocketAddr
+}
+
+impl Link for UdpLink {
+    fn send(&mut self, packet: &[u8]) {
+        //Lost packets are resent anyway, so errors are ignored
+        let _ = self.socket.send_to(packet, self.peer);
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Err(_) => {
+                    return None;
+                },
+                Ok((size, from)) => {
+                    if from == self.peer {
+                        return Some(buffer[..size].to_vec());
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Delayed {
+    deliver_at: Instant,
+    packet: Vec<u8>
+}
+
+//One end of an in-process link that drops, delays and reorders packets like a bad connection
+pub struct SimulatedLink {
+    outgoing: Arc<Mutex<Vec<Delayed>>>,
+    incoming: Arc<Mutex<Vec<Delayed>>>,
+    rng: GameRng,
+    loss: f32,
+    latency: Duration,
+    jitter: Duration,
+    pub dropped: u32
+}
+
+impl SimulatedLink {
+    //loss is the chance of a packet being dropped, each packet is delayed by latency plus up to jitter
+    pub fn pair(loss: f32, latency: Duration, jitter: Duration, seed: u64) -> (SimulatedLink, SimulatedLink) {
+        let a = Arc::new(Mutex::new(Vec::new()));
+        let b = Arc::new(Mutex::new(Vec::new()));
+        let end = |outgoing: &Arc<Mutex<Vec<Delayed>>>, incoming: &Arc<Mutex<Vec<Delayed>>>, seed: u64| {
+            SimulatedLink {
+                outgoing: outgoing.clone(),
+                incoming: incoming.clone(),
+                rng: GameRng::new(seed),
+                loss,
+                latency,
+                jitter,
+                dropped: 0
+            }
+        };
+        (end(&a, &b, seed), end(&b, &a, seed.wrapping_add(1)))
+    }
+}
+
+impl Link for SimulatedLink {
+    fn send(&mut self, packet: &[u8]) {
+        if self.rng.range(0.0, 1.0) < self.loss {
+            self.dropped += 1;
+            return;
+        }
+        let jitter = self.jitter.mul_f32(self.rng.range(0.0, 1.0));
+        self.outgoing.lock().unwrap().push(Delayed {
+            deliver_at: Instant::now() + self.latency + jitter,
+            packet: packet.to_vec()
+        });
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let mut incoming = self.incoming.lock().unwrap();
+        let idx = incoming.iter().position(|delayed| delayed.deliver_at <= now)?;
+        Some(incoming.remove(idx).packet)
+    }
+}
+
+enum Message {
+    Hello,
+    Welcome {
+        seed: u64,
+        input_delay: u32,
+        max_rollback: u32
+    },
+    //inputs are the sender's inputs from start_tick on, ack is how many of the receiver's inputs the sender has
+    //spectators is how many are watching through the host, the other player's is ignored
+    Inputs {
+        ack: u32,
+        start_tick: u32,
+        inputs: Vec<PlayerInput>,
+        checksum: Option<(u32, u32)>,
+        spectators: u16
+    },
+    Bye
+}
+
+fn encode(sequence: u32, message: &Message) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+    packet.write_u32::<LittleEndian>(MAGIC).unwrap();
+    packet.write_u16::<LittleEndian>(PROTOCOL_VERSION).unwrap();
+    packet.write_u32::<LittleEndian>(sequence).unwrap();
+    match message {
+        Message::Hello => {
+            packet.write_u8(HELLO).unwrap();
+        },
+        Message::Welcome { seed, input_delay, max_rollback } => {
+            packet.write_u8(WELCOME).unwrap();
+            packet.write_u64::<LittleEndian>(*seed).unwrap();
+            packet.write_u32::<LittleEndian>(*input_delay).unwrap();
+            packet.write_u32::<LittleEndian>(*max_rollback).unwrap();
+        },
+        Message::Inputs { ack, start_tick, inputs, checksum, spectators } => {
+            packet.write_u8(INPUTS).unwrap();
+            packet.write_u32::<LittleEndian>(*ack).unwrap();
+            packet.write_u32::<LittleEndian>(*start_tick).unwrap();
+            packet.write_u8(inputs.len() as u8).unwrap();
+            for input in inputs.iter() {
+                packet.write_i16::<LittleEndian>(input.axis_y).unwrap();
+                packet.write_u8(if input.ai { INPUT_AI } else { 0 }).unwrap();
+            }
+            match checksum {
+                None => {
+                    packet.write_u8(0).unwrap();
+                },
+                Some((tick, checksum)) => {
+                    packet.write_u8(1).unwrap();
+                    packet.write_u32::<LittleEndian>(*tick).unwrap();
+                    packet.write_u32::<LittleEndian>(*checksum).unwrap();
+                }
+            }
+            packet.write_u16::<LittleEndian>(*spectators).unwrap();
+        },
+        Message::Bye => {
+            packet.write_u8(BYE).unwrap();
+        }
+    }
+    packet
+}
+
+//Returns None for packets from other games, other versions or that are cut short
+fn decode(packet: &[u8]) -> Option<(u32, Message)> {
+    let mut reader = Cursor::new(packet);
+    if reader.read_u32::<LittleEndian>().ok()? != MAGIC || reader.read_u16::<LittleEndian>().ok()? != PROTOCOL_VERSION {
+        return None;
+    }
+    let sequence = reader.read_u32::<LittleEndian>().ok()?;
+    let message = match reader.read_u8().ok()? {
+        HELLO => {
+            Message::Hello
+        },
+        WELCOME => {
+            Message::Welcome {
+                seed: reader.read_u64::<LittleEndian>().ok()?,
+                input_delay: reader.read_u32::<LittleEndian>().ok()?,
+                max_rollback: reader.read_u32::<LittleEndian>().ok()?
+            }
+        },
+        INPUTS => {
+            let ack = reader.read_u32::<LittleEndian>().ok()?;
+            let start_tick = reader.read_u32::<LittleEndian>().ok()?;
+            let count = reader.read_u8().ok()?;
+            let mut inputs = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                inputs.push(PlayerInput {
+                    axis_y: reader.read_i16::<LittleEndian>().ok()?,
+                    ai: reader.read_u8().ok()? & INPUT_AI != 0
+                });
+            }
+            let checksum = if reader.read_u8().ok()? != 0 {
+                Some((reader.read_u32::<LittleEndian>().ok()?, reader.read_u32::<LittleEndian>().ok()?))
+            } else {
+                None
+            };
+            Message::Inputs {
+                ack,
+                start_tick,
+                inputs,
+                checksum,
+                spectators: reader.read_u16::<LittleEndian>().ok()?
+            }
+        },
+        BYE => {
+            Message::Bye
+        },
+        _ => {
+            return None;
+        }
+    };
+    Some((sequence, message))
+}
+
+//Connection to the other player, swapping each player's input for every tick
+pub struct NetSession {
+    link: Box<dyn Link>,
+    //Which player this peer controls, the host is player 1
+    local_player: usize,
+    pub seed: u64,
+    input_delay: u32,
+    pub max_rollback: u32,
+    //Every input so far indexed by tick, the first input_delay ticks are empty for both players
+    local_inputs: Vec<PlayerInput>,
+    remote_inputs: Vec<PlayerInput>,
+    //How many of local_inputs the other player has acknowledged
+    remote_ack: u32,
+    send_sequence: u32,
+    //Highest sequence number received, older packets arriving out of order are ignored
+    receive_sequence: Option<u32>,
+    last_received: Instant,
+    last_sent: Instant,
+    local_checksums: VecDeque<(u32, u32)>,
+    remote_checksums: VecDeque<(u32, u32)>,
+    //People watching through the host, the host sends it along with its inputs
+    spectators: u16
+}
+
+impl NetSession {
+    pub fn new(link: Box<dyn Link>, local_player: usize, seed: u64, input_delay: u32, max_rollback: u32) -> NetSession {
+        let empty = vec![PlayerInput::default(); input_delay as usize];
+        NetSession {
+            link,
+            local_player,
+            seed,
+            input_delay,
+            max_rollback,
+            local_inputs: empty.clone(),
+            remote_inputs: empty,
+            remote_ack: 0,
+            send_sequence: 0,
+            receive_sequence: None,
+            last_received: Instant::now(),
+            last_sent: Instant::now() - RESEND_INTERVAL,
+            local_checksums: VecDeque::with_capacity(CHECKSUM_HISTORY),
+            remote_checksums: VecDeque::with_capacity(CHECKSUM_HISTORY),
+            spectators: 0
+        }
+    }
+
+    //Waits up to timeout for another player to join on port, then starts a match with a random seed
+    pub fn host(port: u16, input_delay: u32, max_rollback: u32, timeout: Duration) -> Result<NetSession, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("Failed to listen on port {}: {}", port, e))?;
+        socket.set_read_timeout(Some(HELLO_INTERVAL)).map_err(|e| e.to_string())?;
+        println!("Waiting for a player to join on port {}", port);
+        let start = Instant::now();
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let peer = loop {
+            if start.elapsed() > timeout {
+                return Err(format!("Nobody joined within {} seconds", timeout.as_secs()));
+            }
+            if let Ok((size, from)) = socket.recv_from(&mut buffer) {
+                if let Some((_, Message::Hello)) = decode(&buffer[..size]) {
+                    break from;
+                }
+            }
+        };
+        println!("{} joined", peer);
+
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let link = UdpLink {
+            socket,
+            peer
+        };
+        let mut session = NetSession::new(Box::new(link), 0, rand::random(), input_delay, max_rollback);
+        session.send(&Message::Welcome {
+            seed: session.seed,
+            input_delay,
+            max_rollback
+        });
+        Ok(session)
+    }
+
+    //Connects to a host at address, e.g. 192.168.0.2:7777, the port can be left out
+    pub fn join(address: &str) -> Result<NetSession, String> {
+        let with_port = if address.contains(':') { address.to_string() } else { format!("{}:{}", address, DEFAULT_PORT) };
+        let peer = with_port.to_socket_addrs().ok().and_then(|mut addresses| addresses.next()).ok_or(format!("Couldn't find {}", address))?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(HELLO_INTERVAL)).map_err(|e| e.to_string())?;
+        println!("Joining {}", peer);
+
+        let start = Instant::now();
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let (seed, input_delay, max_rollback) = 'connect: loop {
+            if start.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("No answer from {}", peer));
+            }
+            socket.send_to(&encode(0, &Message::Hello), peer).map_err(|e| e.to_string())?;
+            let sent_at = Instant::now();
+            while sent_at.elapsed() < HELLO_INTERVAL {
+                if let Ok((size, from)) = socket.recv_from(&mut buffer) {
+                    if let (true, Some((_, Message::Welcome { seed, input_delay, max_rollback }))) = (from == peer, decode(&buffer[..size])) {
+                        break 'connect (seed, input_delay, max_rollback);
+                    }
+                }
+            }
+        };
+        println!("Joined {}", peer);
+
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let link = UdpLink {
+            socket,
+            peer
+        };
+        Ok(NetSession::new(Box::new(link), 1, seed, input_delay, max_rollback))
+    }
+
+    pub fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    fn send(&mut self, message: &Message) {
+        self.send_sequence += 1;
+        let packet = encode(self.send_sequence, message);
+        self.link.send(&packet);
+        self.last_sent = Instant::now();
+    }
+
+    //Sends every input the other player hasn't acknowledged yet, so lost packets don't need to be tracked
+    fn send_inputs(&mut self) {
+        let start_tick = self.remote_ack.min(self.local_inputs.len() as u32);
+        let end_tick = self.local_inputs.len().min(start_tick as usize + MAX_INPUTS_PER_PACKET);
+        let message = Message::Inputs {
+            ack: self.remote_inputs.len() as u32,
+            start_tick,
+            inputs: self.local_inputs[start_tick as usize..end_tick].to_vec(),
+            checksum: self.local_checksums.back().cloned(),
+            spectators: self.spectators
+        };
+        self.send(&message);
+    }
+
+    fn receive(&mut self) -> Result<(), String> {
+        while let Some(packet) = self.link.receive() {
+            let (sequence, message) = match decode(&packet) {
+                None => {
+                    continue;
+                },
+                Some(decoded) => {
+                    decoded
+                }
+            };
+            self.last_received = Instant::now();
+            match message {
+                Message::Hello => {
+                    //Our welcome was lost, so the other player is still asking
+                    if self.local_player == 0 {
+                        let message = Message::Welcome {
+                            seed: self.seed,
+                            input_delay: self.input_delay,
+                            max_rollback: self.max_rollback
+                        };
+                        self.send(&message);
+                    }
+                },
+                Message::Welcome { .. } => {},
+                Message::Inputs { ack, start_tick, inputs, checksum, spectators } => {
+                    if self.receive_sequence.is_some_and(|last| sequence <= last) {
+                        continue;
+                    }
+                    self.receive_sequence = Some(sequence);
+                    if self.local_player != 0 {
+                        self.spectators = spectators;
+                    }
+                    self.remote_ack = self.remote_ack.max(ack);
+                    for (idx, input) in inputs.iter().enumerate() {
+                        let tick = start_tick as usize + idx;
+                        if tick == self.remote_inputs.len() {
+                            self.remote_inputs.push(*input);
+                        }
+                    }
+                    if let Some(checksum) = checksum {
+                        if self.remote_checksums.back() != Some(&checksum) {
+                            if self.remote_checksums.len() == CHECKSUM_HISTORY {
+                                self.remote_checksums.pop_front();
+                            }
+                            self.remote_checksums.push_back(checksum);
+                        }
+                    }
+                },
+                Message::Bye => {
+                    return Err("The other player left".to_string());
+                }
+            }
+        }
+        self.check_desync()?;
+        if self.last_received.elapsed() > TIMEOUT {
+            return Err("Lost connection to the other player".to_string());
+        }
+        Ok(())
+    }
+
+    fn check_desync(&self) -> Result<(), String> {
+        for (tick, remote) in self.remote_checksums.iter() {
+            if let Some((_, local)) = self.local_checksums.iter().find(|(local_tick, _)| local_tick == tick) {
+                if local != remote {
+                    return Err(format!("Desync detected at tick {}", tick));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //Adds this tick's local input, which is played input_delay ticks later
+    //Calling it again for the same tick does nothing
+    pub fn add_local_input(&mut self, tick: u32, input: PlayerInput) {
+        let mut new_input = false;
+        while self.local_inputs.len() as u32 <= tick + self.input_delay {
+            self.local_inputs.push(input);
+            new_input = true;
+        }
+        if new_input {
+            self.send_inputs();
+        }
+    }
+
+    //Receives the other player's inputs, and sends ours again if they haven't all arrived
+    pub fn poll(&mut self) -> Result<(), String> {
+        self.receive()?;
+        if self.last_sent.elapsed() >= RESEND_INTERVAL {
+            self.send_inputs();
+        }
+        Ok(())
+    }
+
+    //Only valid for ticks add_local_input has been called for
+    pub fn local_input(&self, tick: u32) -> PlayerInput {
+        self.local_inputs[tick as usize]
+    }
+
+    pub fn remote_input(&self, tick: u32) -> Option<PlayerInput> {
+        self.remote_inputs.get(tick as usize).cloned()
+    }
+
+    //The other player's inputs are known for every tick before this
+    pub fn remote_input_count(&self) -> u32 {
+        self.remote_inputs.len() as u32
+    }
+
+    //Called with the world's checksum after every tick that ran with the other player's actual input, it's only kept every CHECKSUM_INTERVAL ticks
+    pub fn record_checksum(&mut self, tick: u32, checksum: u32) -> Result<(), String> {
+        if !tick.is_multiple_of(CHECKSUM_INTERVAL) {
+            return Ok(());
+        }
+        if self.local_checksums.len() == CHECKSUM_HISTORY {
+            self.local_checksums.pop_front();
+        }
+        self.local_checksums.push_back((tick, checksum));
+        self.check_desync()
+    }
+
+    //Only the host has spectators, the count is passed on to the other player with the next inputs sent
+    pub fn set_spectator_count(&mut self, count: usize) {
+        if self.local_player == 0 {
+            self.spectators = count.min(u16::MAX as usize) as u16;
+        }
+    }
+
+    //How many are watching the match, on both the host and the other player
+    pub fn spectator_count(&self) -> u32 {
+        self.spectators as u32
+    }
+
+    //Tells the other player we're leaving, so they don't have to wait for the timeout
+    pub fn disconnect(&mut self) {
+        self.send(&Message::Bye);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use specs::{World, DispatcherBuilder};
+
+    use crate::events::GameEvent;
+    use crate::game::{self, PlayerInput, TickEvents};
+    use crate::rollback::Rollback;
+    use crate::replay::Replay;
+    use super::*;
+
+    //Long enough for the ball to cross the arena and hit a paddle a few times
+    const TEST_TICKS: u32 = 600;
+    //Gives up instead of hanging if the peers stop making progress
+    const TEST_TIMEOUT: Duration = Duration::from_secs(60);
+    //Watching through the first peer, which is the host
+    const TEST_SPECTATORS: usize = 3;
+
+    //Stand in for a player, moves the stick in a pattern that depends on the seed
+    fn scripted_input(tick: u32, seed: u64) -> PlayerInput {
+        let phase = (seed % 628) as f32 / 100.0;
+        let axis = (tick as f32 * 0.013 + phase).sin();
+        PlayerInput {
+            axis_y: (axis * 32767.0) as i16,
+            ai: false
+        }
+    }
+
+    struct TestPeer {
+        world: World,
+        rollback: Rollback
+    }
+
+    //Plays one match between two peers over a simulated link, returns the final checksum and the number of rollbacks
+    fn run_match(ticks: u32, seed: u64, max_rollback: u32, loss: f32, latency_ms: u64, jitter_ms: u64) -> (u32, u32) {
+        let (link_a, link_b) = SimulatedLink::pair(loss, Duration::from_millis(latency_ms), Duration::from_millis(jitter_ms), seed);
+        let mut peers = [TestPeer {
+                             world: game::create_headless(seed),
+                             rollback: Rollback::new(NetSession::new(Box::new(link_a), 0, seed, DEFAULT_INPUT_DELAY, max_rollback))
+                         },
+                         TestPeer {
+                             world: game::create_headless(seed),
+                             rollback: Rollback::new(NetSession::new(Box::new(link_b), 1, seed, DEFAULT_INPUT_DELAY, max_rollback))
+                         }];
+        peers[0].rollback.confirmed_events = Some(Vec::new());
+        peers[0].rollback.session.set_spectator_count(TEST_SPECTATORS);
+        peers[1].rollback.replay = Some(Replay::new(seed));
+        let mut dispatchers = [game::add_systems(DispatcherBuilder::new()).build(),
+                               game::add_systems(DispatcherBuilder::new()).build()];
+
+        let start = Instant::now();
+        //Predicted ticks have to be confirmed too, so both worlds are compared after the same inputs
+        while peers.iter().any(|peer| peer.rollback.confirmed_ticks() < ticks) {
+            assert!(start.elapsed() < TEST_TIMEOUT, "Peers stalled before reaching tick {}", ticks);
+            let mut advanced = false;
+            for (idx, (peer, dispatcher)) in peers.iter_mut().zip(dispatchers.iter_mut()).enumerate() {
+                let tick = peer.rollback.tick();
+                if tick >= ticks {
+                    //Keep answering, the other peer may still need our last inputs
+                    peer.rollback.update(&mut peer.world, dispatcher).unwrap();
+                    continue;
+                }
+                let input = scripted_input(tick, seed.wrapping_add(idx as u64));
+                if peer.rollback.advance(&mut peer.world, dispatcher, input).unwrap() {
+                    advanced = true;
+                }
+            }
+            if !advanced {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let checksums = [game::checksum(&peers[0].world), game::checksum(&peers[1].world)];
+        assert_eq!(checksums[0], checksums[1], "Peers finished with different worlds");
+        assert_eq!(peers[1].rollback.session.spectator_count(), TEST_SPECTATORS as u32, "The host's spectator count never reached the other player");
+        //What the second peer recorded has to play out the same without any of the netcode, after a trip through the file format
+        let replay = Replay::decode(&peers[1].rollback.replay.take().unwrap().encode()).unwrap();
+        replay.verify().unwrap();
+        //Spectators are sent the events of confirmed ticks, which have to be the ones a match without rollback would have had
+        assert_eq!(peers[0].rollback.confirmed_events.take().unwrap(), replay_events(&replay));
+        let rollbacks = peers.iter().map(|peer| peer.rollback.rollbacks).sum();
+        (checksums[0], rollbacks)
+    }
+
+    //Every event from playing the replay through, with the number of ticks run after each
+    fn replay_events(replay: &Replay) -> Vec<(u32, GameEvent)> {
+        let mut world = game::create_headless(replay.seed);
+        let mut dispatcher = game::add_systems(DispatcherBuilder::new()).build();
+        let mut events = Vec::new();
+        for tick in 0..replay.ticks() {
+            game::apply_inputs(&mut world, replay.inputs(tick).unwrap());
+            dispatcher.dispatch(&world.res);
+            world.maintain();
+            events.extend(world.read_resource::<TickEvents>().0.iter().map(|event| (tick + 1, *event)));
+        }
+        events
+    }
+
+    #[test]
+    fn perfect_link() {
+        run_match(TEST_TICKS, 1, DEFAULT_MAX_ROLLBACK, 0.0, 0, 0);
+    }
+
+    #[test]
+    fn lossy_link() {
+        run_match(TEST_TICKS, 2, DEFAULT_MAX_ROLLBACK, 0.1, 30, 25);
+    }
+
+    #[test]
+    fn bad_link() {
+        run_match(TEST_TICKS, 3, DEFAULT_MAX_ROLLBACK, 0.25, 80, 40);
+    }
+
+    #[test]
+    fn high_latency() {
+        //Further behind than rollback can predict, so the peers also have to wait for each other
+        run_match(TEST_TICKS, 4, DEFAULT_MAX_ROLLBACK, 0.0, 150, 0);
+    }
+
+    #[test]
+    fn rollback_matches_lockstep() {
+        let (lockstep_checksum, _) = run_match(TEST_TICKS, 5, 0, 0.1, 30, 25);
+        let (rollback_checksum, rollbacks) = run_match(TEST_TICKS, 5, DEFAULT_MAX_ROLLBACK, 0.1, 30, 25);
+        //Both matches had the same inputs, so once every prediction is corrected they have to end up the same
+        assert_eq!(rollback_checksum, lockstep_checksum);
+        //The scripted sticks never stop moving, so predictions are wrong all the time
+        assert!(rollbacks > 0, "Nothing was ever rolled back, so rollback wasn't tested");
+    }
+}
@@ -0,0 +1,165 @@
+use rand::{thread_rng, Rng};
+use specs::{Component, VecStorage, System, Read, ReadStorage, WriteStorage, WriteExpect};
+use specs_derive::{Component};
+
+use crate::fy_math::{Vec2, Color, TransformComponent};
+use crate::render::MeshHandle;
+use crate::timing::FrameTime;
+
+//Oldest particles are replaced once this many are alive
+const MAX_PARTICLES: usize = 4096;
+
+//How a group of particles moves and changes over its lifetime
+#[derive(Copy, Clone)]
+pub struct ParticleStyle {
+    //Seconds each particle lives for
+    pub lifetime: f32,
+    //Particles are launched within spread radians either side of direction, also in radians
+    pub direction: f32,
+    pub spread: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    //Fraction of velocity lost per second
+    pub drag: f32,
+    //Color and size are blended from start to end over the particle's life
+    pub start_color: Color,
+    pub end_color: Color,
+    pub start_size: f32,
+    pub end_size: f32
+}
+
+struct Particle {
+    position: Vec2,
+    velocity: Vec2,
+    age: f32,
+    style: ParticleStyle
+}
+
+//Every live particle, drawn by the renderer as one instanced batch of mesh
+pub struct Particles {
+    pub mesh: MeshHandle,
+    particles: Vec<Particle>,
+    //Index of the particle to replace next when full
+    next_replace: usize
+}
+
+impl Particles {
+    pub fn new(mesh: MeshHandle) -> Particles {
+        Particles {
+            mesh,
+            particles: Vec::with_capacity(MAX_PARTICLES),
+            next_replace: 0
+        }
+    }
+
+    //Spawns count particles at once, e.g. for an explosion
+    pub fn burst(&mut self, position: Vec2, count: usize, style: &ParticleStyle) {
+        let mut rng = thread_rng();
+        for _ in 0..count {
+            self.spawn(position, style, &mut rng);
+        }
+    }
+
+    fn spawn<R: Rng>(&mut self, position: Vec2, style: &ParticleStyle, rng: &mut R) {
+        let angle = if style.spread > 0.0 {
+            style.direction + rng.gen_range(-style.spread, style.spread)
+        } else {
+            style.direction
+        };
+        let speed = if style.max_speed > style.min_speed {
+            rng.gen_range(style.min_speed, style.max_speed)
+        } else {
+            style.min_speed
+        };
+        let particle = Particle {
+            position,
+            velocity: speed * Vec2::new(angle.cos(), angle.sin()),
+            age: 0.0,
+            style: *style
+        };
+
+        if self.particles.len() < MAX_PARTICLES {
+            self.particles.push(particle);
+        } else {
+            self.particles[self.next_replace] = particle;
+            self.next_replace = (self.next_replace + 1) % MAX_PARTICLES;
+        }
+    }
+
+    //Moves every particle and removes the ones that have expired
+    pub fn update(&mut self, dt: f32) {
+        for particle in self.particles.iter_mut() {
+            particle.age += dt;
+            let drag = (1.0 - particle.style.drag * dt).max(0.0);
+            particle.velocity = particle.velocity * drag;
+            particle.position = particle.position + particle.velocity * dt;
+        }
+        self.particles.retain(|particle| particle.age < particle.style.lifetime);
+        if self.next_replace >= self.particles.len() {
+            self.next_replace = 0;
+        }
+    }
+
+    //Position, size and color of every live particle
+    pub fn iter(&self) -> impl Iterator<Item = (Vec2, f32, Color)> + '_ {
+        self.particles.iter().map(|particle| {
+            let style = &particle.style;
+            let t = particle.age / style.lifetime;
+            let size = style.start_size + (style.end_size - style.start_size) * t;
+            (particle.position, size, lerp_color(style.start_color, style.end_color, t))
+        })
+    }
+}
+
+fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    Color {
+        r: a.r + (b.r - a.r) * t,
+        g: a.g + (b.g - a.g) * t,
+        b: a.b + (b.b - a.b) * t,
+        a: a.a + (b.a - a.a) * t
+    }
+}
+
+//Continuously spawns particles at the entity's position, e.g. for a trail
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct ParticleEmitter {
+    pub style: ParticleStyle,
+    //Particles per second
+    pub rate: f32,
+    //Fractional particles carried over between frames
+    pending: f32
+}
+
+impl ParticleEmitter {
+    pub fn new(style: ParticleStyle, rate: f32) -> ParticleEmitter {
+        ParticleEmitter {
+            style,
+            rate,
+            pending: 0.0
+        }
+    }
+}
+
+//Particles are purely visual, so they're simulated once per frame on the real frame time
+pub struct UpdateParticles;
+
+impl<'a> System<'a> for UpdateParticles {
+    type SystemData = (WriteStorage<'a, ParticleEmitter>, ReadStorage<'a, TransformComponent>, Read<'a, FrameTime>, WriteExpect<'a, Particles>);
+
+    fn run(&mut self, (mut emitter_storage, transform_storage, frame_time, mut particles): Self::SystemData) {
+        use specs::Join;
+
+        let dt = frame_time.0;
+        particles.update(dt);
+
+        let mut rng = thread_rng();
+        for (emitter, transform) in (&mut emitter_storage, &transform_storage).join() {
+            emitter.pending += emitter.rate * dt;
+            while emitter.pending >= 1.0 {
+                particles.spawn(transform.position, &emitter.style, &mut rng);
+                emitter.pending -= 1.0;
+            }
+        }
+    }
+}
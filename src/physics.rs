@@ -2,19 +2,21 @@ use specs::{Builder, Component, VecStorage, System, Read, Write, WriteStorage, R
 use specs_derive::{Component};
 
 use crate::fy_math::{TransformComponent, Vec2};
-use crate::render::{Vertex};
+use crate::mesh::Vertex;
 
+#[derive(Clone)]
 struct AABB {
     top_right: Vec2,
     bot_left: Vec2
 }
 
+#[derive(Clone)]
 pub struct Collision {
     pub other: Entity,
     pub mtv: Vec2
 }
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 #[storage(VecStorage)]
 pub struct PhysicsComponent {
     pub velocity: Vec2,
@@ -38,6 +40,12 @@ impl PhysicsComponent {
             collided_objects: Vec::new()
         }
     }
+
+    //Corners of the bounding box with the smallest and largest coordinates, when the entity is at position
+    pub fn bounds(&self, position: Vec2) -> (Vec2, Vec2) {
+        let bbox = self.bbox.adjust_position(position);
+        (bbox.bot_left, bbox.top_right)
+    }
 }
 
 impl AABB {
@@ -0,0 +1,196 @@
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::{KeyboardState, Scancode};
+
+use crate::config::Fallback;
+use crate::game::Controllers;
+
+const AXIS_MAX: f32 = 32768.0;
+
+//Keyboard players move their paddle at this many axis units per second, so the full travel takes a second
+const KEYBOARD_SPEED: f32 = 2.0;
+
+//Up and down keys for each player, player 1 defends the right side so gets the arrow keys
+const KEYBOARD_KEYS: [(Scancode, Scancode); 2] = [(Scancode::Up, Scancode::Down),
+                                                  (Scancode::W, Scancode::S)];
+
+//Describes who is playing in each slot, for the HUD
+#[derive(Default)]
+pub struct SlotLabels(pub Vec<String>);
+
+enum Source {
+    Controller(GameController),
+    Keyboard,
+    Ai
+}
+
+//Things that happened to the player slots which the game should react to
+pub enum SlotEvent {
+    //An active player's controller was unplugged, the slot has gone back to the fallback
+    Disconnected,
+    //A joined player pressed start
+    PausePressed
+}
+
+//Owns the controllers and decides which one drives each paddle
+//Controllers that are plugged in wait until a button is pressed before taking the first free slot
+pub struct Players {
+    subsystem: GameControllerSubsystem,
+    fallback: Fallback,
+    slots: Vec<Source>,
+    //Connected controllers that haven't joined a slot yet
+    waiting: Vec<GameController>,
+    keyboard_axis: Vec<f32>
+}
+
+impl Players {
+    pub fn new(subsystem: GameControllerSubsystem, player_count: usize, fallback: Fallback) -> Players {
+        Players {
+            subsystem,
+            fallback,
+            slots: (0..player_count).map(|_| Players::fallback_source(fallback)).collect(),
+            waiting: Vec::new(),
+            keyboard_axis: vec![0.0; player_count]
+        }
+    }
+
+    fn fallback_source(fallback: Fallback) -> Source {
+        match fallback {
+            Fallback::Ai => Source::Ai,
+            Fallback::Keyboard => Source::Keyboard
+        }
+    }
+
+    fn is_open(&self, instance_id: i32) -> bool {
+        self.waiting.iter().any(|controller| controller.instance_id() == instance_id) ||
+        self.slots.iter().any(|slot| match slot {
+            Source::Controller(controller) => controller.instance_id() == instance_id,
+            _ => false
+        })
+    }
+
+    //SDL also sends ControllerDeviceAdded for every controller already connected at startup
+    pub fn handle_event(&mut self, event: &Event) -> Option<SlotEvent> {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.subsystem.open(which) {
+                    Err(e) => {
+                        println!("Failed to open controller {}: {}", which, e);
+                    },
+                    Ok(controller) => {
+                        if !self.is_open(controller.instance_id()) {
+                            println!("{} connected, press A or Start to join", controller.name());
+                            self.waiting.push(controller);
+                        }
+                    }
+                }
+                None
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.waiting.retain(|controller| controller.instance_id() != which);
+                for (idx, slot) in self.slots.iter_mut().enumerate() {
+                    let removed = match slot {
+                        Source::Controller(controller) => controller.instance_id() == which,
+                        _ => false
+                    };
+                    if removed {
+                        println!("Player {} disconnected", idx + 1);
+                        *slot = Players::fallback_source(self.fallback);
+                        return Some(SlotEvent::Disconnected);
+                    }
+                }
+                None
+            },
+            Event::ControllerButtonDown { which, button, .. } => {
+                let joined = self.slots.iter().any(|slot| match slot {
+                    Source::Controller(controller) => controller.instance_id() == which,
+                    _ => false
+                });
+                if joined {
+                    if button == Button::Start {
+                        return Some(SlotEvent::PausePressed);
+                    }
+                    return None;
+                }
+
+                if button != Button::A && button != Button::Start {
+                    return None;
+                }
+                let waiting_idx = self.waiting.iter().position(|controller| controller.instance_id() == which)?;
+                let free_slot = match self.slots.iter().position(|slot| !matches!(slot, Source::Controller(_))) {
+                    None => {
+                        println!("Both players have already joined");
+                        return None;
+                    },
+                    Some(slot) => {
+                        slot
+                    }
+                };
+                let controller = self.waiting.remove(waiting_idx);
+                println!("{} joined as player {}", controller.name(), free_slot + 1);
+                self.slots[free_slot] = Source::Controller(controller);
+                None
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    //Reads every slot's input for this frame, AI slots are only flagged as AI
+    pub fn update(&mut self, keyboard: &KeyboardState, frame_time: f32, controllers: &mut Controllers) {
+        for (idx, slot) in self.slots.iter_mut().enumerate() {
+            let state = &mut controllers.0[idx];
+            let (up, down) = KEYBOARD_KEYS[idx % KEYBOARD_KEYS.len()];
+            let up = keyboard.is_scancode_pressed(up);
+            let down = keyboard.is_scancode_pressed(down);
+
+            //Pressing a slot's keys takes over from the AI
+            if let Source::Ai = slot {
+                if up || down {
+                    println!("Keyboard joined as player {}", idx + 1);
+                    self.keyboard_axis[idx] = state.left_axis_y;
+                    *slot = Source::Keyboard;
+                }
+            }
+
+            match slot {
+                Source::Controller(controller) => {
+                    state.left_axis_x = controller.axis(sdl2::controller::Axis::LeftX) as f32 / AXIS_MAX;
+                    state.left_axis_y = controller.axis(sdl2::controller::Axis::LeftY) as f32 / AXIS_MAX;
+                    state.ai = false;
+                },
+                Source::Keyboard => {
+                    let direction = if up { -1.0 } else { 0.0 } + if down { 1.0 } else { 0.0 };
+                    let axis = &mut self.keyboard_axis[idx];
+                    *axis = (*axis + direction * KEYBOARD_SPEED * frame_time).clamp(-1.0, 1.0);
+                    state.left_axis_x = 0.0;
+                    state.left_axis_y = *axis;
+                    state.ai = false;
+                },
+                Source::Ai => {
+                    state.ai = true;
+                }
+            }
+        }
+    }
+
+    pub fn controller_mut(&mut self, player: usize) -> Option<&mut GameController> {
+        match self.slots.get_mut(player) {
+            Some(Source::Controller(controller)) => Some(controller),
+            _ => None
+        }
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        let join_hint = if self.waiting.is_empty() { "" } else { " - press A to join" };
+        self.slots.iter().enumerate().map(|(idx, slot)| {
+            match slot {
+                Source::Controller(controller) => format!("P{} {}", idx + 1, controller.name()),
+                Source::Keyboard => format!("P{} Keyboard{}", idx + 1, join_hint),
+                Source::Ai => format!("P{} CPU{}", idx + 1, join_hint)
+            }
+        }).collect()
+    }
+}
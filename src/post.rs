@@ -0,0 +1,370 @@
+use ash::vk;
+use ash::version::DeviceV1_0;
+
+use crate::fy_math::Vec2;
+use crate::config::PostSettings;
+use crate::render::{self, ShaderCode, Texture};
+
+//The scene and bloom are rendered in floating point, so colors brighter than white survive until the final composite
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+//Number of descriptor sets used by the post-processing targets
+pub const DESCRIPTOR_SETS: u32 = 3;
+
+//Brightness above this glows
+const BLOOM_THRESHOLD: f32 = 0.6;
+const BLOOM_INTENSITY: f32 = 1.2;
+//Fraction of brightness taken away from the dark rows between scanlines
+const SCANLINE_STRENGTH: f32 = 0.25;
+//How far the corners are pushed out by the curvature
+const CRT_CURVATURE: f32 = 0.08;
+//Distance the red and blue channels are moved apart at the edge of the screen, in uv units
+const CHROMATIC_ABERRATION: f32 = 0.004;
+
+//Layout must match the push constant block in post.frag
+#[repr(C)]
+struct PostConstants {
+    screen_size: Vec2,
+    bloom_intensity: f32,
+    scanlines: f32,
+    curvature: f32,
+    aberration: f32
+}
+
+//Layout must match the push constant block in blur.frag
+#[repr(C)]
+struct BlurConstants {
+    direction: Vec2,
+    threshold: f32,
+    padding: f32
+}
+
+//An offscreen image that is rendered to, then sampled by a later pass
+struct Target {
+    texture: Texture,
+    framebuffer: vk::Framebuffer
+}
+
+//Owns the HDR target the scene is drawn into and the passes that take it to the swapchain
+//Bloom blurs the bright parts of the scene at half resolution, horizontally then vertically,
+//then post.frag combines the scene and bloom while applying the CRT effects
+pub struct PostProcess {
+    scene: Target,
+    bloom: [Target; 2],
+    sampler: vk::Sampler,
+    blur_pass: vk::RenderPass,
+    present_pass: vk::RenderPass,
+    present_framebuffers: std::vec::Vec<vk::Framebuffer>,
+    blur_layout: vk::PipelineLayout,
+    post_layout: vk::PipelineLayout,
+    blur_pipeline: vk::Pipeline,
+    post_pipeline: vk::Pipeline,
+    render_area: vk::Rect2D,
+    bloom_area: vk::Rect2D,
+    constants: PostConstants
+}
+
+//Creates a render pass with a single color attachment, which is left in final_layout when the pass ends
+fn create_render_pass(device: &ash::Device, format: vk::Format, load_op: vk::AttachmentLoadOp, final_layout: vk::ImageLayout) -> vk::RenderPass {
+    let attachment = [vk::AttachmentDescription::builder()
+        .format(format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(load_op)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(final_layout)
+        .build()];
+
+    let attach_refs = [vk::AttachmentReference::builder()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .build()];
+
+    let subpasses = [vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attach_refs)
+        .build()];
+
+    //Wait for earlier passes to finish reading the image before writing to it,
+    //and make the writes visible to later passes sampling it
+    let dependencies = [vk::SubpassDependency::builder()
+                            .src_subpass(vk::SUBPASS_EXTERNAL)
+                            .dst_subpass(0)
+                            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::FRAGMENT_SHADER)
+                            .src_access_mask(vk::AccessFlags::empty())
+                            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                            .build(),
+                        vk::SubpassDependency::builder()
+                            .src_subpass(0)
+                            .dst_subpass(vk::SUBPASS_EXTERNAL)
+                            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                            .build()];
+
+    let create_info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachment)
+        .subpasses(&subpasses)
+        .dependencies(&dependencies)
+        .build();
+    unsafe { device.create_render_pass(&create_info, None).unwrap() }
+}
+
+//msaa_view is a multisampled image drawn into by render_pass, which is resolved into the target
+#[allow(clippy::too_many_arguments)]
+fn create_target(device: &ash::Device, allocator: &vk_mem::Allocator, render_pass: vk::RenderPass, msaa_view: Option<vk::ImageView>, width: u32, height: u32, set_layout: vk::DescriptorSetLayout, descriptor_pool: vk::DescriptorPool, sampler: vk::Sampler) -> Target {
+    let (image, allocation, _) = {
+        let image_create = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .extent(vk::Extent3D::builder().width(width).height(height).depth(1).build())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .build();
+
+        let alloc_create = vk_mem::AllocationCreateInfo {
+            usage: vk_mem::MemoryUsage::GpuOnly,
+            ..Default::default()
+        };
+
+        allocator.create_image(&image_create, &alloc_create).unwrap()
+    };
+
+    let view = {
+        let create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(HDR_FORMAT)
+            .components(vk::ComponentMapping::builder().r(vk::ComponentSwizzle::IDENTITY).g(vk::ComponentSwizzle::IDENTITY).b(vk::ComponentSwizzle::IDENTITY).a(vk::ComponentSwizzle::IDENTITY).build())
+            .subresource_range(vk::ImageSubresourceRange::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .base_mip_level(0)
+                                .level_count(1)
+                                .base_array_layer(0)
+                                .layer_count(1)
+                                .build());
+        unsafe { device.create_image_view(&create_info, None).unwrap() }
+    };
+
+    let framebuffer = match msaa_view {
+        None => create_framebuffer(device, render_pass, &[view], width, height),
+        Some(msaa_view) => create_framebuffer(device, render_pass, &[msaa_view, view], width, height)
+    };
+    let descriptor_set = render::create_texture_descriptor_set(device, descriptor_pool, set_layout, view, sampler);
+
+    Target {
+        texture: Texture {
+            image,
+            allocation,
+            view,
+            descriptor_set
+        },
+        framebuffer
+    }
+}
+
+fn create_framebuffer(device: &ash::Device, render_pass: vk::RenderPass, attachments: &[vk::ImageView], width: u32, height: u32) -> vk::Framebuffer {
+    let create_info = vk::FramebufferCreateInfo::builder()
+        .render_pass(render_pass)
+        .attachments(attachments)
+        .width(width)
+        .height(height)
+        .layers(1)
+        .build();
+    unsafe { device.create_framebuffer(&create_info, None).unwrap() }
+}
+
+fn create_layout(device: &ash::Device, set_layouts: &[vk::DescriptorSetLayout], push_constant_size: usize) -> vk::PipelineLayout {
+    let push_constant_range = [vk::PushConstantRange::builder()
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        .offset(0)
+        .size(push_constant_size as u32)
+        .build()];
+    let create_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(&push_constant_range)
+        .build();
+    unsafe { device.create_pipeline_layout(&create_info, None).unwrap() }
+}
+
+fn push_constants<T>(device: &ash::Device, command_buffer: vk::CommandBuffer, layout: vk::PipelineLayout, constants: &T) {
+    unsafe {
+        let slice = std::slice::from_raw_parts(constants as *const T as *const u8, std::mem::size_of::<T>());
+        device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0, slice);
+    }
+}
+
+impl PostProcess {
+    //scene_pass is the render pass everything is drawn with, it must leave its HDR_FORMAT attachment ready to be sampled
+    //If msaa_view is given, scene_pass draws into it first and resolves into the scene image
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(device: &ash::Device, allocator: &vk_mem::Allocator, scene_pass: vk::RenderPass, msaa_view: Option<vk::ImageView>, swapchain_format: vk::Format, swapchain_views: &[vk::ImageView], render_area: vk::Rect2D, set_layout: vk::DescriptorSetLayout, descriptor_pool: vk::DescriptorPool, code: &ShaderCode, settings: &PostSettings) -> PostProcess {
+        let width = render_area.extent.width;
+        let height = render_area.extent.height;
+
+        //Linear filtering smooths out the bloom upscale and the curvature
+        let sampler = {
+            let create_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_anisotropy(1.0)
+                .min_lod(0.0)
+                .max_lod(0.0);
+            unsafe { device.create_sampler(&create_info, None).unwrap() }
+        };
+
+        //Every pixel of the blur targets and the swapchain image is drawn over, so they don't need clearing
+        let blur_pass = create_render_pass(device, HDR_FORMAT, vk::AttachmentLoadOp::DONT_CARE, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        let present_pass = create_render_pass(device, swapchain_format, vk::AttachmentLoadOp::DONT_CARE, vk::ImageLayout::PRESENT_SRC_KHR);
+
+        let scene = create_target(device, allocator, scene_pass, msaa_view, width, height, set_layout, descriptor_pool, sampler);
+        let bloom_area = vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(0).y(0).build())
+            .extent(vk::Extent2D::builder().width((width / 2).max(1)).height((height / 2).max(1)).build())
+            .build();
+        let bloom = [create_target(device, allocator, blur_pass, None, bloom_area.extent.width, bloom_area.extent.height, set_layout, descriptor_pool, sampler),
+                     create_target(device, allocator, blur_pass, None, bloom_area.extent.width, bloom_area.extent.height, set_layout, descriptor_pool, sampler)];
+
+        let present_framebuffers = swapchain_views.iter().map(|view| create_framebuffer(device, present_pass, &[*view], width, height)).collect();
+
+        let blur_layout = create_layout(device, &[set_layout], std::mem::size_of::<BlurConstants>());
+        let post_layout = create_layout(device, &[set_layout, set_layout], std::mem::size_of::<PostConstants>());
+
+        let enabled = |on: bool, strength: f32| if on { strength } else { 0.0 };
+        let constants = PostConstants {
+            screen_size: Vec2::new(width as f32, height as f32),
+            bloom_intensity: enabled(settings.bloom, BLOOM_INTENSITY),
+            scanlines: enabled(settings.scanlines, SCANLINE_STRENGTH),
+            curvature: enabled(settings.crt_curvature, CRT_CURVATURE),
+            aberration: enabled(settings.chromatic_aberration, CHROMATIC_ABERRATION)
+        };
+
+        let (blur_pipeline, post_pipeline) = PostProcess::create_pipelines(device, blur_pass, present_pass, blur_layout, post_layout, bloom_area, render_area, code);
+
+        PostProcess {
+            scene,
+            bloom,
+            sampler,
+            blur_pass,
+            present_pass,
+            present_framebuffers,
+            blur_layout,
+            post_layout,
+            blur_pipeline,
+            post_pipeline,
+            render_area,
+            bloom_area,
+            constants
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_pipelines(device: &ash::Device, blur_pass: vk::RenderPass, present_pass: vk::RenderPass, blur_layout: vk::PipelineLayout, post_layout: vk::PipelineLayout, bloom_area: vk::Rect2D, render_area: vk::Rect2D, code: &ShaderCode) -> (vk::Pipeline, vk::Pipeline) {
+        //The full screen triangle is generated in the vertex shader, so there are no vertex inputs
+        let blur = render::create_pipeline(device, blur_pass, blur_layout, code.get("fullscreen.vert"), code.get("blur.frag"), &[], &[], vk::PrimitiveTopology::TRIANGLE_LIST, bloom_area, vk::SampleCountFlags::TYPE_1);
+        let post = render::create_pipeline(device, present_pass, post_layout, code.get("fullscreen.vert"), code.get("post.frag"), &[], &[], vk::PrimitiveTopology::TRIANGLE_LIST, render_area, vk::SampleCountFlags::TYPE_1);
+        (blur, post)
+    }
+
+    //The framebuffer the scene should be drawn into with the scene render pass
+    pub fn scene_framebuffer(&self) -> vk::Framebuffer {
+        self.scene.framebuffer
+    }
+
+    //Frees everything in reverse order of creation, the GPU must be done with it
+    //The descriptor sets are freed along with their pool
+    pub fn destroy(&self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        unsafe {
+            device.destroy_pipeline(self.post_pipeline, None);
+            device.destroy_pipeline(self.blur_pipeline, None);
+            device.destroy_pipeline_layout(self.post_layout, None);
+            device.destroy_pipeline_layout(self.blur_layout, None);
+            for framebuffer in self.present_framebuffers.iter() {
+                device.destroy_framebuffer(*framebuffer, None);
+            }
+        }
+        for target in self.bloom.iter().rev().chain(std::iter::once(&self.scene)) {
+            unsafe { device.destroy_framebuffer(target.framebuffer, None) };
+            target.texture.destroy(device, allocator);
+        }
+        unsafe {
+            device.destroy_render_pass(self.present_pass, None);
+            device.destroy_render_pass(self.blur_pass, None);
+            device.destroy_sampler(self.sampler, None);
+        }
+    }
+
+    #[cfg(feature = "shader-hot-reload")]
+    pub fn reload_shaders(&mut self, device: &ash::Device, code: &ShaderCode) {
+        let (blur, post) = PostProcess::create_pipelines(device, self.blur_pass, self.present_pass, self.blur_layout, self.post_layout, self.bloom_area, self.render_area, code);
+        unsafe {
+            device.destroy_pipeline(self.blur_pipeline, None);
+            device.destroy_pipeline(self.post_pipeline, None);
+        }
+        self.blur_pipeline = blur;
+        self.post_pipeline = post;
+    }
+
+    //Records the bloom passes and the final composite into the given swapchain image
+    //Must be recorded after the scene render pass has ended
+    pub fn record(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, swapchain_idx: usize) {
+        let bloom_on = self.constants.bloom_intensity > 0.0;
+        if bloom_on {
+            let texel = Vec2::new(1.0 / self.bloom_area.extent.width as f32, 1.0 / self.bloom_area.extent.height as f32);
+            //The first pass also picks out the bright parts of the scene while shrinking it to half size
+            let passes = [(&self.scene, &self.bloom[0], Vec2::new(texel.x, 0.0), BLOOM_THRESHOLD),
+                          (&self.bloom[0], &self.bloom[1], Vec2::new(0.0, texel.y), 0.0)];
+            for (source, target, direction, threshold) in passes.iter() {
+                let constants = BlurConstants {
+                    direction: *direction,
+                    threshold: *threshold,
+                    padding: 0.0
+                };
+                self.record_fullscreen(device, command_buffer, self.blur_pass, target.framebuffer, self.bloom_area, self.blur_pipeline, self.blur_layout, &[source.texture.descriptor_set]);
+                push_constants(device, command_buffer, self.blur_layout, &constants);
+                self.finish_fullscreen(device, command_buffer);
+            }
+        }
+
+        //With bloom off the bloom images are never written, so the scene is bound in their place
+        let bloom_set = if bloom_on { self.bloom[1].texture.descriptor_set } else { self.scene.texture.descriptor_set };
+        self.record_fullscreen(device, command_buffer, self.present_pass, self.present_framebuffers[swapchain_idx], self.render_area, self.post_pipeline, self.post_layout, &[self.scene.texture.descriptor_set, bloom_set]);
+        push_constants(device, command_buffer, self.post_layout, &self.constants);
+        self.finish_fullscreen(device, command_buffer);
+    }
+
+    //Begins a render pass and binds everything for a full screen draw, push constants are left to the caller
+    #[allow(clippy::too_many_arguments)]
+    fn record_fullscreen(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass, framebuffer: vk::Framebuffer, area: vk::Rect2D, pipeline: vk::Pipeline, layout: vk::PipelineLayout, descriptor_sets: &[vk::DescriptorSet]) {
+        let rp_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(area)
+            .build();
+        unsafe {
+            device.cmd_begin_render_pass(command_buffer, &rp_begin_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0, descriptor_sets, &[]);
+        }
+    }
+
+    fn finish_fullscreen(&self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            device.cmd_end_render_pass(command_buffer);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use specs::{System, SystemData, Read, Write};
+
+use crate::fy_math::{Vec2, Color};
+use crate::text::{self, TextQueue};
+
+//Frames kept for the overlay graph and trace exports
+const HISTORY_FRAMES: usize = 120;
+
+//GPU work gets its own row in traces
+const GPU_THREAD: u64 = 0;
+
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(GPU_THREAD + 1);
+
+thread_local! {
+    //Small sequential ids are easier to read in trace viewers than OS thread ids
+    static THREAD_ID: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+//Overlay layout in pixels, the graph is scaled so GRAPH_MAX_MS fills its height
+const GRAPH_POSITION: Vec2 = Vec2 { x: 4.0, y: 24.0 };
+const GRAPH_HEIGHT: f32 = 80.0;
+const GRAPH_BAR_WIDTH: f32 = 2.0;
+const GRAPH_MAX_MS: f32 = 33.3;
+const TABLE_LINE_HEIGHT: f32 = 16.0;
+
+//Top level scopes stacked in each bar of the graph, the rest of the frame is drawn in FRAME_COLOR
+const GRAPH_SCOPES: [(&str, Color); 3] = [("simulation", Color { r: 0.3, g: 0.8, b: 0.3, a: 0.9 }),
+                                          ("frame systems", Color { r: 0.9, g: 0.8, b: 0.2, a: 0.9 }),
+                                          ("render", Color { r: 0.3, g: 0.5, b: 1.0, a: 0.9 })];
+const FRAME_COLOR: Color = Color { r: 0.5, g: 0.5, b: 0.5, a: 0.6 };
+const GPU_COLOR: Color = Color { r: 1.0, g: 0.4, b: 0.1, a: 1.0 };
+
+//One timed run of a scope, times are relative to when the profiler was created
+#[derive(Clone)]
+struct Span {
+    name: &'static str,
+    thread: u64,
+    start: Duration,
+    duration: Duration
+}
+
+struct Frame {
+    spans: Vec<Span>,
+    total: Duration
+}
+
+struct ProfileData {
+    //Spans recorded since the last end_frame
+    current: Vec<Span>,
+    frame_start: Duration,
+    frames: VecDeque<Frame>
+}
+
+struct Shared {
+    epoch: Instant,
+    overlay: AtomicBool,
+    data: Mutex<ProfileData>
+}
+
+//Collects CPU scope timings from any thread plus GPU timings from the renderer
+//Cloning gives another handle to the same profiler, so it can be used outside the World
+#[derive(Clone)]
+pub struct Profiler {
+    shared: Arc<Shared>
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler {
+            shared: Arc::new(Shared {
+                epoch: Instant::now(),
+                overlay: AtomicBool::new(false),
+                data: Mutex::new(ProfileData {
+                    current: Vec::new(),
+                    frame_start: Duration::from_secs(0),
+                    frames: VecDeque::with_capacity(HISTORY_FRAMES)
+                })
+            })
+        }
+    }
+}
+
+//Times from its creation until it is dropped
+pub struct Scope<'a> {
+    profiler: &'a Profiler,
+    name: &'static str,
+    start: Instant
+}
+
+impl<'a> Drop for Scope<'a> {
+    fn drop(&mut self) {
+        let thread = THREAD_ID.with(|id| *id);
+        self.profiler.record(self.name, thread, self.start, self.start.elapsed());
+    }
+}
+
+impl Profiler {
+    pub fn scope(&self, name: &'static str) -> Scope<'_> {
+        Scope {
+            profiler: self,
+            name,
+            start: Instant::now()
+        }
+    }
+
+    //GPU times are measured by the renderer and reported once the GPU has finished, start is when the work was submitted
+    pub fn record_gpu(&self, name: &'static str, start: Instant, duration: Duration) {
+        self.record(name, GPU_THREAD, start, duration);
+    }
+
+    fn record(&self, name: &'static str, thread: u64, start: Instant, duration: Duration) {
+        let span = Span {
+            name,
+            thread,
+            start: start.saturating_duration_since(self.shared.epoch),
+            duration
+        };
+        self.shared.data.lock().unwrap().current.push(span);
+    }
+
+    //Moves everything recorded since the last call into the history, should be called once at the end of every frame
+    pub fn end_frame(&self) {
+        let now = self.shared.epoch.elapsed();
+        let mut data = self.shared.data.lock().unwrap();
+        let frame = Frame {
+            spans: std::mem::take(&mut data.current),
+            total: now - data.frame_start
+        };
+        data.frame_start = now;
+        if data.frames.len() == HISTORY_FRAMES {
+            data.frames.pop_front();
+        }
+        data.frames.push_back(frame);
+    }
+
+    pub fn toggle_overlay(&self) {
+        self.shared.overlay.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    //Writes the frames in the history as Chrome trace JSON, which can be opened in chrome://tracing or Perfetto
+    pub fn export_trace(&self, path: &str) -> Result<(), String> {
+        let mut events = vec![format!("{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"GPU\"}}}}", GPU_THREAD)];
+        let data = self.shared.data.lock().unwrap();
+        for frame in data.frames.iter() {
+            for span in frame.spans.iter() {
+                events.push(format!("{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+                                    span.name, span.thread, span.start.as_secs_f64() * 1e6, span.duration.as_secs_f64() * 1e6));
+            }
+        }
+        drop(data);
+
+        let json = format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"));
+        std::fs::write(path, json).map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+}
+
+//Runs a system inside a scope with the given name, so every system can be timed without changing it
+pub struct Profiled<S> {
+    name: &'static str,
+    system: S
+}
+
+impl<S> Profiled<S> {
+    pub fn new(name: &'static str, system: S) -> Profiled<S> {
+        Profiled {
+            name,
+            system
+        }
+    }
+}
+
+impl<'a, S> System<'a> for Profiled<S> where S: System<'a>, S::SystemData: SystemData<'a> {
+    type SystemData = (Read<'a, Profiler>, S::SystemData);
+
+    fn run(&mut self, (profiler, data): Self::SystemData) {
+        let _scope = profiler.scope(self.name);
+        self.system.run(data);
+    }
+}
+
+fn milliseconds(duration: Duration) -> f32 {
+    duration.as_secs_f32() * 1000.0
+}
+
+//Draws a graph of recent frame times split by top level scope, with the average time of every scope listed below
+pub struct DrawProfiler;
+
+impl<'a> System<'a> for DrawProfiler {
+    type SystemData = (Read<'a, Profiler>, Write<'a, TextQueue>);
+
+    fn run(&mut self, (profiler, mut text_queue): Self::SystemData) {
+        if !profiler.shared.overlay.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let data = profiler.shared.data.lock().unwrap();
+        let graph_size = Vec2::new(HISTORY_FRAMES as f32 * GRAPH_BAR_WIDTH, GRAPH_HEIGHT);
+        let pixels_per_ms = GRAPH_HEIGHT / GRAPH_MAX_MS;
+        text_queue.rect(GRAPH_POSITION, graph_size, Color { r: 0.0, g: 0.0, b: 0.0, a: 0.5 });
+
+        //Totals per scope name over the whole history, in the order they were first seen
+        let mut totals: Vec<(&'static str, f32)> = Vec::new();
+        for (idx, frame) in data.frames.iter().enumerate() {
+            let x = GRAPH_POSITION.x + idx as f32 * GRAPH_BAR_WIDTH;
+            let bottom = GRAPH_POSITION.y + GRAPH_HEIGHT;
+            let mut bar = |ms: f32, offset: f32, color: Color| {
+                let height = (ms * pixels_per_ms).min(GRAPH_HEIGHT - offset);
+                if height > 0.0 {
+                    text_queue.rect(Vec2::new(x, bottom - offset - height), Vec2::new(GRAPH_BAR_WIDTH, height), color);
+                }
+                offset + height
+            };
+
+            let mut offset = 0.0;
+            for (name, color) in GRAPH_SCOPES.iter() {
+                let ms: f32 = frame.spans.iter().filter(|span| span.name == *name).map(|span| milliseconds(span.duration)).sum();
+                offset = bar(ms, offset, *color);
+            }
+            let accounted = offset / pixels_per_ms;
+            bar(milliseconds(frame.total) - accounted, offset, FRAME_COLOR);
+
+            let gpu_ms: f32 = frame.spans.iter().filter(|span| span.thread == GPU_THREAD).map(|span| milliseconds(span.duration)).sum();
+            if gpu_ms > 0.0 {
+                let y = bottom - (gpu_ms * pixels_per_ms).min(GRAPH_HEIGHT);
+                text_queue.rect(Vec2::new(x, y - 1.0), Vec2::new(GRAPH_BAR_WIDTH, 2.0), GPU_COLOR);
+            }
+
+            for span in frame.spans.iter() {
+                match totals.iter_mut().find(|(name, _)| *name == span.name) {
+                    None => totals.push((span.name, milliseconds(span.duration))),
+                    Some((_, total)) => *total += milliseconds(span.duration)
+                }
+            }
+        }
+
+        let frame_count = data.frames.len().max(1) as f32;
+        let mut y = GRAPH_POSITION.y + GRAPH_HEIGHT + 4.0;
+        for (name, total) in totals.iter() {
+            let line = format!("{:<24}{:6.2}ms", name, total / frame_count);
+            text_queue.rect(Vec2::new(GRAPH_POSITION.x, y), Vec2::new(text::text_width(&line, 1.0) + 8.0, TABLE_LINE_HEIGHT), Color { r: 0.0, g: 0.0, b: 0.0, a: 0.5 });
+            text_queue.text(&line, Vec2::new(GRAPH_POSITION.x + 4.0, y), 1.0, Color::WHITE);
+            y += TABLE_LINE_HEIGHT;
+        }
+    }
+}
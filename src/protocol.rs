@@ -0,0 +1,839 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use specs::World;
+
+use crate::discovery;
+use crate::fy_math::{Vec2, TransformComponent};
+use crate::physics::PhysicsComponent;
+use crate::events::GameEvent;
+use crate::game::{Ball, Paddle, Score, Snapshot, PlayerInput, PLAYER_COUNT};
+
+//Messages between pong-server and the clients playing on it
+//Clients only send their input, the server runs the match and sends back what happened
+//Spectators watching a server or a netplay host get the same states, but never send input
+
+//Different from netplay's, so a client can't join a peer to peer game by mistake
+const MAGIC: u32 = 0x5352_4f50;
+pub const PROTOCOL_VERSION: u16 = 2;
+
+pub const DEFAULT_SERVER_PORT: u16 = 7780;
+pub const DEFAULT_ROOM: &str = "default";
+pub const MAX_PACKET_SIZE: usize = 1024;
+pub const MAX_ROOM_NAME: usize = 32;
+
+//Ticks between states sent to clients, they interpolate between them
+pub const STATE_INTERVAL: u32 = 3;
+//States kept to send changes from, clients whose newest state is older get a full state instead
+const STATE_HISTORY: usize = 32;
+
+const JOIN: u8 = 0;
+const INPUT: u8 = 1;
+const LEAVE: u8 = 2;
+const JOINED: u8 = 3;
+const REJECTED: u8 = 4;
+const STATE: u8 = 5;
+const WATCH: u8 = 6;
+const ACK: u8 = 7;
+const WATCHING: u8 = 8;
+
+const BALL_HIT: u8 = 0;
+const GOAL: u8 = 1;
+const MATCH_WON: u8 = 2;
+
+//Marks a state sent in full rather than as changes from one the client already has
+const NO_BASE: u32 = u32::MAX;
+
+//Everything a client needs to draw the match, sent as a list of fields so only the ones that changed need sending
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MatchState {
+    //Indexed by Paddle.player_idx
+    pub paddle_y: [f32; PLAYER_COUNT],
+    pub ball_position: Vec2,
+    pub ball_velocity: Vec2,
+    pub score: [u32; 2]
+}
+
+const FIELD_COUNT: usize = 8;
+
+//Everything in a state packet but its events, with every field included
+const STATE_SIZE: usize = 7 + 4 + 2 + 4 + 2 + FIELD_COUNT * 4 + 1;
+//A ball hit and its tick, the biggest event there is
+const MAX_EVENT_SIZE: usize = 4 + 1 + 8 + 8 + 1;
+//Most events sent in one state so it fits in a packet, a client missing more than this only gets the newest
+pub const MAX_STATE_EVENTS: usize = (MAX_PACKET_SIZE - STATE_SIZE) / MAX_EVENT_SIZE;
+
+impl MatchState {
+    pub fn capture(world: &World) -> MatchState {
+        use specs::Join;
+
+        let transforms = world.read_storage::<TransformComponent>();
+        let mut state = MatchState {
+            score: world.read_resource::<Score>().0,
+            ..MatchState::default()
+        };
+        for (paddle, transform) in (&world.read_storage::<Paddle>(), &transforms).join() {
+            if let Some(y) = state.paddle_y.get_mut(paddle.player_idx as usize) {
+                *y = transform.position.y;
+            }
+        }
+        for (_, transform, physics) in (&world.read_storage::<Ball>(), &transforms, &world.read_storage::<PhysicsComponent>()).join() {
+            state.ball_position = transform.position;
+            state.ball_velocity = physics.velocity;
+        }
+        state
+    }
+
+    //The state a rollback snapshot was taken in, so netplay hosts only show spectators ticks that are certain
+    pub fn from_snapshot(snapshot: &Snapshot) -> MatchState {
+        let position = |entity| snapshot.transforms.iter().find(|(other, _)| *other == entity).map(|(_, transform)| transform.position);
+        let mut state = MatchState {
+            score: snapshot.score.0,
+            ..MatchState::default()
+        };
+        for (entity, paddle) in snapshot.paddles.iter() {
+            if let (Some(y), Some(position)) = (state.paddle_y.get_mut(paddle.player_idx as usize), position(*entity)) {
+                *y = position.y;
+            }
+        }
+        for (entity, _) in snapshot.balls.iter() {
+            state.ball_position = position(*entity).unwrap_or_default();
+            state.ball_velocity = snapshot.physics.iter().find(|(other, _)| other == entity).map(|(_, physics)| physics.velocity).unwrap_or_default();
+        }
+        state
+    }
+
+    //Moves the world's paddles and ball to match, the simulation doesn't run on clients
+    pub fn apply(&self, world: &mut World) {
+        use specs::Join;
+
+        let mut transforms = world.write_storage::<TransformComponent>();
+        for (paddle, transform) in (&world.read_storage::<Paddle>(), &mut transforms).join() {
+            if let Some(y) = self.paddle_y.get(paddle.player_idx as usize) {
+                transform.position.y = *y;
+            }
+        }
+        for (_, transform, physics) in (&world.read_storage::<Ball>(), &mut transforms, &mut world.write_storage::<PhysicsComponent>()).join() {
+            transform.position = self.ball_position;
+            physics.velocity = self.ball_velocity;
+        }
+        world.write_resource::<Score>().0 = self.score;
+    }
+
+    fn to_fields(self) -> [u32; FIELD_COUNT] {
+        [self.paddle_y[0].to_bits(), self.paddle_y[1].to_bits(),
+         self.ball_position.x.to_bits(), self.ball_position.y.to_bits(),
+         self.ball_velocity.x.to_bits(), self.ball_velocity.y.to_bits(),
+         self.score[0], self.score[1]]
+    }
+
+    fn from_fields(fields: &[u32; FIELD_COUNT]) -> MatchState {
+        MatchState {
+            paddle_y: [f32::from_bits(fields[0]), f32::from_bits(fields[1])],
+            ball_position: Vec2::new(f32::from_bits(fields[2]), f32::from_bits(fields[3])),
+            ball_velocity: Vec2::new(f32::from_bits(fields[4]), f32::from_bits(fields[5])),
+            score: [fields[6], fields[7]]
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClientMessage {
+    Join {
+        room: String
+    },
+    //ack is the newest state tick the client has, so the server can send changes from it
+    Input {
+        sequence: u32,
+        ack: Option<u32>,
+        input: PlayerInput
+    },
+    Watch {
+        room: String
+    },
+    //Sent by spectators instead of input, so they keep getting states
+    Ack {
+        ack: Option<u32>
+    },
+    Leave
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ServerMessage {
+    Joined {
+        player: u32
+    },
+    Watching,
+    Rejected {
+        reason: String
+    },
+    //A state either sent whole or as the changes from base_tick, with the events since the client's ack
+    //Events are sent again until they're acknowledged, so clients have to skip the ones they already have
+    State {
+        tick: u32,
+        base_tick: Option<u32>,
+        //Bit n is set if field n is included
+        changed: u16,
+        fields: Vec<u32>,
+        events: Vec<(u32, GameEvent)>,
+        spectators: u16
+    }
+}
+
+//The newest states sent, every client is sent the newest as the changes from the one it last acknowledged
+//Events are kept until every client has acknowledged a state from after them
+pub struct StateHistory {
+    states: VecDeque<(u32, MatchState)>,
+    //With the tick they happened on, oldest first
+    events: Vec<(u32, GameEvent)>
+}
+
+impl Default for StateHistory {
+    fn default() -> StateHistory {
+        StateHistory::new()
+    }
+}
+
+impl StateHistory {
+    pub fn new() -> StateHistory {
+        StateHistory {
+            states: VecDeque::with_capacity(STATE_HISTORY),
+            events: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, tick: u32, state: MatchState) {
+        if self.states.len() == STATE_HISTORY {
+            self.states.pop_front();
+        }
+        self.states.push_back((tick, state));
+    }
+
+    pub fn newest_tick(&self) -> Option<u32> {
+        self.states.back().map(|(tick, _)| *tick)
+    }
+
+    //Events have to be added in the order they happened
+    pub fn add_events<I: IntoIterator<Item = (u32, GameEvent)>>(&mut self, events: I) {
+        self.events.extend(events);
+    }
+
+    //The tick a client with ack needs every event after, None if it needs all of them
+    //Without an ack the client has only just joined, so it only needs the events since the state before the newest
+    fn events_after(&self, ack: Option<u32>) -> Option<u32> {
+        match ack {
+            None => {
+                self.states.iter().rev().nth(1).map(|(tick, _)| *tick)
+            },
+            Some(ack) => {
+                Some(ack)
+            }
+        }
+    }
+
+    //The newest state for a client that has ack, None if nothing has been pushed yet
+    //Includes every event up to the state the client hasn't acknowledged
+    pub fn message(&self, ack: Option<u32>, spectators: usize) -> Option<ServerMessage> {
+        let (tick, state) = self.states.back()?;
+        let base = ack.and_then(|ack| self.states.iter().find(|(tick, _)| *tick == ack)).map(|(tick, state)| (*tick, state));
+        let after = self.events_after(ack);
+        let events: Vec<(u32, GameEvent)> = self.events.iter().filter(|(event_tick, _)| event_tick <= tick && after.is_none_or(|after| *event_tick > after)).cloned().collect();
+        Some(ServerMessage::state(*tick, state, base, &events, spectators))
+    }
+
+    //Forgets the events every client has, acks are the newest state each client has
+    //Events from before the oldest state are forgotten anyway, a client that far behind gets a whole state and has missed them
+    pub fn forget_events<I: IntoIterator<Item = Option<u32>>>(&mut self, acks: I) {
+        let (oldest, newest) = match (self.states.front(), self.states.back()) {
+            (Some((oldest, _)), Some((newest, _))) => {
+                (*oldest, *newest)
+            },
+            _ => {
+                return;
+            }
+        };
+        //With no clients every event that's been sent is forgotten
+        let known_through = match acks.into_iter().map(|ack| self.events_after(ack)).min() {
+            None => {
+                Some(newest)
+            },
+            Some(after) => {
+                after.map(|after| after.min(newest))
+            }
+        };
+        self.events.retain(|(tick, _)| *tick >= oldest && known_through.is_none_or(|through| *tick > through));
+    }
+}
+
+//Fills in the fields left out of a state with base's, base has to be the state from the message's base_tick
+pub fn apply_delta(base: Option<&MatchState>, changed: u16, fields: &[u32]) -> Option<MatchState> {
+    let mut all_fields = match base {
+        None => {
+            if changed.count_ones() as usize != FIELD_COUNT {
+                return None;
+            }
+            [0; FIELD_COUNT]
+        },
+        Some(base) => {
+            base.to_fields()
+        }
+    };
+    let mut fields = fields.iter();
+    for (idx, field) in all_fields.iter_mut().enumerate() {
+        if changed & (1 << idx) != 0 {
+            *field = *fields.next()?;
+        }
+    }
+    Some(MatchState::from_fields(&all_fields))
+}
+
+fn write_header(packet: &mut Vec<u8>, kind: u8) {
+    packet.write_u32::<LittleEndian>(MAGIC).unwrap();
+    packet.write_u16::<LittleEndian>(PROTOCOL_VERSION).unwrap();
+    packet.write_u8(kind).unwrap();
+}
+
+//Returns the message kind, None for packets from other games or versions
+fn read_header(reader: &mut Cursor<&[u8]>) -> Option<u8> {
+    if reader.read_u32::<LittleEndian>().ok()? != MAGIC || reader.read_u16::<LittleEndian>().ok()? != PROTOCOL_VERSION {
+        return None;
+    }
+    reader.read_u8().ok()
+}
+
+//Strings longer than 255 bytes are cut short, at a character boundary so they're still valid UTF-8
+fn write_string(packet: &mut Vec<u8>, string: &str) {
+    let bytes = discovery::truncate(string, u8::MAX as usize).as_bytes();
+    packet.write_u8(bytes.len() as u8).unwrap();
+    packet.extend_from_slice(bytes);
+}
+
+fn read_string(reader: &mut Cursor<&[u8]>) -> Option<String> {
+    let length = reader.read_u8().ok()? as usize;
+    let start = reader.position() as usize;
+    let bytes = reader.get_ref().get(start..start + length)?;
+    reader.set_position((start + length) as u64);
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+fn write_vec2(packet: &mut Vec<u8>, value: Vec2) {
+    packet.write_f32::<LittleEndian>(value.x).unwrap();
+    packet.write_f32::<LittleEndian>(value.y).unwrap();
+}
+
+fn read_vec2(reader: &mut Cursor<&[u8]>) -> Option<Vec2> {
+    Some(Vec2::new(reader.read_f32::<LittleEndian>().ok()?, reader.read_f32::<LittleEndian>().ok()?))
+}
+
+fn write_event(packet: &mut Vec<u8>, event: &GameEvent) {
+    match event {
+        GameEvent::BallHit { position, velocity, paddle } => {
+            packet.write_u8(BALL_HIT).unwrap();
+            write_vec2(packet, *position);
+            write_vec2(packet, *velocity);
+            packet.write_i8(paddle.map_or(-1, |paddle| paddle as i8)).unwrap();
+        },
+        GameEvent::Goal { scorer, position } => {
+            packet.write_u8(GOAL).unwrap();
+            packet.write_u8(*scorer as u8).unwrap();
+            write_vec2(packet, *position);
+        },
+        GameEvent::MatchWon { winner } => {
+            packet.write_u8(MATCH_WON).unwrap();
+            packet.write_u8(*winner as u8).unwrap();
+        }
+    }
+}
+
+//Reads a player index, None if it's not one of the players, clients index arrays with them
+fn read_player(reader: &mut Cursor<&[u8]>) -> Option<u32> {
+    let player = reader.read_u8().ok()?;
+    if player as usize >= PLAYER_COUNT {
+        return None;
+    }
+    Some(player as u32)
+}
+
+fn read_event(reader: &mut Cursor<&[u8]>) -> Option<GameEvent> {
+    match reader.read_u8().ok()? {
+        BALL_HIT => {
+            let position = read_vec2(reader)?;
+            let velocity = read_vec2(reader)?;
+            let paddle = reader.read_i8().ok()?;
+            if paddle as isize >= PLAYER_COUNT as isize {
+                return None;
+            }
+            Some(GameEvent::BallHit {
+                position,
+                velocity,
+                paddle: if paddle < 0 { None } else { Some(paddle as u32) }
+            })
+        },
+        GOAL => {
+            let scorer = read_player(reader)?;
+            Some(GameEvent::Goal {
+                scorer,
+                position: read_vec2(reader)?
+            })
+        },
+        MATCH_WON => {
+            Some(GameEvent::MatchWon {
+                winner: read_player(reader)?
+            })
+        },
+        _ => {
+            None
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+        match self {
+            ClientMessage::Join { room } => {
+                write_header(&mut packet, JOIN);
+                write_string(&mut packet, room);
+            },
+            ClientMessage::Input { sequence, ack, input } => {
+                write_header(&mut packet, INPUT);
+                packet.write_u32::<LittleEndian>(*sequence).unwrap();
+                packet.write_u32::<LittleEndian>(ack.unwrap_or(NO_BASE)).unwrap();
+                packet.write_i16::<LittleEndian>(input.axis_y).unwrap();
+                packet.write_u8(input.ai as u8).unwrap();
+            },
+            ClientMessage::Watch { room } => {
+                write_header(&mut packet, WATCH);
+                write_string(&mut packet, room);
+            },
+            ClientMessage::Ack { ack } => {
+                write_header(&mut packet, ACK);
+                packet.write_u32::<LittleEndian>(ack.unwrap_or(NO_BASE)).unwrap();
+            },
+            ClientMessage::Leave => {
+                write_header(&mut packet, LEAVE);
+            }
+        }
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<ClientMessage> {
+        let mut reader = Cursor::new(packet);
+        let kind = read_header(&mut reader)?;
+        match kind {
+            JOIN | WATCH => {
+                let room = read_string(&mut reader)?;
+                if room.len() > MAX_ROOM_NAME {
+                    return None;
+                }
+                if kind == JOIN {
+                    Some(ClientMessage::Join {
+                        room
+                    })
+                } else {
+                    Some(ClientMessage::Watch {
+                        room
+                    })
+                }
+            },
+            ACK => {
+                let ack = reader.read_u32::<LittleEndian>().ok()?;
+                Some(ClientMessage::Ack {
+                    ack: if ack == NO_BASE { None } else { Some(ack) }
+                })
+            },
+            INPUT => {
+                let sequence = reader.read_u32::<LittleEndian>().ok()?;
+                let ack = reader.read_u32::<LittleEndian>().ok()?;
+                Some(ClientMessage::Input {
+                    sequence,
+                    ack: if ack == NO_BASE { None } else { Some(ack) },
+                    input: PlayerInput {
+                        axis_y: reader.read_i16::<LittleEndian>().ok()?,
+                        ai: reader.read_u8().ok()? != 0
+                    }
+                })
+            },
+            LEAVE => {
+                Some(ClientMessage::Leave)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+}
+
+impl ServerMessage {
+    //Only includes the fields that are different from base, if there is one
+    //If there are too many events the oldest are left out, they're the ones long past on the client
+    fn state(tick: u32, state: &MatchState, base: Option<(u32, &MatchState)>, events: &[(u32, GameEvent)], spectators: usize) -> ServerMessage {
+        let fields = state.to_fields();
+        let base_fields = base.map(|(_, base)| base.to_fields());
+        let mut changed = 0;
+        let mut changed_fields = Vec::with_capacity(FIELD_COUNT);
+        for (idx, field) in fields.iter().enumerate() {
+            if base_fields.is_none_or(|base_fields| base_fields[idx] != *field) {
+                changed |= 1 << idx;
+                changed_fields.push(*field);
+            }
+        }
+        ServerMessage::State {
+            tick,
+            base_tick: base.map(|(tick, _)| tick),
+            changed,
+            fields: changed_fields,
+            events: events[events.len().saturating_sub(MAX_STATE_EVENTS)..].to_vec(),
+            spectators: spectators.min(u16::MAX as usize) as u16
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+        match self {
+            ServerMessage::Joined { player } => {
+                write_header(&mut packet, JOINED);
+                packet.write_u32::<LittleEndian>(*player).unwrap();
+            },
+            ServerMessage::Watching => {
+                write_header(&mut packet, WATCHING);
+            },
+            ServerMessage::Rejected { reason } => {
+                write_header(&mut packet, REJECTED);
+                write_string(&mut packet, reason);
+            },
+            ServerMessage::State { tick, base_tick, changed, fields, events, spectators } => {
+                write_header(&mut packet, STATE);
+                packet.write_u32::<LittleEndian>(*tick).unwrap();
+                packet.write_u16::<LittleEndian>(*spectators).unwrap();
+                packet.write_u32::<LittleEndian>(base_tick.unwrap_or(NO_BASE)).unwrap();
+                packet.write_u16::<LittleEndian>(*changed).unwrap();
+                for field in fields.iter() {
+                    packet.write_u32::<LittleEndian>(*field).unwrap();
+                }
+                let count = events.len().min(MAX_STATE_EVENTS);
+                packet.write_u8(count as u8).unwrap();
+                for (tick, event) in events.iter().skip(events.len() - count) {
+                    packet.write_u32::<LittleEndian>(*tick).unwrap();
+                    write_event(&mut packet, event);
+                }
+            }
+        }
+        packet
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<ServerMessage> {
+        let mut reader = Cursor::new(packet);
+        match read_header(&mut reader)? {
+            JOINED => {
+                Some(ServerMessage::Joined {
+                    player: reader.read_u32::<LittleEndian>().ok()?
+                })
+            },
+            WATCHING => {
+                Some(ServerMessage::Watching)
+            },
+            REJECTED => {
+                Some(ServerMessage::Rejected {
+                    reason: read_string(&mut reader)?
+                })
+            },
+            STATE => {
+                let tick = reader.read_u32::<LittleEndian>().ok()?;
+                let spectators = reader.read_u16::<LittleEndian>().ok()?;
+                let base_tick = reader.read_u32::<LittleEndian>().ok()?;
+                let changed = reader.read_u16::<LittleEndian>().ok()?;
+                let mut fields = Vec::with_capacity(changed.count_ones() as usize);
+                for _ in 0..changed.count_ones() {
+                    fields.push(reader.read_u32::<LittleEndian>().ok()?);
+                }
+                let count = reader.read_u8().ok()?;
+                let mut events = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let tick = reader.read_u32::<LittleEndian>().ok()?;
+                    events.push((tick, read_event(&mut reader)?));
+                }
+                Some(ServerMessage::State {
+                    tick,
+                    base_tick: if base_tick == NO_BASE { None } else { Some(base_tick) },
+                    changed,
+                    fields,
+                    events,
+                    spectators
+                })
+            },
+            _ => {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_with_too_many_events_fits_in_a_packet() {
+        let event = GameEvent::BallHit {
+            position: Vec2::new(1.0, 2.0),
+            velocity: Vec2::new(3.0, 4.0),
+            paddle: Some(1)
+        };
+        let mut history = StateHistory::new();
+        history.add_events((0..300).map(|tick| (tick, event)));
+        history.push(300, MatchState::default());
+        let packet = history.message(None, 0).unwrap().encode();
+        assert!(packet.len() <= MAX_PACKET_SIZE, "State is {} bytes", packet.len());
+        match ServerMessage::decode(&packet) {
+            Some(ServerMessage::State { events: decoded, .. }) => {
+                assert_eq!(decoded.len(), MAX_STATE_EVENTS);
+                assert_eq!(decoded[0].0, 300 - MAX_STATE_EVENTS as u32);
+                assert_eq!(decoded.last().unwrap().0, 299);
+            },
+            _ => {
+                panic!("The state didn't decode");
+            }
+        }
+    }
+    //Encodes a state holding just the event, then changes the byte at offset from the start of the event
+    fn state_with_event(event: GameEvent, offset: usize, value: u8) -> Vec<u8> {
+        let mut history = StateHistory::new();
+        history.push(3, MatchState::default());
+        history.add_events(vec![(3, event)]);
+        let mut packet = history.message(None, 0).unwrap().encode();
+        //The event is last, after its tick
+        let start = STATE_SIZE + 4;
+        packet[start + offset] = value;
+        packet
+    }
+
+    fn decoded_events(packet: &[u8]) -> Option<Vec<(u32, GameEvent)>> {
+        match ServerMessage::decode(packet)? {
+            ServerMessage::State { events, .. } => Some(events),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn events_with_players_out_of_range_are_rejected() {
+        let goal = GameEvent::Goal { scorer: 1, position: Vec2::new(0.0, 0.0) };
+        let won = GameEvent::MatchWon { winner: 0 };
+        let hit = GameEvent::BallHit { position: Vec2::new(0.0, 0.0), velocity: Vec2::new(1.0, 0.0), paddle: None };
+        assert_eq!(decoded_events(&state_with_event(goal, 1, 1)).unwrap()[0].1, goal);
+        assert_eq!(decoded_events(&state_with_event(won, 1, 0)).unwrap()[0].1, won);
+        assert_eq!(decoded_events(&state_with_event(hit, 17, 1)).unwrap()[0].1, GameEvent::BallHit { position: Vec2::new(0.0, 0.0), velocity: Vec2::new(1.0, 0.0), paddle: Some(1) });
+        assert_eq!(decoded_events(&state_with_event(hit, 17, 0xff)).unwrap()[0].1, hit);
+
+        for player in [PLAYER_COUNT as u8, 7, u8::MAX].iter() {
+            assert!(decoded_events(&state_with_event(goal, 1, *player)).is_none(), "Goal by player {} was accepted", player);
+            assert!(decoded_events(&state_with_event(won, 1, *player)).is_none(), "Match won by player {} was accepted", player);
+        }
+        for paddle in [PLAYER_COUNT as u8, 100].iter() {
+            assert!(decoded_events(&state_with_event(hit, 17, *paddle)).is_none(), "Hit on paddle {} was accepted", paddle);
+        }
+    }
+    #[test]
+    fn long_strings_are_cut_without_splitting_characters() {
+        //Each é is two bytes, so the 255 byte limit falls in the middle of one
+        let reason = format!("x{}", "é".repeat(200));
+        let packet = ServerMessage::Rejected { reason: reason.clone() }.encode();
+        match ServerMessage::decode(&packet) {
+            Some(ServerMessage::Rejected { reason: decoded }) => {
+                assert_eq!(decoded.len(), 255);
+                assert!(reason.starts_with(&decoded));
+            },
+            _ => {
+                panic!("The rejection didn't decode");
+            }
+        }
+    }
+    fn test_state(tick: u32) -> MatchState {
+        MatchState {
+            paddle_y: [1.5, -2.0],
+            ball_position: Vec2::new(tick as f32 * 0.1, 3.0),
+            ball_velocity: Vec2::new(10.0, -4.0),
+            score: [tick / 100, 2]
+        }
+    }
+
+    fn test_events() -> Vec<(u32, GameEvent)> {
+        vec![(2, GameEvent::BallHit { position: Vec2::new(0.5, 0.5), velocity: Vec2::new(1.0, 1.0), paddle: Some(0) }),
+             (4, GameEvent::Goal { scorer: 0, position: Vec2::new(-1.0, 2.0) }),
+             (5, GameEvent::MatchWon { winner: 0 }),
+             (6, GameEvent::BallHit { position: Vec2::new(0.5, 0.5), velocity: Vec2::new(1.0, 1.0), paddle: None })]
+    }
+
+    fn client_messages() -> Vec<ClientMessage> {
+        vec![ClientMessage::Join { room: DEFAULT_ROOM.to_string() },
+             ClientMessage::Join { room: "ünïcode".to_string() },
+             ClientMessage::Input { sequence: 12, ack: Some(9), input: PlayerInput { axis_y: -32768, ai: false } },
+             ClientMessage::Input { sequence: 0, ack: None, input: PlayerInput { axis_y: 100, ai: true } },
+             ClientMessage::Watch { room: "".to_string() },
+             ClientMessage::Ack { ack: Some(300) },
+             ClientMessage::Ack { ack: None },
+             ClientMessage::Leave]
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        let mut history = StateHistory::new();
+        history.push(3, test_state(3));
+        history.push(6, test_state(6));
+        history.add_events(test_events());
+        vec![ServerMessage::Joined { player: 1 },
+             ServerMessage::Watching,
+             ServerMessage::Rejected { reason: "Room is full".to_string() },
+             history.message(None, 2).unwrap(),
+             history.message(Some(3), 0).unwrap(),
+             history.message(Some(6), 0).unwrap()]
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for message in client_messages() {
+            assert_eq!(ClientMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for message in server_messages() {
+            assert_eq!(ServerMessage::decode(&message.encode()).unwrap(), message);
+        }
+    }
+
+    fn message_events(message: Option<ServerMessage>) -> Vec<u32> {
+        match message {
+            Some(ServerMessage::State { events, .. }) => events.iter().map(|(tick, _)| *tick).collect(),
+            _ => panic!("Not a state")
+        }
+    }
+
+    #[test]
+    fn events_are_sent_until_acknowledged() {
+        let mut history = StateHistory::new();
+        history.push(3, test_state(3));
+        history.add_events(test_events());
+        //Events after the newest state wait for the next one
+        assert_eq!(message_events(history.message(None, 0)), vec![2]);
+        history.push(6, test_state(6));
+        assert_eq!(message_events(history.message(Some(3), 0)), vec![4, 5, 6]);
+        //A client without an ack only gets the events since the state before the newest
+        assert_eq!(message_events(history.message(None, 0)), vec![4, 5, 6]);
+
+        //Kept for the client that hasn't acknowledged them
+        history.forget_events(vec![Some(6), Some(3)]);
+        assert_eq!(message_events(history.message(Some(3), 0)), vec![4, 5, 6]);
+        assert_eq!(message_events(history.message(Some(6), 0)), Vec::<u32>::new());
+        history.forget_events(vec![Some(6), Some(6)]);
+        history.push(9, test_state(9));
+        assert_eq!(message_events(history.message(Some(3), 0)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn unacknowledged_events_are_forgotten_with_their_states() {
+        let mut history = StateHistory::new();
+        history.add_events(test_events());
+        for tick in 1..=(STATE_HISTORY as u32 + 8) {
+            history.push(tick, test_state(tick));
+            //Stuck before the first state, so it never acknowledges anything kept
+            history.forget_events(vec![Some(0)]);
+        }
+        //The oldest state kept is after every event
+        assert_eq!(message_events(history.message(Some(1), 0)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn apply_delta_without_base() {
+        let mut history = StateHistory::new();
+        history.push(3, test_state(3));
+        match history.message(None, 0).unwrap() {
+            ServerMessage::State { base_tick, changed, fields, .. } => {
+                assert_eq!(base_tick, None);
+                assert_eq!(apply_delta(None, changed, &fields), Some(test_state(3)));
+                //A state without a base has to have every field
+                assert_eq!(apply_delta(None, changed & !1, &fields[1..]), None);
+            },
+            _ => {
+                panic!("Not a state");
+            }
+        }
+    }
+
+    #[test]
+    fn apply_delta_with_base() {
+        let mut history = StateHistory::new();
+        history.push(3, test_state(3));
+        history.push(6, test_state(6));
+        match history.message(Some(3), 0).unwrap() {
+            ServerMessage::State { base_tick, changed, fields, .. } => {
+                assert_eq!(base_tick, Some(3));
+                //Only the ball's x position changed
+                assert_eq!(fields.len(), 1);
+                assert_eq!(apply_delta(Some(&test_state(3)), changed, &fields), Some(test_state(6)));
+                assert_eq!(apply_delta(Some(&test_state(3)), changed, &[]), None);
+            },
+            _ => {
+                panic!("Not a state");
+            }
+        }
+
+        //States older than the history are sent whole
+        match history.message(Some(1), 0).unwrap() {
+            ServerMessage::State { base_tick, changed, .. } => {
+                assert_eq!(base_tick, None);
+                assert_eq!(changed.count_ones() as usize, FIELD_COUNT);
+            },
+            _ => {
+                panic!("Not a state");
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_packets() {
+        for message in client_messages() {
+            let packet = message.encode();
+            for length in 0..packet.len() {
+                assert!(ClientMessage::decode(&packet[..length]).is_none(), "Decoded the first {} bytes of {:?}", length, message);
+            }
+        }
+        for message in server_messages() {
+            let packet = message.encode();
+            for length in 0..packet.len() {
+                assert!(ServerMessage::decode(&packet[..length]).is_none(), "Decoded the first {} bytes of {:?}", length, message);
+            }
+        }
+    }
+
+    #[test]
+    fn other_games_and_versions_are_rejected() {
+        let mut packet = ClientMessage::Leave.encode();
+        packet[0] ^= 0xff;
+        assert!(ClientMessage::decode(&packet).is_none());
+        let mut packet = ServerMessage::Watching.encode();
+        packet[4] = packet[4].wrapping_add(1);
+        assert!(ServerMessage::decode(&packet).is_none());
+        let mut packet = ClientMessage::Leave.encode();
+        packet[6] = 200;
+        assert!(ClientMessage::decode(&packet).is_none());
+    }
+
+    #[test]
+    fn long_room_names_are_rejected() {
+        let packet = ClientMessage::Join { room: "r".repeat(MAX_ROOM_NAME + 1) }.encode();
+        assert!(ClientMessage::decode(&packet).is_none());
+    }
+
+    #[test]
+    fn garbage_is_never_accepted() {
+        let mut rng = crate::game::GameRng::new(1);
+        for _ in 0..1000 {
+            let length = rng.range(0.0, MAX_PACKET_SIZE as f32) as usize;
+            let packet: Vec<u8> = (0..length).map(|_| rng.range(0.0, 256.0) as u8).collect();
+            assert!(ClientMessage::decode(&packet).is_none());
+            assert!(ServerMessage::decode(&packet).is_none());
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use specs::World;
+use shrev::EventChannel;
+
+use crate::events::GameEvent;
+use crate::game::{PlayerInput, TICK_RATE};
+use crate::camera::ARENA_WIDTH;
+use crate::protocol::{self, ClientMessage, ServerMessage, MatchState, MAX_PACKET_SIZE};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const JOIN_INTERVAL: Duration = Duration::from_millis(200);
+//The match ends if the server sends nothing for this long
+const TIMEOUT: Duration = Duration::from_secs(5);
+//States kept beyond the delay, to interpolate between and to decode the changes the server sends
+const STATE_HISTORY: usize = 32;
+
+//A match played on a pong-server, or watched on a server or netplay host
+//The client only sends input and draws the states it gets back
+pub struct RemoteMatch {
+    socket: UdpSocket,
+    server: SocketAddr,
+    //None when spectating
+    pub player: Option<usize>,
+    //Number of people watching, including this client if it's spectating
+    pub spectators: u32,
+    sequence: u32,
+    //Oldest first
+    states: VecDeque<(u32, MatchState)>,
+    //Most states kept, enough to cover the delay
+    history: usize,
+    //Events waiting until the match is shown at the tick they happened on
+    events: Vec<(u32, GameEvent)>,
+    //Newest state whose events have been taken, the server keeps sending events until the state after them is acknowledged
+    events_through: Option<u32>,
+    //Newest tick received and when, to estimate the server's current tick
+    newest: Option<(u32, Instant)>,
+    //The match is shown this many ticks behind the newest state, so there's usually a newer state to interpolate towards
+    delay: u32,
+    last_received: Instant
+}
+
+impl RemoteMatch {
+    //Joins room on the server at address, e.g. 192.168.0.2:7780, the port can be left out
+    pub fn join(address: &str, room: &str, delay: u32) -> Result<RemoteMatch, String> {
+        RemoteMatch::connect(address, room, delay, false)
+    }
+
+    //Watches the match in room without playing, address can also be a netplay host's spectator port
+    pub fn watch(address: &str, room: &str, delay: u32) -> Result<RemoteMatch, String> {
+        RemoteMatch::connect(address, room, delay, true)
+    }
+
+    fn connect(address: &str, room: &str, delay: u32, spectate: bool) -> Result<RemoteMatch, String> {
+        if room.len() > protocol::MAX_ROOM_NAME {
+            return Err(format!("Room names can be at most {} bytes", protocol::MAX_ROOM_NAME));
+        }
+        let with_port = if address.contains(':') { address.to_string() } else { format!("{}:{}", address, protocol::DEFAULT_SERVER_PORT) };
+        let server = with_port.to_socket_addrs().ok().and_then(|mut addresses| addresses.next()).ok_or(format!("Couldn't find {}", address))?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(JOIN_INTERVAL)).map_err(|e| e.to_string())?;
+        println!("{} room {} on {}", if spectate { "Watching" } else { "Joining" }, room, server);
+
+        let start = Instant::now();
+        let join = if spectate {
+            ClientMessage::Watch {
+                room: room.to_string()
+            }
+        } else {
+            ClientMessage::Join {
+                room: room.to_string()
+            }
+        };
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let player = 'connect: loop {
+            if start.elapsed() > CONNECT_TIMEOUT {
+                return Err(format!("No answer from {}", server));
+            }
+            socket.send_to(&join.encode(), server).map_err(|e| e.to_string())?;
+            let sent_at = Instant::now();
+            while sent_at.elapsed() < JOIN_INTERVAL {
+                let (size, from) = match socket.recv_from(&mut buffer) {
+                    Err(_) => {
+                        continue;
+                    },
+                    Ok(received) => {
+                        received
+                    }
+                };
+                match (from == server, ServerMessage::decode(&buffer[..size])) {
+                    (true, Some(ServerMessage::Joined { player })) => {
+                        break 'connect Some(player as usize);
+                    },
+                    (true, Some(ServerMessage::Watching)) => {
+                        break 'connect None;
+                    },
+                    (true, Some(ServerMessage::Rejected { reason })) => {
+                        return Err(reason);
+                    },
+                    _ => {}
+                }
+            }
+        };
+        match player {
+            None => {
+                println!("Watching room {}, {} ticks behind", room, delay);
+            },
+            Some(player) => {
+                println!("Joined room {} as player {}", room, player + 1);
+            }
+        }
+
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let history = (delay / protocol::STATE_INTERVAL) as usize + STATE_HISTORY;
+        Ok(RemoteMatch {
+            socket,
+            server,
+            player,
+            spectators: 0,
+            sequence: 0,
+            states: VecDeque::with_capacity(history),
+            history,
+            events: Vec::new(),
+            events_through: None,
+            newest: None,
+            delay,
+            last_received: Instant::now()
+        })
+    }
+
+    //Sent every frame, the server uses whichever input arrived last
+    //Spectators only acknowledge the newest state, their input is never sent
+    pub fn send_input(&mut self, input: PlayerInput) {
+        let ack = self.states.back().map(|(tick, _)| *tick);
+        let message = match self.player {
+            None => {
+                ClientMessage::Ack {
+                    ack
+                }
+            },
+            Some(_) => {
+                self.sequence += 1;
+                ClientMessage::Input {
+                    sequence: self.sequence,
+                    ack,
+                    input
+                }
+            }
+        };
+        let _ = self.socket.send_to(&message.encode(), self.server);
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while let Ok((size, from)) = self.socket.recv_from(&mut buffer) {
+            if from != self.server {
+                continue;
+            }
+            let (tick, base_tick, changed, fields, events, spectators) = match ServerMessage::decode(&buffer[..size]) {
+                Some(ServerMessage::State { tick, base_tick, changed, fields, events, spectators }) => (tick, base_tick, changed, fields, events, spectators),
+                _ => {
+                    continue;
+                }
+            };
+            self.last_received = Instant::now();
+            self.spectators = spectators as u32;
+            //A state has every event since the client's ack, so only the ones after the last state taken are new
+            //Late states are no use, a newer state has already brought their events
+            if self.events_through.is_none_or(|through| tick > through) {
+                let through = self.events_through;
+                self.events.extend(events.into_iter().filter(|(event_tick, _)| through.is_none_or(|through| *event_tick > through)));
+                self.events_through = Some(tick);
+            }
+            if self.states.back().is_some_and(|(newest, _)| tick <= *newest) {
+                continue;
+            }
+
+            let base = match base_tick {
+                None => {
+                    None
+                },
+                Some(base_tick) => {
+                    match self.states.iter().find(|(tick, _)| *tick == base_tick) {
+                        None => {
+                            continue;
+                        },
+                        Some((_, state)) => {
+                            Some(state)
+                        }
+                    }
+                }
+            };
+            let state = match protocol::apply_delta(base, changed, &fields) {
+                None => {
+                    continue;
+                },
+                Some(state) => {
+                    state
+                }
+            };
+            if self.states.len() == self.history {
+                self.states.pop_front();
+            }
+            self.states.push_back((tick, state));
+            self.newest = Some((tick, Instant::now()));
+        }
+    }
+
+    //Receives states and moves the world to where the match was delay ticks ago, interpolating between states
+    pub fn update(&mut self, world: &mut World) -> Result<(), String> {
+        self.receive();
+        if self.last_received.elapsed() > TIMEOUT {
+            return Err("Lost connection to the server".to_string());
+        }
+        let (newest_tick, received_at) = match self.newest {
+            None => {
+                return Ok(());
+            },
+            Some(newest) => {
+                newest
+            }
+        };
+
+        //Where the server is now, guessed from how long ago the newest state arrived
+        let server_tick = newest_tick as f32 + received_at.elapsed().as_secs_f32() / TICK_RATE;
+        let shown_tick = server_tick - self.delay as f32;
+
+        let next = self.states.iter().position(|(tick, _)| *tick as f32 > shown_tick);
+        let state = match next {
+            //Ahead of every state, so hold the newest rather than guessing
+            None => {
+                self.states.back().unwrap().1
+            },
+            Some(0) => {
+                self.states[0].1
+            },
+            Some(next) => {
+                let (from_tick, from) = self.states[next - 1];
+                let (to_tick, to) = self.states[next];
+                let t = (shown_tick - from_tick as f32) / (to_tick - from_tick) as f32;
+                let lerp = |a: f32, b: f32| a + (b - a) * t;
+                let mut state = from;
+                for (y, to_y) in state.paddle_y.iter_mut().zip(to.paddle_y.iter()) {
+                    *y = lerp(*y, *to_y);
+                }
+                //After a goal the ball jumps back to the middle, which shouldn't be drawn sweeping across the arena
+                if (to.ball_position.x - from.ball_position.x).abs() < ARENA_WIDTH / 2.0 {
+                    state.ball_position.x = lerp(from.ball_position.x, to.ball_position.x);
+                    state.ball_position.y = lerp(from.ball_position.y, to.ball_position.y);
+                }
+                state
+            }
+        };
+        state.apply(world);
+
+        let mut channel = world.write_resource::<EventChannel<GameEvent>>();
+        let due: Vec<GameEvent> = self.events.iter().filter(|(tick, _)| *tick as f32 <= shown_tick).map(|(_, event)| *event).collect();
+        self.events.retain(|(tick, _)| *tick as f32 > shown_tick);
+        channel.iter_write(due);
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) {
+        let _ = self.socket.send_to(&ClientMessage::Leave.encode(), self.server);
+    }
+}
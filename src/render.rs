@@ -7,65 +7,241 @@ use ash::vk_make_version;
 use std::ffi::{CString, CStr};
 use std::os::raw::{c_char, c_void};
 
-use specs::{Builder, Component, VecStorage, System, ReadStorage};
+use specs::{Builder, Component, VecStorage, System, Read, ReadExpect, ReadStorage, Write, Entities};
 use specs_derive::{Component};
 
 use byteorder::{NativeEndian, ByteOrder};
 
-use crate::fy_math::{Vec2, Vec4, Mat4, TransformComponent};
-
-//16MB for uploads
-const UPLOAD_BUFFER_SIZE: u64 = 16 * 1024 * 1024;
+use crate::fy_math::{Vec2, Vec4, Mat4, Color, TransformComponent};
+use crate::config::{VideoSettings, PostSettings, VsyncMode};
+use crate::text::{self, TextQueue, TextVertex};
+use crate::sprite::{self, Sprite, TextureHandle};
+use crate::upload::UploadQueue;
+use crate::mesh::{MeshData, Vertex};
+use crate::camera::{self, Camera, ARENA_WIDTH, ARENA_HEIGHT};
+use crate::particles::Particles;
+use crate::post::{self, PostProcess};
+use crate::capture::CapturedImage;
+use crate::profiler::Profiler;
+use crate::debug_draw::{DebugDraw, DebugVertex, MAX_DEBUG_VERTICES};
+
+//Each texture uses one descriptor set from the pool
+const MAX_TEXTURES: u32 = 64;
+
+//Descriptor sets allocated alongside the textures, for the font atlas and the post-processing targets
+const INTERNAL_DESCRIPTOR_SETS: u32 = 1 + post::DESCRIPTOR_SETS;
+
+//Maximum number of instances drawn through the batched path each frame
+const MAX_INSTANCES: usize = 65536;
+
+//Untextured draws sample a single white pixel, so every draw can share the textured pipeline
+const WHITE_TEXTURE: TextureHandle = TextureHandle(0);
+//Written before the scene pass, between it and post processing, and after post processing
+const TIMESTAMP_COUNT: u32 = 3;
+
+//Covers the whole arena, used to draw the background image
+const ARENA_VERTICES: [Vertex; 4] = [Vertex { position: Vec2 { x: -ARENA_WIDTH / 2.0, y: -ARENA_HEIGHT / 2.0 }, uv: Vec2 { x: 0.0, y: 0.0 } },
+                                     Vertex { position: Vec2 { x: ARENA_WIDTH / 2.0, y: -ARENA_HEIGHT / 2.0 }, uv: Vec2 { x: 1.0, y: 0.0 } },
+                                     Vertex { position: Vec2 { x: ARENA_WIDTH / 2.0, y: ARENA_HEIGHT / 2.0 }, uv: Vec2 { x: 1.0, y: 1.0 } },
+                                     Vertex { position: Vec2 { x: -ARENA_WIDTH / 2.0, y: ARENA_HEIGHT / 2.0 }, uv: Vec2 { x: 0.0, y: 1.0 } }];
+const ARENA_INDICES: [u32; 6] = [0,1,2,0,2,3];
 
 struct VulkanBuffer {
     buffer: vk::Buffer,
     allocation: vk_mem::Allocation
 }
 
+impl VulkanBuffer {
+    //The GPU must be done with it
+    fn destroy(&self, allocator: &vk_mem::Allocator) {
+        allocator.destroy_buffer(self.buffer, &self.allocation).unwrap();
+    }
+}
+
+pub struct Texture {
+    pub image: vk::Image,
+    pub allocation: vk_mem::Allocation,
+    pub view: vk::ImageView,
+    pub descriptor_set: vk::DescriptorSet
+}
+
+impl Texture {
+    //The GPU must be done with it, the descriptor set is freed along with its pool
+    pub fn destroy(&self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        unsafe { device.destroy_image_view(self.view, None) };
+        allocator.destroy_image(self.image, &self.allocation).unwrap();
+    }
+}
+
+struct Pipelines {
+    graphics: vk::Pipeline,
+    text: vk::Pipeline,
+    instanced: vk::Pipeline,
+    debug: vk::Pipeline
+}
+
+//SPIR-V for every shader stage, keyed by the source file name in shaders/
+pub struct ShaderCode {
+    pub stages: std::vec::Vec<(&'static str, std::vec::Vec<u8>)>
+}
+
+impl ShaderCode {
+    //The shaders compiled by build.rs
+    pub fn embedded() -> ShaderCode {
+        let stages = vec![("shader.vert", include_bytes!(concat!(env!("OUT_DIR"), "/shader.vert.spv")).to_vec()),
+                          ("shader.frag", include_bytes!(concat!(env!("OUT_DIR"), "/shader.frag.spv")).to_vec()),
+                          ("text.vert", include_bytes!(concat!(env!("OUT_DIR"), "/text.vert.spv")).to_vec()),
+                          ("text.frag", include_bytes!(concat!(env!("OUT_DIR"), "/text.frag.spv")).to_vec()),
+                          ("instanced.vert", include_bytes!(concat!(env!("OUT_DIR"), "/instanced.vert.spv")).to_vec()),
+                          ("instanced.frag", include_bytes!(concat!(env!("OUT_DIR"), "/instanced.frag.spv")).to_vec()),
+                          ("debug.vert", include_bytes!(concat!(env!("OUT_DIR"), "/debug.vert.spv")).to_vec()),
+                          ("fullscreen.vert", include_bytes!(concat!(env!("OUT_DIR"), "/fullscreen.vert.spv")).to_vec()),
+                          ("blur.frag", include_bytes!(concat!(env!("OUT_DIR"), "/blur.frag.spv")).to_vec()),
+                          ("post.frag", include_bytes!(concat!(env!("OUT_DIR"), "/post.frag.spv")).to_vec())];
+        ShaderCode {
+            stages
+        }
+    }
+
+    pub fn get(&self, name: &str) -> &[u8] {
+        let (_, spv) = self.stages.iter().find(|(stage, _)| *stage == name).unwrap();
+        spv
+    }
+}
+
 pub struct RenderContext {
     instance: ash::Instance,
+    debug_report_loader: DebugReport,
+    debug_call_back: vk::DebugReportCallbackEXT,
     phys_device: vk::PhysicalDevice,
     device: ash::Device,
+    surface_ext: Surface,
     surface: vk::SurfaceKHR,
     mem_allocator: vk_mem::Allocator,
     graphics_queue: vk::Queue,
     swapchain_ext: Swapchain,
     swapchain: vk::SwapchainKHR,
+    swapchain_images: std::vec::Vec<vk::Image>,
+    swapchain_image_views: std::vec::Vec<vk::ImageView>,
+    swapchain_format: vk::Format,
     sc_image_ready_sem: vk::Semaphore,
     render_finished_sem: vk::Semaphore,
+    //Signaled when the last frame submitted has finished on the GPU
+    frame_fence: vk::Fence,
+    command_pool: vk::CommandPool,
     graphics_command_buffer: vk::CommandBuffer,
     sub_command_pools: std::vec::Vec<vk::CommandPool>,
     sub_command_buffers: std::vec::Vec<vk::CommandBuffer>,
-    framebuffers: std::vec::Vec<vk::Framebuffer>,
     render_pass: vk::RenderPass,
-    graphics_pipeline: vk::Pipeline,
+    //Pipelines are created with it, only needed again when they're rebuilt
+    #[cfg(feature = "shader-hot-reload")]
+    msaa_samples: vk::SampleCountFlags,
+    msaa_image: Option<(vk::Image, vk_mem::Allocation, vk::ImageView)>,
+    post: PostProcess,
+    pipelines: Pipelines,
     pipeline_layout: vk::PipelineLayout,
     render_area: vk::Rect2D,
+    view_projection: Mat4,
     thread_pool: std::sync::Arc<rayon::ThreadPool>,
-    upload_buffer: VulkanBuffer
+    uploads: UploadQueue,
+    texture_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sampler: vk::Sampler,
+    font_atlas: Texture,
+    text_pipeline_layout: vk::PipelineLayout,
+    text_vertex_buffer: VulkanBuffer,
+    text_vertices: std::vec::Vec<TextVertex>,
+    text_command_buffer: vk::CommandBuffer,
+    textures: std::vec::Vec<Texture>,
+    background: Option<(TextureHandle, RenderComponent)>,
+    background_command_buffer: vk::CommandBuffer,
+    meshes: std::vec::Vec<Mesh>,
+    mesh_lookup: std::collections::HashMap<std::vec::Vec<u32>, MeshHandle>,
+    instance_buffer: VulkanBuffer,
+    instance_batches: std::vec::Vec<std::vec::Vec<InstanceData>>,
+    batch_command_buffer: vk::CommandBuffer,
+    debug_vertex_buffer: VulkanBuffer,
+    debug_command_buffer: vk::CommandBuffer,
+    //Host visible copy of a swapchain image, None if the swapchain can't be copied from
+    capture_buffer: Option<VulkanBuffer>,
+    capture_requested: bool,
+    capture_in_flight: bool,
+    captured: Option<CapturedImage>,
+    //Timestamps around the scene and post processing, None if the queue doesn't support timestamps
+    timestamp_pool: Option<vk::QueryPool>,
+    //Nanoseconds per timestamp tick
+    timestamp_period: f32,
+    timestamp_mask: u64,
+    //When the timed frame was submitted, its timestamps are read back at the start of the next frame
+    timestamp_submitted: Option<std::time::Instant>
 }
 
+//Layout must match the push constant block in shader.vert and shader.frag
+//The transform is the camera's view projection multiplied by the model matrix
+#[repr(C)]
+struct PushConstants {
+    transform: Mat4,
+    color: Color
+}
+
+//Per-instance data for instanced draws, layout must match the instance attributes in instanced.vert
 #[repr(C)]
 #[derive(Copy, Clone)]
-pub struct Vertex {
-    pub position: Vec2
+struct InstanceData {
+    position: Vec2,
+    scale: Vec2,
+    color: Color
 }
 
-#[derive(Component)]
-#[storage(VecStorage)]
-pub struct RenderComponent {
+//Index into the meshes owned by the RenderContext
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MeshHandle(pub usize);
+
+//Vertices and indices share a single GPU buffer, with the indices placed after the vertices
+struct Mesh {
     vertex_buffer: VulkanBuffer,
     index_offset: vk::DeviceSize,
     num_indices: u32
 }
 
+//Draws a mesh from the RenderContext, any number of entities can share the same mesh
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct RenderComponent {
+    pub mesh: MeshHandle,
+    pub color: Color
+}
+
+//Entities sharing a mesh are drawn together in one instanced draw call, which is much cheaper
+//than a RenderComponent when there are many of them
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Instanced {
+    pub mesh: MeshHandle,
+    pub scale: Vec2,
+    pub color: Color
+}
+
 impl RenderComponent {
-    pub fn new(context: &mut RenderContext, vertices: &[Vertex], indices: &[u32]) -> RenderComponent {
+    pub fn new(mesh: MeshHandle) -> RenderComponent {
+        RenderComponent::with_color(mesh, Color::WHITE)
+    }
+
+    pub fn with_color(mesh: MeshHandle, color: Color) -> RenderComponent {
+        RenderComponent {
+            mesh,
+            color
+        }
+    }
+}
+
+impl Mesh {
+    //The copy into the buffer is queued, it's submitted before the next frame is drawn
+    fn new(context: &mut RenderContext, vertices: &[Vertex], indices: &[u32]) -> Mesh {
         //Create a buffer to hold the vertices
         let vertices_size = vertices.len() * std::mem::size_of::<Vertex>();
         let indices_size = indices.len() * std::mem::size_of::<u32>();
         let buffer_size = (vertices_size + indices_size) as u64;
-        assert!(buffer_size< UPLOAD_BUFFER_SIZE, "Staging buffer not large enough for upload!");
         let (buffer, allocation, _) = {
             let buf_create = vk::BufferCreateInfo::builder()
                 .size(buffer_size)
@@ -80,59 +256,434 @@ impl RenderComponent {
             context.mem_allocator.create_buffer(&buf_create, &alloc_create).unwrap()
         };
 
-        //Copy vertex data to staging buffer
-        let data_ptr = context.mem_allocator.map_memory(&context.upload_buffer.allocation).unwrap() as *mut Vertex;
-        let index_ptr = unsafe { data_ptr.offset(vertices.len() as isize) as *mut u32};
-        let dest_verts = unsafe { core::slice::from_raw_parts_mut(data_ptr, vertices.len()) };
-        let dest_idxs = unsafe { core::slice::from_raw_parts_mut(index_ptr, indices.len()) };
-        dest_verts.copy_from_slice(vertices);
-        dest_idxs.copy_from_slice(indices);
-        let vertex_buffer = VulkanBuffer {
-            buffer,
-            allocation
-        };
+        let mut data = Vec::with_capacity(buffer_size as usize);
+        data.extend_from_slice(unsafe { core::slice::from_raw_parts(vertices.as_ptr() as *const u8, vertices_size) });
+        data.extend_from_slice(unsafe { core::slice::from_raw_parts(indices.as_ptr() as *const u8, indices_size) });
+        context.uploads.upload_buffer(&context.device, &context.mem_allocator, buffer, &data);
 
-        //Run command buffer to copy to vertex buffer
-        {
-            let begin_info = vk::CommandBufferBeginInfo::builder()
-                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
-                .build();
-            unsafe { context.device.begin_command_buffer(context.graphics_command_buffer, &begin_info).unwrap() };
+        Mesh {
+            vertex_buffer: VulkanBuffer {
+                buffer,
+                allocation
+            },
+            index_offset: vertices_size as vk::DeviceSize,
+            num_indices: indices.len() as u32
+        }
+    }
+}
 
-            let copy_region = vk::BufferCopy::builder()
-                .src_offset(0)
-                .dst_offset(0)
-                .size(buffer_size)
-                .build();
-            let regions = [copy_region];
-            unsafe { 
-                context.device.cmd_copy_buffer(context.graphics_command_buffer, context.upload_buffer.buffer, buffer, &regions);
-                context.device.end_command_buffer(context.graphics_command_buffer).unwrap(); 
-            }
+const PUSH_CONSTANT_SIZE: u32 = std::mem::size_of::<PushConstants>() as u32;
 
-            let cmd_bfrs = [context.graphics_command_buffer];
+//Picks the present mode matching the vsync setting, falling back to FIFO which is always available
+fn choose_present_mode(vsync: VsyncMode, available: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
+    let preferred: &[vk::PresentModeKHR] = match vsync {
+        VsyncMode::On => &[vk::PresentModeKHR::FIFO],
+        VsyncMode::Off => &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX],
+        VsyncMode::Mailbox => &[vk::PresentModeKHR::MAILBOX],
+        VsyncMode::Adaptive => &[vk::PresentModeKHR::FIFO_RELAXED]
+    };
 
-            let submit = vk::SubmitInfo::builder()
-                .command_buffers(&cmd_bfrs)
-                .build();
+    for mode in preferred.iter() {
+        if available.contains(mode) {
+            return *mode;
+        }
+    }
 
-            let submits = [submit];
+    println!("Vsync mode {:?} is not supported, falling back to FIFO", vsync);
+    vk::PresentModeKHR::FIFO
+}
 
-            unsafe {
-                context.device.queue_submit(context.graphics_queue, &submits, vk::Fence::null()).unwrap();
-                context.device.queue_wait_idle(context.graphics_queue).unwrap();
-            }
+//Picks the highest sample count the GPU supports for color attachments, up to the requested count
+fn choose_sample_count(requested: u32, supported: vk::SampleCountFlags) -> vk::SampleCountFlags {
+    let counts = [(8, vk::SampleCountFlags::TYPE_8), (4, vk::SampleCountFlags::TYPE_4), (2, vk::SampleCountFlags::TYPE_2)];
+    for (count, flags) in counts.iter() {
+        if *count <= requested && supported.contains(*flags) {
+            return *flags;
         }
+    }
+    vk::SampleCountFlags::TYPE_1
+}
 
-        RenderComponent {
-            vertex_buffer,
-            index_offset: vertices_size as vk::DeviceSize,
-            num_indices: indices.len() as u32
-        }
+//Creates the multisampled image the scene is drawn into before it's resolved, it is never read outside the render pass
+fn create_msaa_image(device: &ash::Device, allocator: &vk_mem::Allocator, width: u32, height: u32, samples: vk::SampleCountFlags) -> (vk::Image, vk_mem::Allocation, vk::ImageView) {
+    let (image, allocation, _) = {
+        let image_create = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(post::HDR_FORMAT)
+            .extent(vk::Extent3D::builder().width(width).height(height).depth(1).build())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .build();
+
+        let alloc_create = vk_mem::AllocationCreateInfo {
+            usage: vk_mem::MemoryUsage::GpuOnly,
+            ..Default::default()
+        };
+
+        allocator.create_image(&image_create, &alloc_create).unwrap()
+    };
+
+    let view = {
+        let create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(post::HDR_FORMAT)
+            .components(vk::ComponentMapping::builder().r(vk::ComponentSwizzle::IDENTITY).g(vk::ComponentSwizzle::IDENTITY).b(vk::ComponentSwizzle::IDENTITY).a(vk::ComponentSwizzle::IDENTITY).build())
+            .subresource_range(vk::ImageSubresourceRange::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .base_mip_level(0)
+                                .level_count(1)
+                                .base_array_layer(0)
+                                .layer_count(1)
+                                .build());
+        unsafe { device.create_image_view(&create_info, None).unwrap() }
+    };
+
+    (image, allocation, view)
+}
+
+//Creates a sampled image and queues a copy of the pixels into it
+fn create_sampled_image(device: &ash::Device, allocator: &vk_mem::Allocator, uploads: &mut UploadQueue, width: u32, height: u32, format: vk::Format, pixels: &[u8]) -> (vk::Image, vk_mem::Allocation, vk::ImageView) {
+    let (image, allocation, _) = {
+        let image_create = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D::builder().width(width).height(height).depth(1).build())
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .build();
+
+        let alloc_create = vk_mem::AllocationCreateInfo {
+            usage: vk_mem::MemoryUsage::GpuOnly,
+            ..Default::default()
+        };
+
+        allocator.create_image(&image_create, &alloc_create).unwrap()
+    };
+
+    let texel_size = match format {
+        vk::Format::R8_UNORM => 1,
+        vk::Format::R8G8B8A8_UNORM => 4,
+        other => panic!("No texel size for {:?}", other)
+    };
+    uploads.upload_image(device, allocator, image, width, height, texel_size, pixels);
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build();
+
+    let view = {
+        let create_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .components(vk::ComponentMapping::builder().r(vk::ComponentSwizzle::IDENTITY).g(vk::ComponentSwizzle::IDENTITY).b(vk::ComponentSwizzle::IDENTITY).a(vk::ComponentSwizzle::IDENTITY).build())
+            .subresource_range(subresource_range);
+        unsafe { device.create_image_view(&create_info, None).unwrap() }
+    };
+
+    (image, allocation, view)
+}
+
+//Allocates a descriptor set binding a texture and sampler, matching texture_set_layout
+pub fn create_texture_descriptor_set(device: &ash::Device, pool: vk::DescriptorPool, layout: vk::DescriptorSetLayout, view: vk::ImageView, sampler: vk::Sampler) -> vk::DescriptorSet {
+    let layouts = [layout];
+    let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(pool)
+        .set_layouts(&layouts);
+    let descriptor_set = unsafe { device.allocate_descriptor_sets(&alloc_info).unwrap()[0] };
+
+    let image_info = [vk::DescriptorImageInfo::builder()
+        .image_view(view)
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .build()];
+    let sampler_info = [vk::DescriptorImageInfo::builder()
+        .sampler(sampler)
+        .build()];
+
+    let writes = [vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&image_info)
+                    .build(),
+                  vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .image_info(&sampler_info)
+                    .build()];
+    unsafe { device.update_descriptor_sets(&writes, &[]) };
+
+    descriptor_set
+}
+
+//Builds a pipeline drawing into the given area of the screen with standard alpha blending
+#[allow(clippy::too_many_arguments)]
+pub fn create_pipeline(device: &ash::Device, render_pass: vk::RenderPass, layout: vk::PipelineLayout, v_spv: &[u8], f_spv: &[u8], vertex_bindings: &[vk::VertexInputBindingDescription], vertex_attributes: &[vk::VertexInputAttributeDescription], topology: vk::PrimitiveTopology, area: vk::Rect2D, samples: vk::SampleCountFlags) -> vk::Pipeline {
+    let mut f_code = vec![0; f_spv.len() / 4];
+    let mut v_code = vec![0; v_spv.len() / 4];
+
+    NativeEndian::read_u32_into(v_spv, v_code.as_mut_slice());
+    NativeEndian::read_u32_into(f_spv, f_code.as_mut_slice());
+
+    let create_info = vk::ShaderModuleCreateInfo::builder()
+        .code(f_code.as_slice())
+        .build();
+    let f_mod = unsafe { device.create_shader_module(&create_info, None).unwrap() };
+
+    let create_info = vk::ShaderModuleCreateInfo::builder()
+        .code(v_code.as_slice())
+        .build();
+    let v_mod = unsafe { device.create_shader_module(&create_info, None).unwrap() };
+
+    let entrypoint = CString::new("main").unwrap();
+    let v_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::VERTEX)
+        .module(v_mod)
+        .name(&entrypoint)
+        .build();
+    let f_stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::FRAGMENT)
+        .module(f_mod)
+        .name(&entrypoint)
+        .build();
+
+    let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+        .vertex_attribute_descriptions(vertex_attributes)
+        .vertex_binding_descriptions(vertex_bindings)
+        .build();
+
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(topology)
+        .primitive_restart_enable(false)
+        .build();
+
+    //Standard fill for rasterization
+    let raster_state = vk::PipelineRasterizationStateCreateInfo::builder()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(vk::PolygonMode::FILL)
+        .cull_mode(vk::CullModeFlags::NONE)
+        .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+        .depth_bias_enable(false)
+        .line_width(1.0)
+        .build();
+
+    //Viewport and scissor cover the given area, anything outside it is clipped
+    let viewport = [vk::Viewport::builder()
+        .x(area.offset.x as f32)
+        .y(area.offset.y as f32)
+        .width(area.extent.width as f32)
+        .height(area.extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0)
+        .build()];
+    let scissor = [area];
+
+    let view_state = vk::PipelineViewportStateCreateInfo::builder()
+        .viewports(&viewport)
+        .scissors(&scissor)
+        .build();
+
+    //Must match the sample count of the render pass's color attachment
+    let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(samples)
+        .sample_shading_enable(false)
+        .alpha_to_coverage_enable(false)
+        .alpha_to_one_enable(false)
+        .build();
+
+    //Standard alpha blending, so translucent colors can be used
+    let blend_attachment = [vk::PipelineColorBlendAttachmentState::builder()
+        .blend_enable(true)
+        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ONE)
+        .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .alpha_blend_op(vk::BlendOp::ADD)
+        .color_write_mask(vk::ColorComponentFlags::all())
+        .build()];
+
+    let blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+        .logic_op_enable(false)
+        .attachments(&blend_attachment)
+        .build();
+
+    let stages = [v_stage, f_stage];
+
+    let create_info = [vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&stages)
+        .vertex_input_state(&vertex_input)
+        .input_assembly_state(&input_assembly)
+        .viewport_state(&view_state)
+        .rasterization_state(&raster_state)
+        .multisample_state(&multisample_state)
+        .color_blend_state(&blend_state)
+        .render_pass(render_pass)
+        .subpass(0)
+        .layout(layout)
+        .build()];
+    let pipelines = unsafe { device.create_graphics_pipelines(vk::PipelineCache::null(), &create_info, None).unwrap() };
+    unsafe {
+        device.destroy_shader_module(v_mod, None);
+        device.destroy_shader_module(f_mod, None);
     }
+    pipelines[0]
 }
 
-const PUSH_CONSTANT_SIZE: u32 = std::mem::size_of::<Mat4>() as u32;
+//Builds every pipeline from the given shaders, called again whenever the shaders are reloaded
+fn create_pipelines(device: &ash::Device, render_pass: vk::RenderPass, pipeline_layout: vk::PipelineLayout, text_pipeline_layout: vk::PipelineLayout, render_area: vk::Rect2D, samples: vk::SampleCountFlags, code: &ShaderCode) -> Pipelines {
+    //The scene is letterboxed to the arena's aspect ratio, the HUD can still use the whole window
+    let scene_area = {
+        let (offset, size) = camera::letterbox(render_area.extent.width, render_area.extent.height);
+        vk::Rect2D::builder()
+            .offset(vk::Offset2D::builder().x(offset.x as i32).y(offset.y as i32).build())
+            .extent(vk::Extent2D::builder().width(size.x as u32).height(size.y as u32).build())
+            .build()
+    };
+
+    //Create a graphics pipeline around the vertex and fragment shaders
+    let graphics_pipeline = {
+        let vertex_binding = [vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(std::mem::size_of::<Vertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()];
+
+        let vertex_attribute = [vk::VertexInputAttributeDescription::builder()
+                                    .location(0)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(0)
+                                    .build(),
+                                vk::VertexInputAttributeDescription::builder()
+                                    .location(1)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(8)
+                                    .build()];
+
+        create_pipeline(device, render_pass, pipeline_layout, code.get("shader.vert"), code.get("shader.frag"), &vertex_binding, &vertex_attribute, vk::PrimitiveTopology::TRIANGLE_LIST, scene_area, samples)
+    };
+
+    //Text vertices are already in clip space, so no transform is needed
+    let text_pipeline = {
+        let vertex_binding = [vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(std::mem::size_of::<TextVertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()];
+
+        let vertex_attributes = [vk::VertexInputAttributeDescription::builder()
+                                    .location(0)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(0)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(1)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(8)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(2)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32B32A32_SFLOAT)
+                                    .offset(16)
+                                    .build()];
+
+        create_pipeline(device, render_pass, text_pipeline_layout, code.get("text.vert"), code.get("text.frag"), &vertex_binding, &vertex_attributes, vk::PrimitiveTopology::TRIANGLE_LIST, render_area, samples)
+    };
+
+    //Instanced draws take their transform and color from a second, per-instance vertex buffer
+    let instanced_pipeline = {
+        let vertex_bindings = [vk::VertexInputBindingDescription::builder()
+                                .binding(0)
+                                .stride(std::mem::size_of::<Vertex>() as u32)
+                                .input_rate(vk::VertexInputRate::VERTEX)
+                                .build(),
+                               vk::VertexInputBindingDescription::builder()
+                                .binding(1)
+                                .stride(std::mem::size_of::<InstanceData>() as u32)
+                                .input_rate(vk::VertexInputRate::INSTANCE)
+                                .build()];
+
+        let vertex_attributes = [vk::VertexInputAttributeDescription::builder()
+                                    .location(0)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(0)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(1)
+                                    .binding(1)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(0)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(2)
+                                    .binding(1)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(8)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(3)
+                                    .binding(1)
+                                    .format(vk::Format::R32G32B32A32_SFLOAT)
+                                    .offset(16)
+                                    .build()];
+
+        create_pipeline(device, render_pass, pipeline_layout, code.get("instanced.vert"), code.get("instanced.frag"), &vertex_bindings, &vertex_attributes, vk::PrimitiveTopology::TRIANGLE_LIST, scene_area, samples)
+    };
+
+    //Debug lines are already in world units and carry their own colors
+    let debug_pipeline = {
+        let vertex_binding = [vk::VertexInputBindingDescription::builder()
+            .binding(0)
+            .stride(std::mem::size_of::<DebugVertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+            .build()];
+
+        let vertex_attributes = [vk::VertexInputAttributeDescription::builder()
+                                    .location(0)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32_SFLOAT)
+                                    .offset(0)
+                                    .build(),
+                                 vk::VertexInputAttributeDescription::builder()
+                                    .location(1)
+                                    .binding(0)
+                                    .format(vk::Format::R32G32B32A32_SFLOAT)
+                                    .offset(8)
+                                    .build()];
+
+        create_pipeline(device, render_pass, pipeline_layout, code.get("debug.vert"), code.get("instanced.frag"), &vertex_binding, &vertex_attributes, vk::PrimitiveTopology::LINE_LIST, scene_area, samples)
+    };
+
+    Pipelines {
+        graphics: graphics_pipeline,
+        text: text_pipeline,
+        instanced: instanced_pipeline,
+        debug: debug_pipeline
+    }
+}
 
 unsafe extern "system" fn vulkan_debug_callback(
     _: vk::DebugReportFlagsEXT,
@@ -149,7 +700,7 @@ unsafe extern "system" fn vulkan_debug_callback(
 }
 
 impl RenderContext {
-    pub fn new(window: &sdl2::video::Window, window_size_x: u32, window_size_y: u32, thread_pool: std::sync::Arc<rayon::ThreadPool>, num_threads: usize) -> RenderContext {
+    pub fn new(window: &sdl2::video::Window, window_size_x: u32, window_size_y: u32, video_settings: &VideoSettings, post_settings: &PostSettings, thread_pool: std::sync::Arc<rayon::ThreadPool>, num_threads: usize) -> RenderContext {
         let sdl_vk_exts = window.vulkan_instance_extensions().unwrap();
         let entry = Entry::new().unwrap();
 
@@ -200,9 +751,9 @@ impl RenderContext {
         let inst_handle = instance.handle().as_raw() as usize;
         let surface_ext = Surface::new(&entry, &instance);
         let surface: vk::SurfaceKHR = vk::Handle::from_raw(window.vulkan_create_surface(inst_handle).unwrap());
-        let _surface_caps = unsafe { surface_ext.get_physical_device_surface_capabilities(physical_device, surface).unwrap() };
+        let surface_caps = unsafe { surface_ext.get_physical_device_surface_capabilities(physical_device, surface).unwrap() };
         let surface_formats = unsafe { surface_ext.get_physical_device_surface_formats(physical_device, surface).unwrap() };
-        let _surface_present_modes = unsafe { surface_ext.get_physical_device_surface_present_modes(physical_device, surface).unwrap() };
+        let surface_present_modes = unsafe { surface_ext.get_physical_device_surface_present_modes(physical_device, surface).unwrap() };
 
         let queue_props = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
 
@@ -267,68 +818,140 @@ impl RenderContext {
 
         let swapchain_ext = Swapchain::new(&instance, &device);
 
+        let limits = unsafe { instance.get_physical_device_properties(physical_device) }.limits;
+        //The scene is drawn in HDR, which can support fewer sample counts than the framebuffer limits say
+        let hdr_sample_counts = match unsafe { instance.get_physical_device_image_format_properties(physical_device, post::HDR_FORMAT, vk::ImageType::TYPE_2D, vk::ImageTiling::OPTIMAL,
+                                                                                                     vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT, vk::ImageCreateFlags::empty()) } {
+            Err(_) => {
+                vk::SampleCountFlags::TYPE_1
+            },
+            Ok(properties) => {
+                properties.sample_counts
+            }
+        };
+        let msaa_samples = choose_sample_count(video_settings.msaa, limits.framebuffer_color_sample_counts & hdr_sample_counts);
+        println!("Using {:?} MSAA samples", msaa_samples);
+
+        let present_mode = choose_present_mode(video_settings.vsync, &surface_present_modes);
+        println!("Using present mode {:?}", present_mode);
+
+        //Screen captures copy straight out of the swapchain images, which only works for 8 bit RGBA or BGRA formats
+        let swapchain_format = surface_formats[0].format;
+        let can_capture = surface_caps.supported_usage_flags.contains(vk::ImageUsageFlags::TRANSFER_SRC) && matches!(swapchain_format, vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB | vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB);
+        if !can_capture {
+            println!("Swapchain format {:?} can't be captured, screenshots and recording are disabled", swapchain_format);
+        }
+
         let swapchain = {
+            //Mailbox needs a third image so rendering never has to wait on presentation
+            let mut image_count = if present_mode == vk::PresentModeKHR::MAILBOX {
+                3
+            } else {
+                2
+            };
+            image_count = image_count.max(surface_caps.min_image_count);
+            if surface_caps.max_image_count > 0 {
+                image_count = image_count.min(surface_caps.max_image_count);
+            }
+
             let create_info = vk::SwapchainCreateInfoKHR::builder()
                 .surface(surface)
-                .min_image_count(2)
+                .min_image_count(image_count)
                 .image_format(surface_formats[0].format)           //This method picks the first available format and color space
                 .image_color_space(surface_formats[0].color_space) 
                 .image_extent(vk::Extent2D::builder().width(window_size_x).height(window_size_y).build())
                 .image_array_layers(1)
-                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+                .image_usage(if can_capture { vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC } else { vk::ImageUsageFlags::COLOR_ATTACHMENT })
                 .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
                 .pre_transform(vk::SurfaceTransformFlagsKHR::IDENTITY)
                 .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                .present_mode(vk::PresentModeKHR::FIFO) //FIFO is guaranteed to be available
+                .present_mode(present_mode)
                 .clipped(true);
             unsafe { swapchain_ext.create_swapchain(&create_info, None).unwrap() }
         };
 
         let render_pass = {
 
+            //The scene is drawn into an offscreen HDR image, which post-processing then copies to the swapchain
             //An attachment description describes the layout of the rendering attachment
-            let attachment = [vk::AttachmentDescription::builder()
-                .format(surface_formats[0].format) //Use the same format as the swapchain images
-                .samples(vk::SampleCountFlags::TYPE_1) //No multisampling
+            let scene_attachment = vk::AttachmentDescription::builder()
+                .format(post::HDR_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1) //Post-processing samples a regular single sampled image
                 .load_op(vk::AttachmentLoadOp::CLEAR) //Clear this image when the render pass begins (clear value is specified later)
-                .store_op(vk::AttachmentStoreOp::STORE) //Store this image at the end of rendering to present
+                .store_op(vk::AttachmentStoreOp::STORE) //Store this image at the end of rendering for post-processing
                 .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE) //No depth/stencil is used, so these can be dont care
                 .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(vk::ImageLayout::UNDEFINED) //This app doesn't read from the attachment, so this specifies the data is unknown
-                .final_layout(vk::ImageLayout::PRESENT_SRC_KHR) //This layout is what the image will be moved to once the render pass ends
-                .build()];
+                .initial_layout(vk::ImageLayout::UNDEFINED) //The previous contents are cleared anyway, so this specifies the data is unknown
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL) //This layout is what the image will be moved to once the render pass ends, ready to be sampled
+                .build();
+
+            //With MSAA everything is drawn into a multisampled image instead, which is resolved into the scene image
+            //at the end of the subpass. Only the resolved result is needed afterwards, so the samples aren't stored
+            let attachments = if msaa_samples == vk::SampleCountFlags::TYPE_1 {
+                vec![scene_attachment]
+            } else {
+                let msaa_attachment = vk::AttachmentDescription::builder()
+                    .format(post::HDR_FORMAT)
+                    .samples(msaa_samples)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .build();
+                let mut resolve_attachment = scene_attachment;
+                resolve_attachment.load_op = vk::AttachmentLoadOp::DONT_CARE;
+                vec![msaa_attachment, resolve_attachment]
+            };
 
             //Attachment references describe the layout that each attachment should be in when the subpass begins
             let attach_refs = [vk::AttachmentReference::builder()
                 .attachment(0)
                 .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                 .build()];
+            let resolve_refs = [vk::AttachmentReference::builder()
+                .attachment(1)
+                .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                .build()];
 
             //Each renderpass is a collection of subpasses. This app only uses one pass to render
-            let subpasses = [vk::SubpassDescription::builder()
+            let mut subpass = vk::SubpassDescription::builder()
                 .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-                .color_attachments(&attach_refs)
-                .build()];
+                .color_attachments(&attach_refs);
+            if attachments.len() > 1 {
+                subpass = subpass.resolve_attachments(&resolve_refs);
+            }
+            let subpasses = [subpass.build()];
 
             //Subpass dependencies specify memory dependencies that must happen during subpass transitions
             //Image layout transitions normally automatically occur before the subpass begins using the layouts in
             //AttachmentDescription's and AttachmentReference's
-            //For presentation, however, the swapchain image is usually not acquired yet, so this dependency
-            //moves the layout transition to COLOR_ATTACHMENT to before the actual COLOR_ATTACHMENT_OUTPUT actually occurs
-            let present_dependency = vk::SubpassDependency::builder()
+            //The image was sampled by last frame's post-processing, so that has to finish before it is drawn over
+            let start_dependency = vk::SubpassDependency::builder()
                 .src_subpass(vk::SUBPASS_EXTERNAL)
                 .dst_subpass(0)
-                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT) 
-                .src_access_mask(vk::AccessFlags::empty()) //Nothing needs to be waited on for the image to transition
+                .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .src_access_mask(vk::AccessFlags::empty()) //Reads don't need to be made visible, only waited on
                 .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT) //Writes occur in the COLOR_ATTACHMENT_OUTPUT stage
                 .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE) //Must transition image before writing to it
                 .build();
 
-            let dependencies = [present_dependency];
+            //Post-processing samples the finished scene in its fragment shaders
+            let end_dependency = vk::SubpassDependency::builder()
+                .src_subpass(0)
+                .dst_subpass(vk::SUBPASS_EXTERNAL)
+                .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build();
+
+            let dependencies = [start_dependency, end_dependency];
 
             //Build the render pass
             let create_info = vk::RenderPassCreateInfo::builder()
-                .attachments(&attachment)
+                .attachments(&attachments)
                 .subpasses(&subpasses)
                 .dependencies(&dependencies)
                 .build();
@@ -338,11 +961,10 @@ impl RenderContext {
         //Get handles to the actual swapchain images
         let swapchain_images = unsafe { swapchain_ext.get_swapchain_images(swapchain).unwrap() };
 
-        //Create image views and framebuffers
+        //Create image views, the framebuffers using them are owned by the post-processing
         let mut swapchain_image_views = Vec::new();
-        let mut framebuffers = Vec::new();
 
-        for (i, image) in swapchain_images.iter().enumerate() {
+        for image in swapchain_images.iter() {
             //Image views describe access on a subset of an image resource (i.e. a few mipmap layers)
             //As the swapchain images should not use mipmapping and aren't array images, the image view should cover the entire image
             let create_info = vk::ImageViewCreateInfo::builder()
@@ -359,166 +981,72 @@ impl RenderContext {
                                     .build());
             let iv = unsafe { device.create_image_view(&create_info, None).unwrap() };
             swapchain_image_views.push(iv);
+        }
 
-            //Framebuffers specify a particular image view to use as an attachment. These will be used with the render pass created above
-            let attachments = [swapchain_image_views[i]];
-
-            let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(render_pass)
-                .attachments(&attachments)
-                .width(window_size_x)
-                .height(window_size_y)
-                .layers(1)
-                .build();
+        //Textures are bound as a separate image and sampler, as one descriptor set per texture
+        let texture_set_layout = {
+            let bindings = [vk::DescriptorSetLayoutBinding::builder()
+                                .binding(0)
+                                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                                .descriptor_count(1)
+                                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                                .build(),
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(1)
+                                .descriptor_type(vk::DescriptorType::SAMPLER)
+                                .descriptor_count(1)
+                                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                                .build()];
+            let create_info = vk::DescriptorSetLayoutCreateInfo::builder()
+                .bindings(&bindings);
+            unsafe { device.create_descriptor_set_layout(&create_info, None).unwrap() }
+        };
 
-            let fb = unsafe { device.create_framebuffer(&create_info, None).unwrap() };
+        let descriptor_pool = {
+            let pool_sizes = [vk::DescriptorPoolSize::builder()
+                                .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                                .descriptor_count(MAX_TEXTURES + INTERNAL_DESCRIPTOR_SETS)
+                                .build(),
+                              vk::DescriptorPoolSize::builder()
+                                .ty(vk::DescriptorType::SAMPLER)
+                                .descriptor_count(MAX_TEXTURES + INTERNAL_DESCRIPTOR_SETS)
+                                .build()];
+            let create_info = vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(MAX_TEXTURES + INTERNAL_DESCRIPTOR_SETS)
+                .pool_sizes(&pool_sizes);
+            unsafe { device.create_descriptor_pool(&create_info, None).unwrap() }
+        };
 
-            framebuffers.push(fb);
-        }
+        //Nearest filtering keeps the pixel art look of the font and sprites
+        let sampler = {
+            let create_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::NEAREST)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_anisotropy(1.0)
+                .min_lod(0.0)
+                .max_lod(0.0);
+            unsafe { device.create_sampler(&create_info, None).unwrap() }
+        };
 
         //A pipeline layout is a collection of all of the descriptor set layouts and push constants that will be used in a single pipeline
         let pipeline_layout = {
             let push_constant_range = [vk::PushConstantRange::builder()
-                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
                 .offset(0)
                 .size(PUSH_CONSTANT_SIZE)
                 .build()];
+            let set_layouts = [texture_set_layout];
             let create_info = vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&set_layouts)
                 .push_constant_ranges(&push_constant_range)
                 .build();
             unsafe { device.create_pipeline_layout(&create_info, None).unwrap() }
         };
 
-        //Create a graphics pipeline around the vertex and fragment shaders
-        let graphics_pipeline = {
-            let f_spv = include_bytes!("../frag.spv");
-            let v_spv = include_bytes!("../vert.spv");
-
-            let mut f_code = vec![0; f_spv.len() / 4];
-            let mut v_code = vec![0; v_spv.len() / 4];
-
-            NativeEndian::read_u32_into(v_spv, v_code.as_mut_slice());
-            NativeEndian::read_u32_into(f_spv, f_code.as_mut_slice());
-
-            let create_info = vk::ShaderModuleCreateInfo::builder()
-                .code(f_code.as_slice())
-                .build();
-            let f_mod = unsafe { device.create_shader_module(&create_info, None).unwrap() };
-
-            let create_info = vk::ShaderModuleCreateInfo::builder()
-                .code(v_code.as_slice())
-                .build();
-            let v_mod = unsafe { device.create_shader_module(&create_info, None).unwrap() };
-
-            let entrypoint = CString::new("main").unwrap();
-            let v_stage = vk::PipelineShaderStageCreateInfo::builder()
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .module(v_mod)
-                .name(&entrypoint)
-                .build();
-            let f_stage = vk::PipelineShaderStageCreateInfo::builder()
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(f_mod)
-                .name(&entrypoint)
-                .build();
-
-            let vertex_binding = vk::VertexInputBindingDescription::builder()
-                .binding(0)
-                .stride(std::mem::size_of::<Vertex>() as u32)
-                .input_rate(vk::VertexInputRate::VERTEX)
-                .build();
-
-            let vertex_attribute = vk::VertexInputAttributeDescription::builder()
-                .location(0)
-                .binding(0)
-                .format(vk::Format::R32G32_SFLOAT)
-                .offset(0)
-                .build();
-
-            let vertex_binding = [vertex_binding];
-            let vertex_attribute = [vertex_attribute];
-
-            let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
-                .vertex_attribute_descriptions(&vertex_attribute)
-                .vertex_binding_descriptions(&vertex_binding)
-                .build();
-
-            //Verticies will be drawn as points
-            let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
-                .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-                .primitive_restart_enable(false)
-                .build();
-
-            //Standard fill for rasterization
-            let raster_state = vk::PipelineRasterizationStateCreateInfo::builder()
-                .depth_clamp_enable(false)
-                .rasterizer_discard_enable(false)
-                .polygon_mode(vk::PolygonMode::FILL)
-                .cull_mode(vk::CullModeFlags::NONE)
-                .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-                .depth_bias_enable(false)
-                .line_width(1.0)
-                .build();
-
-            //Viewport and scissor cover the entire screen
-            let viewport = [vk::Viewport::builder()
-                .x(0.0)
-                .y(0.0)
-                .width(window_size_x as f32)
-                .height(window_size_y as f32)
-                .min_depth(0.0)
-                .max_depth(1.0)
-                .build()];
-            let scissor = [vk::Rect2D::builder()
-                .offset(vk::Offset2D::builder().x(0).y(0).build())
-                .extent(vk::Extent2D::builder().width(window_size_x).height(window_size_y).build())
-                .build()];
-
-            let view_state = vk::PipelineViewportStateCreateInfo::builder()
-                .viewports(&viewport)
-                .scissors(&scissor)
-                .build();
-
-            //No multisampling
-            let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-                .rasterization_samples(vk::SampleCountFlags::TYPE_1)
-                .sample_shading_enable(false)
-                .alpha_to_coverage_enable(false)
-                .alpha_to_one_enable(false)
-                .build();
-
-            //No blending
-            let blend_attachment = [vk::PipelineColorBlendAttachmentState::builder()
-                .blend_enable(false)
-                .color_write_mask(vk::ColorComponentFlags::all())
-                .build()];
-
-            let blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-                .logic_op_enable(false)
-                .attachments(&blend_attachment)
-                .build();
-
-            let stages = [v_stage, f_stage];
-
-            let create_info = [vk::GraphicsPipelineCreateInfo::builder()
-                .stages(&stages)
-                .vertex_input_state(&vertex_input)
-                .input_assembly_state(&input_assembly)
-                .viewport_state(&view_state)
-                .rasterization_state(&raster_state)
-                .multisample_state(&multisample_state)
-                .color_blend_state(&blend_state)
-                .render_pass(render_pass)
-                .subpass(0)
-                .layout(pipeline_layout)
-                .build()];
-            let pipelines = unsafe { device.create_graphics_pipelines(vk::PipelineCache::null(), &create_info, None).unwrap() };
-            unsafe {
-                device.destroy_shader_module(v_mod, None);
-                device.destroy_shader_module(f_mod, None);
-            }
-            pipelines[0]
-        };
 
         let graphics_command_buffer = {
             let alloc_info = vk::CommandBufferAllocateInfo::builder()
@@ -549,28 +1077,85 @@ impl RenderContext {
             let create_info = vk::SemaphoreCreateInfo::builder().build();
             unsafe { (device.create_semaphore(&create_info, None).unwrap(), device.create_semaphore(&create_info, None).unwrap()) }
         };
+        //Starts signaled, there's no frame to wait for before the first one
+        let frame_fence = {
+            let create_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+            unsafe { device.create_fence(&create_info, None).unwrap() }
+        };
 
         let render_area = vk::Rect2D::builder()
             .offset(vk::Offset2D::builder().x(0).y(0).build())
             .extent(vk::Extent2D::builder().width(window_size_x).height(window_size_y).build())
             .build();
 
-        //Create buffer to handle staging uploads
-        let upload_buffer = {
-            let (buffer, allocation, _) = {
-            let buf_create = vk::BufferCreateInfo::builder()
-                .size(UPLOAD_BUFFER_SIZE)
-                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+        let mut uploads = UploadQueue::new(&device, &allocator, graphics_queue, graphics_queue_family_index);
+
+        let font_atlas = {
+            let pixels = text::build_atlas();
+            let (image, allocation, view) = create_sampled_image(&device, &allocator, &mut uploads, text::ATLAS_WIDTH, text::ATLAS_HEIGHT, vk::Format::R8_UNORM, &pixels);
+            let descriptor_set = create_texture_descriptor_set(&device, descriptor_pool, texture_set_layout, view, sampler);
+            Texture {
+                image,
+                allocation,
+                view,
+                descriptor_set
+            }
+        };
+
+        let text_pipeline_layout = {
+            let set_layouts = [texture_set_layout];
+            let create_info = vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&set_layouts)
                 .build();
+            unsafe { device.create_pipeline_layout(&create_info, None).unwrap() }
+        };
 
-            let alloc_create = vk_mem::AllocationCreateInfo {
-                usage: vk_mem::MemoryUsage::CpuOnly,
-                required_flags: vk::MemoryPropertyFlags::empty(),
-                preferred_flags: vk::MemoryPropertyFlags::empty(),
-                ..Default::default()
+
+        let shader_code = ShaderCode::embedded();
+        let pipelines = create_pipelines(&device, render_pass, pipeline_layout, text_pipeline_layout, render_area, msaa_samples, &shader_code);
+        let msaa_image = if msaa_samples == vk::SampleCountFlags::TYPE_1 {
+            None
+        } else {
+            Some(create_msaa_image(&device, &allocator, window_size_x, window_size_y, msaa_samples))
+        };
+        let post = PostProcess::new(&device, &allocator, render_pass, msaa_image.as_ref().map(|(_, _, view)| *view), surface_formats[0].format, &swapchain_image_views, render_area, texture_set_layout, descriptor_pool, &shader_code, post_settings);
+
+
+        let instance_buffer = {
+            let (buffer, allocation, _) = {
+                let buf_create = vk::BufferCreateInfo::builder()
+                    .size((MAX_INSTANCES * std::mem::size_of::<InstanceData>()) as u64)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .build();
+
+                let alloc_create = vk_mem::AllocationCreateInfo {
+                    usage: vk_mem::MemoryUsage::CpuToGpu,
+                    ..Default::default()
+                };
+
+                allocator.create_buffer(&buf_create, &alloc_create).unwrap()
             };
 
-            allocator.create_buffer(&buf_create, &alloc_create).unwrap()
+            VulkanBuffer {
+                buffer,
+                allocation
+            }
+        };
+
+        //Text is rebuilt every frame, so it lives in host visible memory
+        let text_vertex_buffer = {
+            let (buffer, allocation, _) = {
+                let buf_create = vk::BufferCreateInfo::builder()
+                    .size((text::MAX_QUADS * text::VERTICES_PER_QUAD * std::mem::size_of::<TextVertex>()) as u64)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .build();
+
+                let alloc_create = vk_mem::AllocationCreateInfo {
+                    usage: vk_mem::MemoryUsage::CpuToGpu,
+                    ..Default::default()
+                };
+
+                allocator.create_buffer(&buf_create, &alloc_create).unwrap()
             };
 
             VulkanBuffer {
@@ -579,47 +1164,648 @@ impl RenderContext {
             }
         };
 
-        RenderContext {
+        let debug_vertex_buffer = {
+            let (buffer, allocation, _) = {
+                let buf_create = vk::BufferCreateInfo::builder()
+                    .size((MAX_DEBUG_VERTICES * std::mem::size_of::<DebugVertex>()) as u64)
+                    .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                    .build();
+
+                let alloc_create = vk_mem::AllocationCreateInfo {
+                    usage: vk_mem::MemoryUsage::CpuToGpu,
+                    ..Default::default()
+                };
+
+                allocator.create_buffer(&buf_create, &alloc_create).unwrap()
+            };
+
+            VulkanBuffer {
+                buffer,
+                allocation
+            }
+        };
+
+        let (text_command_buffer, background_command_buffer, batch_command_buffer, debug_command_buffer) = {
+            let alloc_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::SECONDARY)
+                .command_buffer_count(4);
+
+            let buffers = unsafe { device.allocate_command_buffers(&alloc_info).unwrap() };
+            (buffers[0], buffers[1], buffers[2], buffers[3])
+        };
+
+        let capture_buffer = if can_capture {
+            let (buffer, allocation, _) = {
+                let buf_create = vk::BufferCreateInfo::builder()
+                    .size(window_size_x as u64 * window_size_y as u64 * 4)
+                    .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                    .build();
+
+                let alloc_create = vk_mem::AllocationCreateInfo {
+                    usage: vk_mem::MemoryUsage::GpuToCpu,
+                    ..Default::default()
+                };
+
+                allocator.create_buffer(&buf_create, &alloc_create).unwrap()
+            };
+
+            Some(VulkanBuffer {
+                buffer,
+                allocation
+            })
+        } else {
+            None
+        };
+
+        let timestamp_bits = queue_props[graphics_queue_family_index as usize].timestamp_valid_bits;
+        let timestamp_pool = if timestamp_bits > 0 {
+            let create_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count(TIMESTAMP_COUNT);
+            Some(unsafe { device.create_query_pool(&create_info, None).unwrap() })
+        } else {
+            println!("GPU timestamps aren't supported, GPU times won't be profiled");
+            None
+        };
+        let timestamp_mask = if timestamp_bits >= 64 { u64::MAX } else { (1 << timestamp_bits) - 1 };
+
+        let mut context = RenderContext {
             instance,
+            debug_report_loader,
+            debug_call_back,
             phys_device: physical_device,
             device,
+            surface_ext,
             surface,
             mem_allocator: allocator,
             graphics_queue,
             swapchain_ext,
             swapchain,
+            swapchain_images,
+            swapchain_image_views,
+            swapchain_format,
             sc_image_ready_sem,
             render_finished_sem,
+            frame_fence,
+            command_pool,
             graphics_command_buffer,
             sub_command_buffers,
             sub_command_pools,
-            framebuffers,
             render_pass,
-            graphics_pipeline,
+            #[cfg(feature = "shader-hot-reload")]
+            msaa_samples,
+            msaa_image,
+            post,
+            pipelines,
             render_area,
+            view_projection: Mat4::identity(),
             pipeline_layout,
             thread_pool,
-            upload_buffer
+            uploads,
+            texture_set_layout,
+            descriptor_pool,
+            sampler,
+            font_atlas,
+            text_pipeline_layout,
+            text_vertex_buffer,
+            text_vertices: Vec::new(),
+            text_command_buffer,
+            textures: Vec::new(),
+            background: None,
+            background_command_buffer,
+            meshes: Vec::new(),
+            mesh_lookup: std::collections::HashMap::new(),
+            instance_buffer,
+            instance_batches: Vec::new(),
+            batch_command_buffer,
+            debug_vertex_buffer,
+            debug_command_buffer,
+            capture_buffer,
+            capture_requested: false,
+            capture_in_flight: false,
+            captured: None,
+            timestamp_pool,
+            timestamp_period: limits.timestamp_period,
+            timestamp_mask,
+            timestamp_submitted: None
+        };
+
+        let white = context.create_texture(1, 1, &[0xff, 0xff, 0xff, 0xff]);
+        assert!(white == WHITE_TEXTURE);
+
+        context
+    }
+
+    //Uploads RGBA8 pixels into a new texture usable by Sprite components
+    pub fn create_texture(&mut self, width: u32, height: u32, pixels: &[u8]) -> TextureHandle {
+        assert!(self.textures.len() < MAX_TEXTURES as usize, "Too many textures created!");
+        let (image, allocation, view) = create_sampled_image(&self.device, &self.mem_allocator, &mut self.uploads, width, height, vk::Format::R8G8B8A8_UNORM, pixels);
+        let descriptor_set = create_texture_descriptor_set(&self.device, self.descriptor_pool, self.texture_set_layout, view, self.sampler);
+        self.textures.push(Texture {
+            image,
+            allocation,
+            view,
+            descriptor_set
+        });
+        TextureHandle(self.textures.len() - 1)
+    }
+
+    pub fn load_texture(&mut self, path: &str) -> Result<TextureHandle, String> {
+        let (width, height, pixels) = sprite::load_png(path)?;
+        Ok(self.create_texture(width, height, &pixels))
+    }
+
+    //Creates a mesh that can be shared between any number of entities
+    //Creating a mesh identical to an existing one returns the existing handle instead of uploading another copy
+    pub fn create_mesh(&mut self, vertices: &[Vertex], indices: &[u32]) -> MeshHandle {
+        let mut key = Vec::with_capacity(vertices.len() * 4 + indices.len());
+        key.extend(vertices.iter().flat_map(|v| vec![v.position.x.to_bits(), v.position.y.to_bits(), v.uv.x.to_bits(), v.uv.y.to_bits()]));
+        key.push(u32::MAX);
+        key.extend_from_slice(indices);
+        if let Some(handle) = self.mesh_lookup.get(&key) {
+            return *handle;
+        }
+
+        let mesh = Mesh::new(self, vertices, indices);
+        self.meshes.push(mesh);
+        let handle = MeshHandle(self.meshes.len() - 1);
+        self.mesh_lookup.insert(key, handle);
+        handle
+    }
+
+    pub fn load_mesh(&mut self, data: &MeshData) -> MeshHandle {
+        self.create_mesh(&data.vertices, &data.indices)
+    }
+
+    //Stretches a texture over the whole arena, behind everything else
+    pub fn set_background(&mut self, texture: TextureHandle) {
+        let quad = self.create_mesh(&ARENA_VERTICES, &ARENA_INDICES);
+        self.background = Some((texture, RenderComponent::new(quad)));
+    }
+
+    //Rebuilds every pipeline from new shaders, e.g. after they were edited while the game is running
+    #[cfg(feature = "shader-hot-reload")]
+    pub fn reload_shaders(&mut self, code: &ShaderCode) {
+        let pipelines = create_pipelines(&self.device, self.render_pass, self.pipeline_layout, self.text_pipeline_layout, self.render_area, self.msaa_samples, code);
+        unsafe {
+            self.device.device_wait_idle().unwrap();
+            self.device.destroy_pipeline(self.pipelines.graphics, None);
+            self.device.destroy_pipeline(self.pipelines.text, None);
+            self.device.destroy_pipeline(self.pipelines.instanced, None);
+            self.device.destroy_pipeline(self.pipelines.debug, None);
+        }
+        self.pipelines = pipelines;
+        self.post.reload_shaders(&self.device, code);
+    }
+
+    //Copies the next frame drawn out of the swapchain, it is returned by take_capture once the frame has finished
+    //Does nothing if the swapchain can't be captured
+    pub fn request_capture(&mut self) {
+        self.capture_requested = self.capture_buffer.is_some();
+    }
+
+    pub fn take_capture(&mut self) -> Option<CapturedImage> {
+        self.captured.take()
+    }
+
+    //Copies the finished frame into the capture buffer, it's read back at the start of the next frame
+    fn record_capture(&self, command_buffer: vk::CommandBuffer, image_idx: usize) {
+        let buffer = self.capture_buffer.as_ref().unwrap().buffer;
+        let image = self.swapchain_images[image_idx];
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        //The present pass leaves the image ready to present, so it's moved to TRANSFER_SRC for the copy and back again after
+        let to_transfer = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .build()];
+        let to_present = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .build()];
+        let to_host = [vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()];
+
+        let copy_region = [vk::BufferImageCopy::builder()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(vk::ImageSubresourceLayers::builder()
+                                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                .mip_level(0)
+                                .base_array_layer(0)
+                                .layer_count(1)
+                                .build())
+            .image_offset(vk::Offset3D::builder().x(0).y(0).z(0).build())
+            .image_extent(vk::Extent3D::builder().width(self.render_area.extent.width).height(self.render_area.extent.height).depth(1).build())
+            .build()];
+
+        unsafe {
+            self.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &to_transfer);
+            self.device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buffer, &copy_region);
+            self.device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::HOST | vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(), &[], &to_host, &to_present);
+        }
+    }
+
+    //Reads the last captured frame out of the capture buffer as RGBA, the GPU must have finished with it
+    fn read_capture(&self) -> CapturedImage {
+        let width = self.render_area.extent.width;
+        let height = self.render_area.extent.height;
+        let size = (width * height * 4) as usize;
+        let allocation = &self.capture_buffer.as_ref().unwrap().allocation;
+
+        let data_ptr = self.mem_allocator.map_memory(allocation).unwrap();
+        let mut pixels = unsafe { core::slice::from_raw_parts(data_ptr, size) }.to_vec();
+        self.mem_allocator.unmap_memory(allocation).unwrap();
+
+        let bgra = self.swapchain_format == vk::Format::B8G8R8A8_UNORM || self.swapchain_format == vk::Format::B8G8R8A8_SRGB;
+        for pixel in pixels.chunks_mut(4) {
+            if bgra {
+                pixel.swap(0, 2);
+            }
+            //The swapchain is presented as opaque, whatever ended up in the alpha channel
+            pixel[3] = 0xff;
+        }
+
+        CapturedImage {
+            width,
+            height,
+            pixels
+        }
+    }
+
+    //Records a single textured draw of a RenderComponent at the given position
+    fn record_draw(&self, command_buffer: vk::CommandBuffer, renderable: &RenderComponent, position: &Vec2, texture: TextureHandle) {
+        let x = Vec4 {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+            w: 0.0
+        };
+        let y = Vec4 {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+            w: 0.0
+        };
+        let z = Vec4 {
+            x: 0.0, 
+            y: 0.0,
+            z: 1.0,
+            w: 0.0
+        };
+        let w = Vec4 {
+            x: position.x,
+            y: position.y,
+            z: 0.0,
+            w: 1.0
+        };
+        let m = Mat4 {
+            x,
+            y,
+            z,
+            w
+        };
+        let constants = PushConstants {
+            transform: self.view_projection * m,
+            color: renderable.color
+        };
+        
+        unsafe {
+            let ptr = &constants as *const PushConstants;
+            let slice = std::slice::from_raw_parts(ptr as *const u8, PUSH_CONSTANT_SIZE as usize);
+            let descriptor_sets = [self.textures[texture.0].descriptor_set];
+            self.device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipelines.graphics);
+            self.device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline_layout, 0, &descriptor_sets, &[]);
+            self.device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0, &slice);
+            let offsets: [vk::DeviceSize; 1] = [0];
+            let mesh = &self.meshes[renderable.mesh.0];
+            let buffers = [mesh.vertex_buffer.buffer];
+            self.device.cmd_bind_vertex_buffers(command_buffer, 0, &buffers, &offsets);
+            self.device.cmd_bind_index_buffer(command_buffer, mesh.vertex_buffer.buffer, mesh.index_offset, vk::IndexType::UINT32);
+            self.device.cmd_draw_indexed(command_buffer, mesh.num_indices, 1, 0, 0, 0);
+        }
+    }
+
+    //The background gets its own secondary command buffer so it is always drawn before the scene
+    fn record_background(&self, framebuffer: vk::Framebuffer) {
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(self.render_pass)
+            .subpass(0)
+            .framebuffer(framebuffer);
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .inheritance_info(&inheritance_info)
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE);
+
+        unsafe { self.device.begin_command_buffer(self.background_command_buffer, &begin_info).unwrap() };
+        if let Some((texture, quad)) = &self.background {
+            self.record_draw(self.background_command_buffer, quad, &Vec2::new(0.0, 0.0), *texture);
+        }
+        unsafe { self.device.end_command_buffer(self.background_command_buffer).unwrap() };
+    }
+
+    //Groups Instanced entities and particles by mesh and records one instanced draw per mesh
+    fn record_batches(&mut self, framebuffer: vk::Framebuffer, instanced_storage: &ReadStorage<Instanced>, transform_storage: &ReadStorage<TransformComponent>, particles: &Particles) {
+        use specs::Join;
+
+        self.instance_batches.resize_with(self.meshes.len(), Vec::new);
+        for batch in self.instance_batches.iter_mut() {
+            batch.clear();
+        }
+
+        for (instanced, transform) in (instanced_storage, transform_storage).join() {
+            self.instance_batches[instanced.mesh.0].push(InstanceData {
+                position: transform.position,
+                scale: instanced.scale,
+                color: instanced.color
+            });
+        }
+
+        //Particles aren't entities, but are drawn the same way
+        for (position, size, color) in particles.iter() {
+            self.instance_batches[particles.mesh.0].push(InstanceData {
+                position,
+                scale: Vec2::new(size, size),
+                color
+            });
+        }
+
+        //Batches are packed one after another into the instance buffer, anything past MAX_INSTANCES is dropped
+        let mut batch_ranges = Vec::new();
+        let data_ptr = self.mem_allocator.map_memory(&self.instance_buffer.allocation).unwrap() as *mut InstanceData;
+        let dest = unsafe { core::slice::from_raw_parts_mut(data_ptr, MAX_INSTANCES) };
+        let mut first_instance = 0;
+        for (mesh_idx, batch) in self.instance_batches.iter().enumerate() {
+            let count = batch.len().min(MAX_INSTANCES - first_instance);
+            if count == 0 {
+                continue;
+            }
+            dest[first_instance..first_instance + count].copy_from_slice(&batch[..count]);
+            batch_ranges.push((mesh_idx, first_instance, count));
+            first_instance += count;
+        }
+        self.mem_allocator.unmap_memory(&self.instance_buffer.allocation).unwrap();
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(self.render_pass)
+            .subpass(0)
+            .framebuffer(framebuffer);
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .inheritance_info(&inheritance_info)
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE);
+
+        let constants = PushConstants {
+            transform: self.view_projection,
+            color: Color::WHITE
+        };
+
+        unsafe {
+            self.device.begin_command_buffer(self.batch_command_buffer, &begin_info).unwrap();
+            if !batch_ranges.is_empty() {
+                let ptr = &constants as *const PushConstants;
+                let slice = std::slice::from_raw_parts(ptr as *const u8, PUSH_CONSTANT_SIZE as usize);
+                self.device.cmd_bind_pipeline(self.batch_command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipelines.instanced);
+                self.device.cmd_push_constants(self.batch_command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0, slice);
+            }
+            for (mesh_idx, first_instance, count) in batch_ranges.iter() {
+                let mesh = &self.meshes[*mesh_idx];
+                let buffers = [mesh.vertex_buffer.buffer, self.instance_buffer.buffer];
+                let offsets: [vk::DeviceSize; 2] = [0, (first_instance * std::mem::size_of::<InstanceData>()) as vk::DeviceSize];
+                self.device.cmd_bind_vertex_buffers(self.batch_command_buffer, 0, &buffers, &offsets);
+                self.device.cmd_bind_index_buffer(self.batch_command_buffer, mesh.vertex_buffer.buffer, mesh.index_offset, vk::IndexType::UINT32);
+                self.device.cmd_draw_indexed(self.batch_command_buffer, mesh.num_indices, *count as u32, 0, 0, 0);
+            }
+            self.device.end_command_buffer(self.batch_command_buffer).unwrap();
+        }
+    }
+
+    //Records the debug lines into their own secondary command buffer, drawn over the scene but under the HUD
+    fn record_debug(&self, framebuffer: vk::Framebuffer, debug_draw: &DebugDraw) {
+        let vertices = debug_draw.vertices();
+        if !vertices.is_empty() {
+            let data_ptr = self.mem_allocator.map_memory(&self.debug_vertex_buffer.allocation).unwrap() as *mut DebugVertex;
+            let dest = unsafe { core::slice::from_raw_parts_mut(data_ptr, vertices.len()) };
+            dest.copy_from_slice(vertices);
+            self.mem_allocator.unmap_memory(&self.debug_vertex_buffer.allocation).unwrap();
+        }
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(self.render_pass)
+            .subpass(0)
+            .framebuffer(framebuffer);
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .inheritance_info(&inheritance_info)
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE);
+
+        let constants = PushConstants {
+            transform: self.view_projection,
+            color: Color::WHITE
+        };
+
+        unsafe {
+            self.device.begin_command_buffer(self.debug_command_buffer, &begin_info).unwrap();
+            if !vertices.is_empty() {
+                let ptr = &constants as *const PushConstants;
+                let slice = std::slice::from_raw_parts(ptr as *const u8, PUSH_CONSTANT_SIZE as usize);
+                let buffers = [self.debug_vertex_buffer.buffer];
+                let offsets: [vk::DeviceSize; 1] = [0];
+                self.device.cmd_bind_pipeline(self.debug_command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipelines.debug);
+                self.device.cmd_push_constants(self.debug_command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0, slice);
+                self.device.cmd_bind_vertex_buffers(self.debug_command_buffer, 0, &buffers, &offsets);
+                self.device.cmd_draw(self.debug_command_buffer, vertices.len() as u32, 1, 0, 0);
+            }
+            self.device.end_command_buffer(self.debug_command_buffer).unwrap();
+        }
+    }
+
+    //Records the queued text into its own secondary command buffer, drawn after the scene
+    fn record_text(&mut self, framebuffer: vk::Framebuffer, text_queue: &TextQueue) {
+        let screen_width = self.render_area.extent.width as f32;
+        let screen_height = self.render_area.extent.height as f32;
+        self.text_vertices.clear();
+        text_queue.build_vertices(screen_width, screen_height, &mut self.text_vertices);
+
+        if !self.text_vertices.is_empty() {
+            let data_ptr = self.mem_allocator.map_memory(&self.text_vertex_buffer.allocation).unwrap() as *mut TextVertex;
+            let dest = unsafe { core::slice::from_raw_parts_mut(data_ptr, self.text_vertices.len()) };
+            dest.copy_from_slice(&self.text_vertices);
+            self.mem_allocator.unmap_memory(&self.text_vertex_buffer.allocation).unwrap();
+        }
+
+        let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
+            .render_pass(self.render_pass)
+            .subpass(0)
+            .framebuffer(framebuffer);
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .inheritance_info(&inheritance_info)
+            .flags(vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE);
+
+        unsafe {
+            self.device.begin_command_buffer(self.text_command_buffer, &begin_info).unwrap();
+            if !self.text_vertices.is_empty() {
+                let descriptor_sets = [self.font_atlas.descriptor_set];
+                let buffers = [self.text_vertex_buffer.buffer];
+                let offsets: [vk::DeviceSize; 1] = [0];
+                self.device.cmd_bind_pipeline(self.text_command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipelines.text);
+                self.device.cmd_bind_descriptor_sets(self.text_command_buffer, vk::PipelineBindPoint::GRAPHICS, self.text_pipeline_layout, 0, &descriptor_sets, &[]);
+                self.device.cmd_bind_vertex_buffers(self.text_command_buffer, 0, &buffers, &offsets);
+                self.device.cmd_draw(self.text_command_buffer, self.text_vertices.len() as u32, 1, 0, 0);
+            }
+            self.device.end_command_buffer(self.text_command_buffer).unwrap();
+        }
+    }
+}
+
+impl Drop for RenderContext {
+    //Everything is freed in reverse order of creation, once the last frame has finished
+    fn drop(&mut self) {
+        unsafe {
+            self.device.wait_for_fences(&[self.frame_fence], true, u64::MAX).unwrap();
+            //Presentation may still be waiting on the render finished semaphore
+            self.device.device_wait_idle().unwrap();
+        }
+
+        for mesh in self.meshes.iter() {
+            mesh.vertex_buffer.destroy(&self.mem_allocator);
+        }
+        for texture in self.textures.iter().rev() {
+            texture.destroy(&self.device, &self.mem_allocator);
+        }
+        if let Some(pool) = self.timestamp_pool {
+            unsafe { self.device.destroy_query_pool(pool, None) };
+        }
+        if let Some(buffer) = self.capture_buffer.as_ref() {
+            buffer.destroy(&self.mem_allocator);
+        }
+        self.debug_vertex_buffer.destroy(&self.mem_allocator);
+        self.text_vertex_buffer.destroy(&self.mem_allocator);
+        self.instance_buffer.destroy(&self.mem_allocator);
+        self.post.destroy(&self.device, &self.mem_allocator);
+        if let Some((image, allocation, view)) = self.msaa_image.as_ref() {
+            unsafe { self.device.destroy_image_view(*view, None) };
+            self.mem_allocator.destroy_image(*image, allocation).unwrap();
+        }
+        unsafe {
+            self.device.destroy_pipeline(self.pipelines.debug, None);
+            self.device.destroy_pipeline(self.pipelines.instanced, None);
+            self.device.destroy_pipeline(self.pipelines.text, None);
+            self.device.destroy_pipeline(self.pipelines.graphics, None);
+            self.device.destroy_pipeline_layout(self.text_pipeline_layout, None);
+        }
+        self.font_atlas.destroy(&self.device, &self.mem_allocator);
+        self.uploads.destroy(&self.device, &self.mem_allocator);
+
+        unsafe {
+            self.device.destroy_fence(self.frame_fence, None);
+            self.device.destroy_semaphore(self.render_finished_sem, None);
+            self.device.destroy_semaphore(self.sc_image_ready_sem, None);
+            self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device.destroy_sampler(self.sampler, None);
+            //Frees every descriptor set allocated from it too
+            self.device.destroy_descriptor_pool(self.descriptor_pool, None);
+            self.device.destroy_descriptor_set_layout(self.texture_set_layout, None);
+            for view in self.swapchain_image_views.iter() {
+                self.device.destroy_image_view(*view, None);
+            }
+            self.device.destroy_render_pass(self.render_pass, None);
+            self.swapchain_ext.destroy_swapchain(self.swapchain, None);
+            //Command buffers are freed along with their pools
+            for pool in self.sub_command_pools.iter() {
+                self.device.destroy_command_pool(*pool, None);
+            }
+            self.device.destroy_command_pool(self.command_pool, None);
+        }
+
+        //The allocator would otherwise be dropped after the device it allocates from
+        self.mem_allocator.destroy();
+        unsafe {
+            self.device.destroy_device(None);
+            self.surface_ext.destroy_surface(self.surface, None);
+            self.debug_report_loader.destroy_debug_report_callback(self.debug_call_back, None);
+            self.instance.destroy_instance(None);
         }
     }
 }
 
 impl <'a> System<'a> for RenderContext {
-    type SystemData = (ReadStorage<'a, RenderComponent>, ReadStorage<'a, TransformComponent>);
+    type SystemData = (ReadStorage<'a, RenderComponent>, ReadStorage<'a, TransformComponent>, ReadStorage<'a, Sprite>, ReadStorage<'a, Instanced>, Write<'a, TextQueue>, ReadExpect<'a, Camera>, ReadExpect<'a, Particles>, Write<'a, DebugDraw>, Read<'a, Profiler>, Entities<'a>);
 
-    fn run (&mut self, (render_storage, transform_storage): Self::SystemData) {
+    fn run (&mut self, (render_storage, transform_storage, sprite_storage, instanced_storage, mut text_queue, camera, particles, mut debug_draw, profiler, entities): Self::SystemData) {
         use specs::ParJoin;
         use rayon::prelude::*;
 
-        unsafe { self.device.device_wait_idle().unwrap() };
+        //Only one frame is in flight, its command buffers, vertex buffers and semaphores are all reused by the next one
+        let scope = profiler.scope("render: wait for last frame");
+        unsafe { self.device.wait_for_fences(&[self.frame_fence], true, u64::MAX).unwrap() };
+        drop(scope);
+
+        if let (Some(pool), Some(submitted)) = (self.timestamp_pool, self.timestamp_submitted.take()) {
+            let mut timestamps = [0u64; TIMESTAMP_COUNT as usize];
+            unsafe { self.device.get_query_pool_results(pool, 0, TIMESTAMP_COUNT, &mut timestamps, vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT).unwrap() };
+            let duration = |start: u64, end: u64| {
+                let ticks = end.wrapping_sub(start) & self.timestamp_mask;
+                std::time::Duration::from_nanos((ticks as f64 * self.timestamp_period as f64) as u64)
+            };
+            //Lined up with when the frame was submitted, the GPU's clock can't be compared with the CPU's
+            let scene = duration(timestamps[0], timestamps[1]);
+            profiler.record_gpu("gpu scene", submitted, scene);
+            profiler.record_gpu("gpu post", submitted + scene, duration(timestamps[1], timestamps[2]));
+        }
+
+        if self.capture_in_flight {
+            self.captured = Some(self.read_capture());
+            self.capture_in_flight = false;
+        }
+
+        self.view_projection = camera.view_projection();
+
+        //Submit anything created since the last frame ahead of the draws that use it
+        let scope = profiler.scope("render: uploads");
+        self.uploads.collect(&self.device);
+        self.uploads.flush(&self.device);
+        drop(scope);
 
+        let scope = profiler.scope("render: acquire");
         let (fb_idx, _) = unsafe { self.swapchain_ext.acquire_next_image(self.swapchain, std::u64::MAX, self.sc_image_ready_sem, vk::Fence::null()).unwrap() };
+        drop(scope);
+
+        let scope = profiler.scope("render: record");
 
         for sub_cmd_bfr in self.sub_command_buffers.iter() {
             let inheritance_info = vk::CommandBufferInheritanceInfo::builder()
                 .render_pass(self.render_pass)
                 .subpass(0)
-                .framebuffer(self.framebuffers[fb_idx as usize]);
+                .framebuffer(self.post.scene_framebuffer());
 
             let begin_info = vk::CommandBufferBeginInfo::builder()
                 .inheritance_info(&inheritance_info)
@@ -628,7 +1814,8 @@ impl <'a> System<'a> for RenderContext {
             unsafe { self.device.begin_command_buffer(*sub_cmd_bfr, &begin_info).unwrap(); }
         }
 
-        (&render_storage, &transform_storage).par_join().for_each(|(renderable, transform)| {
+        //The renderer is run outside of the dispatcher, so recording has to be moved into the thread pool
+        self.thread_pool.install(|| (&render_storage, &transform_storage, &entities).par_join().for_each(|(renderable, transform, entity)| {
             let idx = match self.thread_pool.current_thread_index() {
                 None => {
                     panic!("Rendering operations occured outside thread pool!");
@@ -638,74 +1825,69 @@ impl <'a> System<'a> for RenderContext {
                 }
             };
 
-            let position = &transform.position;
-            let x = Vec4 {
-                x: 1.0,
-                y: 0.0,
-                z: 0.0,
-                w: 0.0
-            };
-            let y = Vec4 {
-                x: 0.0,
-                y: 1.0,
-                z: 0.0,
-                w: 0.0
-            };
-            let z = Vec4 {
-                x: 0.0, 
-                y: 0.0,
-                z: 1.0,
-                w: 0.0
+            let texture = match sprite_storage.get(entity) {
+                None => WHITE_TEXTURE,
+                Some(sprite) => sprite.texture
             };
-            let w = Vec4 {
-                x: position.x,
-                y: position.y,
-                z: 0.0,
-                w: 1.0
-            };
-            let m = Mat4 {
-                x,
-                y,
-                z,
-                w
-            };
-            
-            unsafe {
-                let ptr = &m as *const Mat4;
-                let slice = std::slice::from_raw_parts(ptr as *const u8, PUSH_CONSTANT_SIZE as usize);
-                self.device.cmd_bind_pipeline(self.sub_command_buffers[idx], vk::PipelineBindPoint::GRAPHICS, self.graphics_pipeline);
-                self.device.cmd_push_constants(self.sub_command_buffers[idx], self.pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, &slice);
-                let offsets: [vk::DeviceSize; 1] = [0];
-                let buffers = [renderable.vertex_buffer.buffer];
-                self.device.cmd_bind_vertex_buffers(self.sub_command_buffers[idx], 0, &buffers, &offsets);
-                self.device.cmd_bind_index_buffer(self.sub_command_buffers[idx], renderable.vertex_buffer.buffer, renderable.index_offset, vk::IndexType::UINT32);
-                self.device.cmd_draw_indexed(self.sub_command_buffers[idx], renderable.num_indices, 1, 0, 0, 0);
-            }
-        });
+            self.record_draw(self.sub_command_buffers[idx], renderable, &transform.position, texture);
+        }));
 
         for sub_cmd_bfr in self.sub_command_buffers.iter() {
             unsafe { self.device.end_command_buffer(*sub_cmd_bfr).unwrap(); }
         }
+
+        self.record_background(self.post.scene_framebuffer());
+        self.record_batches(self.post.scene_framebuffer(), &instanced_storage, &transform_storage, &particles);
+        self.record_debug(self.post.scene_framebuffer(), &debug_draw);
+        self.record_text(self.post.scene_framebuffer(), &text_queue);
+        text_queue.clear();
+        debug_draw.clear();
         let begin_info = vk::CommandBufferBeginInfo::builder()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
             .build();
         unsafe { self.device.begin_command_buffer(self.graphics_command_buffer, &begin_info).unwrap() };
+        if let Some(pool) = self.timestamp_pool {
+            unsafe {
+                self.device.cmd_reset_query_pool(self.graphics_command_buffer, pool, 0, TIMESTAMP_COUNT);
+                self.device.cmd_write_timestamp(self.graphics_command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, pool, 0);
+            }
+        }
         let clear_value = vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0]};
         let clear_value = [vk::ClearValue { color: clear_value}];
         let rp_begin_info = vk::RenderPassBeginInfo::builder()
             .render_pass(self.render_pass)
-            .framebuffer(self.framebuffers[fb_idx as usize])
+            .framebuffer(self.post.scene_framebuffer())
             .render_area(self.render_area)
             .clear_values(&clear_value)
             .build();
 
         unsafe {
             self.device.cmd_begin_render_pass(self.graphics_command_buffer, &rp_begin_info, vk::SubpassContents::SECONDARY_COMMAND_BUFFERS); 
+            self.device.cmd_execute_commands(self.graphics_command_buffer, &[self.background_command_buffer]);
             self.device.cmd_execute_commands(self.graphics_command_buffer, self.sub_command_buffers.as_slice());
+            self.device.cmd_execute_commands(self.graphics_command_buffer, &[self.batch_command_buffer]);
+            self.device.cmd_execute_commands(self.graphics_command_buffer, &[self.debug_command_buffer]);
+            self.device.cmd_execute_commands(self.graphics_command_buffer, &[self.text_command_buffer]);
             self.device.cmd_end_render_pass(self.graphics_command_buffer);
+            if let Some(pool) = self.timestamp_pool {
+                self.device.cmd_write_timestamp(self.graphics_command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, pool, 1);
+            }
+        }
+        self.post.record(&self.device, self.graphics_command_buffer, fb_idx as usize);
+        if let Some(pool) = self.timestamp_pool {
+            unsafe { self.device.cmd_write_timestamp(self.graphics_command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE, pool, 2) };
+        }
+        if self.capture_requested {
+            self.record_capture(self.graphics_command_buffer, fb_idx as usize);
+            self.capture_requested = false;
+            self.capture_in_flight = true;
+        }
+        unsafe {
             self.device.end_command_buffer(self.graphics_command_buffer).unwrap();
         }
+        drop(scope);
 
+        let scope = profiler.scope("render: submit");
         let wait_semaphores = [self.sc_image_ready_sem];
         let dst_stage_mask = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
         let cmd_buffers = [self.graphics_command_buffer];
@@ -717,7 +1899,14 @@ impl <'a> System<'a> for RenderContext {
             .command_buffers(&cmd_buffers)
             .signal_semaphores(&signal_semaphores)
             .build()];
-        unsafe { self.device.queue_submit(self.graphics_queue, &submit, vk::Fence::null()).unwrap() };
+        if self.timestamp_pool.is_some() {
+            self.timestamp_submitted = Some(std::time::Instant::now());
+        }
+        unsafe {
+            self.device.reset_fences(&[self.frame_fence]).unwrap();
+            self.device.queue_submit(self.graphics_queue, &submit, self.frame_fence).unwrap();
+        }
+        drop(scope);
 
         let wait_semaphores = [self.render_finished_sem];
         let swapchains = [self.swapchain];
@@ -728,6 +1917,7 @@ impl <'a> System<'a> for RenderContext {
             .swapchains(&swapchains)
             .image_indices(&image_indices)
             .build();
+        let _scope = profiler.scope("render: present");
         unsafe { self.swapchain_ext.queue_present(self.graphics_queue, &present_info).unwrap() };
     }
 }
\ No newline at end of file
@@ -0,0 +1,392 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use specs::{World, DispatcherBuilder};
+
+use crate::game::{self, PlayerInput, PLAYER_COUNT, TICK_RATE};
+
+//Replays from other games or versions aren't loaded
+const MAGIC: u32 = 0x4c50_5250;
+const VERSION: u16 = 2;
+//Every this many ticks the world's checksum is saved, so playback can tell where it stopped matching the recording
+const CHECKSUM_INTERVAL: u32 = 100;
+
+const INPUT_AI: u8 = 1;
+//Longest replay that's loaded, a day at 100 ticks a second, anything claiming more is corrupt
+const MAX_TICKS: u64 = 24 * 60 * 60 * 100;
+
+//Playback speeds picked from with the slower and faster keys
+const SPEEDS: [f32; 7] = [0.125, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 3;
+
+pub const EXTENSION: &str = "replay";
+
+//Everything needed to play a match again tick for tick, the simulation is deterministic so only the inputs are kept
+pub struct Replay {
+    pub seed: u64,
+    //Every player's input, one tick after another
+    inputs: Vec<PlayerInput>,
+    //The world after every CHECKSUM_INTERVAL ticks
+    checksums: Vec<u32>
+}
+
+impl Replay {
+    pub fn new(seed: u64) -> Replay {
+        Replay {
+            seed,
+            inputs: Vec::new(),
+            checksums: Vec::new()
+        }
+    }
+
+    pub fn ticks(&self) -> u32 {
+        (self.inputs.len() / PLAYER_COUNT) as u32
+    }
+
+    //Inputs every player had on tick, None past the end of the recording
+    pub fn inputs(&self, tick: u32) -> Option<&[PlayerInput]> {
+        let start = tick as usize * PLAYER_COUNT;
+        self.inputs.get(start..start + PLAYER_COUNT)
+    }
+
+    //Expected checksum of the world after ticks have run, when one was saved
+    pub fn checksum(&self, ticks: u32) -> Option<u32> {
+        if ticks == 0 || !ticks.is_multiple_of(CHECKSUM_INTERVAL) {
+            return None;
+        }
+        self.checksums.get((ticks / CHECKSUM_INTERVAL - 1) as usize).copied()
+    }
+
+    //Adds the inputs a tick ran with, called after the tick so checksum gets the world it left behind
+    //The checksum is only worked out on the ticks it's saved for
+    pub fn record<F: FnOnce() -> u32>(&mut self, inputs: &[PlayerInput], checksum: F) {
+        self.inputs.extend_from_slice(&inputs[..PLAYER_COUNT]);
+        if self.ticks().is_multiple_of(CHECKSUM_INTERVAL) {
+            self.checksums.push(checksum());
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.encode()).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Replay, String> {
+        let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Replay::decode(&data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    //Inputs are saved as runs of ticks where nobody's input changed, which is most of them
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(MAGIC).unwrap();
+        data.write_u16::<LittleEndian>(VERSION).unwrap();
+        data.write_u64::<LittleEndian>(self.seed).unwrap();
+        data.write_f32::<LittleEndian>(TICK_RATE).unwrap();
+        data.write_u8(PLAYER_COUNT as u8).unwrap();
+
+        let ticks: Vec<&[PlayerInput]> = self.inputs.chunks(PLAYER_COUNT).collect();
+        let runs: Vec<&[&[PlayerInput]]> = ticks.chunk_by(|a, b| a == b).collect();
+        data.write_u32::<LittleEndian>(runs.len() as u32).unwrap();
+        for run in runs.iter() {
+            data.write_u32::<LittleEndian>(run.len() as u32).unwrap();
+            for input in run[0].iter() {
+                data.write_i16::<LittleEndian>(input.axis_y).unwrap();
+                data.write_u8(if input.ai { INPUT_AI } else { 0 }).unwrap();
+            }
+        }
+
+        data.write_u32::<LittleEndian>(self.checksums.len() as u32).unwrap();
+        for checksum in self.checksums.iter() {
+            data.write_u32::<LittleEndian>(*checksum).unwrap();
+        }
+        data
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<Replay, String> {
+        let truncated = |_| "The replay is cut short".to_string();
+        if data.read_u32::<LittleEndian>().map_err(truncated)? != MAGIC {
+            return Err("Not a replay".to_string());
+        }
+        let version = data.read_u16::<LittleEndian>().map_err(truncated)?;
+        if version != VERSION {
+            return Err(format!("Replay version {} can't be played, only version {}", version, VERSION));
+        }
+        let seed = data.read_u64::<LittleEndian>().map_err(truncated)?;
+        //A different tick or player count would be a different simulation, so the inputs wouldn't play out the same
+        let tick_rate = data.read_f32::<LittleEndian>().map_err(truncated)?;
+        let player_count = data.read_u8().map_err(truncated)? as usize;
+        if tick_rate != TICK_RATE || player_count != PLAYER_COUNT {
+            return Err(format!("Recorded with {} players at {}s a tick, this game has {} at {}s", player_count, tick_rate, PLAYER_COUNT, TICK_RATE));
+        }
+
+        let mut inputs = Vec::new();
+        let mut ticks: u64 = 0;
+        let run_count = data.read_u32::<LittleEndian>().map_err(truncated)?;
+        for _ in 0..run_count {
+            let length = data.read_u32::<LittleEndian>().map_err(truncated)?;
+            ticks += length as u64;
+            if ticks > MAX_TICKS {
+                return Err(format!("The replay claims more than {} ticks", MAX_TICKS));
+            }
+            let mut tick = Vec::with_capacity(PLAYER_COUNT);
+            for _ in 0..PLAYER_COUNT {
+                tick.push(PlayerInput {
+                    axis_y: data.read_i16::<LittleEndian>().map_err(truncated)?,
+                    ai: data.read_u8().map_err(truncated)? & INPUT_AI != 0
+                });
+            }
+            for _ in 0..length {
+                inputs.extend_from_slice(&tick);
+            }
+        }
+
+        let checksum_count = data.read_u32::<LittleEndian>().map_err(truncated)?;
+        //Checked before allocating, so a corrupt count can't ask for gigabytes
+        if checksum_count as usize * 4 > data.len() {
+            return Err("The replay is cut short".to_string());
+        }
+        let mut checksums = Vec::with_capacity(checksum_count as usize);
+        for _ in 0..checksum_count {
+            checksums.push(data.read_u32::<LittleEndian>().map_err(truncated)?);
+        }
+        Ok(Replay {
+            seed,
+            inputs,
+            checksums
+        })
+    }
+
+    //Runs the whole replay without a window, returns an error at the first saved checksum that doesn't match
+    pub fn verify(&self) -> Result<(), String> {
+        let mut world = game::create_headless(self.seed);
+        let mut dispatcher = game::add_systems(DispatcherBuilder::new()).build();
+        for tick in 0..self.ticks() {
+            game::apply_inputs(&mut world, self.inputs(tick).unwrap());
+            dispatcher.dispatch(&world.res);
+            world.maintain();
+            if let Some(expected) = self.checksum(tick + 1) {
+                let checksum = game::checksum(&world);
+                if checksum != expected {
+                    return Err(format!("Diverged by tick {}, the world's checksum is {:08x} but {:08x} was recorded", tick + 1, checksum, expected));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//Plays every replay at path, or in it if it's a folder, checking each ends up where it was recorded
+//For using saved replays as regression tests, errors if any of them don't match
+pub fn run_verify(path: &str) -> Result<(), String> {
+    let mut paths = Vec::new();
+    if std::path::Path::new(path).is_dir() {
+        let entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path, e))?;
+        for entry in entries {
+            let entry_path = entry.map_err(|e| e.to_string())?.path();
+            if entry_path.extension().is_some_and(|extension| extension == EXTENSION) {
+                paths.push(entry_path.to_string_lossy().to_string());
+            }
+        }
+        paths.sort();
+    } else {
+        paths.push(path.to_string());
+    }
+    if paths.is_empty() {
+        return Err(format!("No replays in {}", path));
+    }
+
+    let mut failed = 0;
+    for path in paths.iter() {
+        let result = Replay::load(path).and_then(|replay| replay.verify().map(|_| replay.ticks()).map_err(|e| format!("{}: {}", path, e)));
+        match result {
+            Err(e) => {
+                println!("FAIL {}", e);
+                failed += 1;
+            },
+            Ok(ticks) => {
+                println!("ok   {}: {} ticks", path, ticks);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} replays failed", failed, paths.len()));
+    }
+    Ok(())
+}
+
+//Feeds a replay's inputs to the game in place of the controllers, at an adjustable speed
+pub struct Playback {
+    replay: Replay,
+    //The next tick to run
+    tick: u32,
+    speed: usize,
+    step_requested: bool,
+    //First tick the world was found not to match the recording
+    diverged: Option<u32>
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Playback {
+        Playback {
+            replay,
+            tick: 0,
+            speed: NORMAL_SPEED,
+            step_requested: false,
+            diverged: None
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.replay.seed
+    }
+
+    pub fn speed(&self) -> f32 {
+        SPEEDS[self.speed]
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = self.speed.saturating_sub(1);
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tick >= self.replay.ticks()
+    }
+
+    //Runs a single tick while paused
+    pub fn step(&mut self) {
+        self.step_requested = true;
+    }
+
+    //True once after step was called
+    pub fn take_step(&mut self) -> bool {
+        std::mem::replace(&mut self.step_requested, false)
+    }
+
+    //Inputs for the next tick, None once the replay has ended
+    pub fn next_inputs(&mut self) -> Option<Vec<PlayerInput>> {
+        let inputs = self.replay.inputs(self.tick)?.to_vec();
+        self.tick += 1;
+        Some(inputs)
+    }
+
+    //Called after each tick, reports the first time the world stops matching the recording
+    pub fn check(&mut self, world: &World) {
+        if self.diverged.is_some() {
+            return;
+        }
+        if let Some(expected) = self.replay.checksum(self.tick) {
+            if game::checksum(world) != expected {
+                println!("Replay diverged from the recording by tick {}", self.tick);
+                self.diverged = Some(self.tick);
+            }
+        }
+    }
+
+    //Shown on the HUD, e.g. "REPLAY 0.5x 1200/6000"
+    pub fn status(&self, paused: bool) -> String {
+        let state = if self.is_finished() { "END".to_string() } else if paused { "PAUSED".to_string() } else { format!("{}x", SPEEDS[self.speed]) };
+        let diverged = if self.diverged.is_some() { " DESYNC" } else { "" };
+        format!("REPLAY {} {}/{}{}", state, self.tick, self.replay.ticks(), diverged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A replay with runs of the same inputs and a change every few ticks, with the checksums a real match would have
+    fn recorded(ticks: u32) -> Replay {
+        let mut replay = Replay::new(42);
+        let mut world = game::create_headless(replay.seed);
+        let mut dispatcher = game::add_systems(DispatcherBuilder::new()).build();
+        for tick in 0..ticks {
+            let inputs = [PlayerInput { axis_y: ((tick / 7) as i16).wrapping_mul(997), ai: false },
+                          PlayerInput { axis_y: 0, ai: tick % 50 < 25 }];
+            game::apply_inputs(&mut world, &inputs);
+            dispatcher.dispatch(&world.res);
+            world.maintain();
+            replay.record(&inputs, || game::checksum(&world));
+        }
+        replay
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = recorded(250);
+        let decoded = Replay::decode(&replay.encode()).unwrap();
+        assert_eq!(decoded.seed, replay.seed);
+        assert_eq!(decoded.inputs, replay.inputs);
+        assert_eq!(decoded.checksums, replay.checksums);
+        assert_eq!(decoded.ticks(), 250);
+        decoded.verify().unwrap();
+    }
+
+    #[test]
+    fn truncated() {
+        let data = recorded(150).encode();
+        for length in 0..data.len() {
+            assert!(Replay::decode(&data[..length]).is_err(), "Decoded the first {} of {} bytes", length, data.len());
+        }
+    }
+
+    #[test]
+    fn wrong_magic() {
+        let mut data = recorded(10).encode();
+        data[0] ^= 0xff;
+        assert_eq!(Replay::decode(&data).err().unwrap(), "Not a replay");
+    }
+
+    //Header of an empty replay, with the run count left for the test to write
+    fn header() -> Vec<u8> {
+        let mut data = Replay::new(0).encode();
+        data.truncate(data.len() - 8);
+        data
+    }
+
+    #[test]
+    fn huge_run() {
+        let mut data = header();
+        data.write_u32::<LittleEndian>(2).unwrap();
+        for _ in 0..2 {
+            data.write_u32::<LittleEndian>(u32::MAX).unwrap();
+            data.extend_from_slice(&[0; PLAYER_COUNT * 3]);
+        }
+        data.write_u32::<LittleEndian>(0).unwrap();
+        assert!(Replay::decode(&data).is_err());
+    }
+
+    #[test]
+    fn huge_checksum_count() {
+        let mut data = header();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(u32::MAX).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        assert!(Replay::decode(&data).is_err());
+    }
+
+    #[test]
+    fn ai_flag() {
+        let mut data = header();
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.write_u32::<LittleEndian>(1).unwrap();
+        for _ in 0..PLAYER_COUNT {
+            data.write_i16::<LittleEndian>(0).unwrap();
+            //Bits other than INPUT_AI are ignored, so they're free for later versions
+            data.write_u8(INPUT_AI | 0x80).unwrap();
+        }
+        data.write_u32::<LittleEndian>(0).unwrap();
+        let replay = Replay::decode(&data).unwrap();
+        assert!(replay.inputs(0).unwrap().iter().all(|input| input.ai));
+    }
+
+    #[test]
+    fn verify_catches_changed_checksum() {
+        let mut replay = recorded(200);
+        replay.verify().unwrap();
+        replay.checksums[1] ^= 1;
+        let error = replay.verify().err().unwrap();
+        assert!(error.starts_with("Diverged by tick 200"), "{}", error);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::VecDeque;
+
+use specs::{World, Dispatcher};
+
+use crate::events::GameEvent;
+use crate::game::{self, PlayerInput, Snapshot, Resimulating, TickEvents, PLAYER_COUNT};
+use crate::netplay::NetSession;
+use crate::replay::Replay;
+
+//A tick that ran before the other player's input for it arrived
+struct Frame {
+    tick: u32,
+    //The world before the tick ran
+    snapshot: Snapshot,
+    //What the other player was guessed to have pressed
+    remote_input: PlayerInput,
+    //The world after the tick ran
+    checksum: u32,
+    //What happened during the tick, replaced if it runs again
+    events: Vec<GameEvent>
+}
+
+//Runs an online match without waiting for the other player, GGPO style
+//Their inputs are predicted, and when the real ones arrive and differ the world is put back and the ticks run again
+pub struct Rollback {
+    pub session: NetSession,
+    //Every tick from confirmed_ticks on, oldest first
+    frames: VecDeque<Frame>,
+    //The next tick to run
+    tick: u32,
+    //Ticks before this ran with the other player's actual inputs
+    confirmed_ticks: u32,
+    //The world before the newest confirmed tick ran, for showing spectators
+    confirmed_state: Option<Snapshot>,
+    pub rollbacks: u32,
+    //Ticks are recorded once they're confirmed, predicted ones could still change
+    pub replay: Option<Replay>,
+    //Events from confirmed ticks, with the number of ticks run after they happened, collected while Some
+    pub confirmed_events: Option<Vec<(u32, GameEvent)>>
+}
+
+impl Rollback {
+    pub fn new(session: NetSession) -> Rollback {
+        let max_rollback = session.max_rollback as usize;
+        Rollback {
+            session,
+            frames: VecDeque::with_capacity(max_rollback + 1),
+            tick: 0,
+            confirmed_ticks: 0,
+            confirmed_state: None,
+            rollbacks: 0,
+            replay: None,
+            confirmed_events: None
+        }
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn confirmed_ticks(&self) -> u32 {
+        self.confirmed_ticks
+    }
+
+    //The newest world that can't be rolled back any more, with the number of ticks it took to get there
+    pub fn confirmed_state(&self) -> Option<(u32, &Snapshot)> {
+        let snapshot = self.confirmed_state.as_ref()?;
+        Some((self.confirmed_ticks - 1, snapshot))
+    }
+
+    //Players keep doing what they were last seen doing
+    fn predict(&self, tick: u32) -> PlayerInput {
+        match self.session.remote_input(tick) {
+            Some(input) => {
+                input
+            },
+            None => {
+                self.session.remote_input_count().checked_sub(1).and_then(|last| self.session.remote_input(last)).unwrap_or_default()
+            }
+        }
+    }
+
+    fn run_tick(&self, world: &mut World, dispatcher: &mut Dispatcher, tick: u32, remote_input: PlayerInput) {
+        let local_player = self.session.local_player();
+        let mut inputs = [PlayerInput::default(); PLAYER_COUNT];
+        inputs[local_player] = self.session.local_input(tick);
+        inputs[1 - local_player] = remote_input;
+        game::apply_inputs(world, &inputs);
+        dispatcher.dispatch(&world.res);
+        world.maintain();
+    }
+
+    //Receives the other player's inputs and runs again from the first tick that was predicted wrong
+    pub fn update(&mut self, world: &mut World, dispatcher: &mut Dispatcher) -> Result<(), String> {
+        self.session.poll()?;
+        let known_ticks = self.session.remote_input_count().min(self.tick);
+
+        let mispredicted = self.frames.iter().position(|frame| {
+            frame.tick < known_ticks && self.session.remote_input(frame.tick) != Some(frame.remote_input)
+        });
+        if let Some(first) = mispredicted {
+            self.rollbacks += 1;
+            game::restore(world, &self.frames[first].snapshot);
+            world.write_resource::<Resimulating>().0 = true;
+            for idx in first..self.frames.len() {
+                let tick = self.frames[idx].tick;
+                let remote_input = self.predict(tick);
+                if idx != first {
+                    self.frames[idx].snapshot = game::save(world);
+                }
+                self.run_tick(world, dispatcher, tick, remote_input);
+                let frame = &mut self.frames[idx];
+                frame.remote_input = remote_input;
+                frame.checksum = game::checksum(world);
+                frame.events = world.read_resource::<TickEvents>().0.clone();
+            }
+            world.write_resource::<Resimulating>().0 = false;
+        }
+
+        //Only confirmed ticks are compared with the other player, predicted ones are expected to differ
+        while self.confirmed_ticks < known_ticks {
+            let frame = self.frames.pop_front().unwrap();
+            self.confirmed_ticks += 1;
+            self.session.record_checksum(frame.tick + 1, frame.checksum)?;
+            if let Some(replay) = self.replay.as_mut() {
+                let local_player = self.session.local_player();
+                let mut inputs = [PlayerInput::default(); PLAYER_COUNT];
+                inputs[local_player] = self.session.local_input(frame.tick);
+                inputs[1 - local_player] = frame.remote_input;
+                replay.record(&inputs, || frame.checksum);
+            }
+            if let Some(events) = self.confirmed_events.as_mut() {
+                events.extend(frame.events.iter().map(|event| (frame.tick + 1, *event)));
+            }
+            self.confirmed_state = Some(frame.snapshot);
+        }
+        Ok(())
+    }
+
+    //Runs the next tick, returns false without running it when it's too far ahead of the other player's inputs
+    pub fn advance(&mut self, world: &mut World, dispatcher: &mut Dispatcher, local_input: PlayerInput) -> Result<bool, String> {
+        self.session.add_local_input(self.tick, local_input);
+        self.update(world, dispatcher)?;
+        if self.tick + 1 > self.session.remote_input_count() + self.session.max_rollback {
+            return Ok(false);
+        }
+
+        let remote_input = self.predict(self.tick);
+        let snapshot = game::save(world);
+        self.run_tick(world, dispatcher, self.tick, remote_input);
+        self.frames.push_back(Frame {
+            tick: self.tick,
+            snapshot,
+            remote_input,
+            checksum: game::checksum(world),
+            events: world.read_resource::<TickEvents>().0.clone()
+        });
+        self.tick += 1;
+        Ok(true)
+    }
+}
@@ -0,0 +1,326 @@
+//Dedicated server for online matches, runs each room's simulation and sends its state to the clients playing or watching in it
+//Only uses the simulation modules, so it builds and runs without SDL or Vulkan
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use specs::{World, Dispatcher, DispatcherBuilder};
+use shrev::{EventChannel, ReaderId};
+
+use pong_deluxe::{events, game, discovery, protocol};
+use events::GameEvent;
+use game::{PlayerInput, PLAYER_COUNT, TICK_RATE};
+use discovery::{Announcer, Beacon, GameMode};
+use protocol::{ClientMessage, ServerMessage, MatchState, StateHistory, MAX_PACKET_SIZE, STATE_INTERVAL};
+
+//Clients that send nothing for this long are dropped and their paddle goes back to the AI
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    address: SocketAddr,
+    //None for spectators, who can't affect the match
+    player: Option<usize>,
+    input: PlayerInput,
+    //Inputs older than the newest received are ignored, they may arrive out of order
+    sequence: Option<u32>,
+    //Newest state the client has, states are sent as the changes from it
+    ack: Option<u32>,
+    last_heard: Instant
+}
+
+struct Room {
+    world: World,
+    dispatcher: Dispatcher<'static, 'static>,
+    reader: ReaderId<GameEvent>,
+    clients: Vec<Client>,
+    tick: u32,
+    history: StateHistory
+}
+
+impl Room {
+    fn new(pool: &Arc<rayon::ThreadPool>) -> Room {
+        let mut world = game::create_headless(rand::random());
+        let reader = world.write_resource::<EventChannel<GameEvent>>().register_reader();
+        let dispatcher = game::add_systems(DispatcherBuilder::new()).with_pool(pool.clone()).build();
+        //Nobody has joined yet, so both paddles start out played by the AI
+        game::apply_inputs(&mut world, &[PlayerInput { axis_y: 0, ai: true }; PLAYER_COUNT]);
+        Room {
+            world,
+            dispatcher,
+            reader,
+            clients: Vec::new(),
+            tick: 0,
+            history: StateHistory::new()
+        }
+    }
+
+    fn free_player(&self) -> Option<usize> {
+        (0..PLAYER_COUNT).find(|player| self.clients.iter().all(|client| client.player != Some(*player)))
+    }
+
+    fn spectator_count(&self) -> usize {
+        self.clients.iter().filter(|client| client.player.is_none()).count()
+    }
+
+    fn step(&mut self, socket: &UdpSocket) {
+        //Players without a client are played by the AI
+        let mut inputs = [PlayerInput { axis_y: 0, ai: true }; PLAYER_COUNT];
+        for client in self.clients.iter() {
+            if let Some(player) = client.player {
+                inputs[player] = client.input;
+            }
+        }
+        game::apply_inputs(&mut self.world, &inputs);
+        self.dispatcher.dispatch(&self.world.res);
+        self.world.maintain();
+        self.tick += 1;
+
+        let tick = self.tick;
+        self.history.add_events(self.world.read_resource::<EventChannel<GameEvent>>().read(&mut self.reader).map(|event| (tick, *event)));
+        if !self.tick.is_multiple_of(STATE_INTERVAL) {
+            return;
+        }
+
+        self.history.push(tick, MatchState::capture(&self.world));
+        let spectators = self.spectator_count();
+        for client in self.clients.iter() {
+            if let Some(message) = self.history.message(client.ack, spectators) {
+                let _ = socket.send_to(&message.encode(), client.address);
+            }
+        }
+        //Events are sent with every state until the clients acknowledge them
+        self.history.forget_events(self.clients.iter().map(|client| client.ack));
+    }
+}
+
+//Returns the port to listen on and the name to announce on the local network
+fn parse_args() -> (u16, String) {
+    let mut port = protocol::DEFAULT_SERVER_PORT;
+    let mut name = discovery::default_name();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                match args.next().and_then(|port| port.parse().ok()) {
+                    None => {
+                        println!("--port needs a port number");
+                    },
+                    Some(value) => {
+                        port = value;
+                    }
+                }
+            },
+            "--name" => {
+                match args.next() {
+                    None => {
+                        println!("--name needs a name to announce");
+                    },
+                    Some(value) => {
+                        name = value;
+                    }
+                }
+            },
+            _ => {
+                println!("Ignoring unknown argument {}", arg);
+            }
+        }
+    }
+    (port, name)
+}
+
+//The room a client is in and the player it plays, None if it isn't in any
+fn client_room(rooms: &HashMap<String, Room>, address: SocketAddr) -> Option<(String, Option<usize>)> {
+    rooms.iter().find_map(|(name, room)| room.clients.iter().find(|client| client.address == address).map(|client| (name.clone(), client.player)))
+}
+
+fn handle_message(socket: &UdpSocket, rooms: &mut HashMap<String, Room>, pool: &Arc<rayon::ThreadPool>, from: SocketAddr, message: ClientMessage) {
+    match message {
+        ClientMessage::Join { room: name } => {
+            //Joins are resent until they're answered, so the client may already be in the room
+            let reply = match client_room(rooms, from) {
+                Some((room, _)) if room != name => {
+                    ServerMessage::Rejected {
+                        reason: format!("Already in room {}, leave it first", room)
+                    }
+                },
+                Some((_, None)) => {
+                    ServerMessage::Rejected {
+                        reason: "Already watching a match".to_string()
+                    }
+                },
+                Some((_, Some(player))) => {
+                    ServerMessage::Joined {
+                        player: player as u32
+                    }
+                },
+                None => {
+                    let room = rooms.entry(name.clone()).or_insert_with(|| {
+                        println!("Opened room {}", name);
+                        Room::new(pool)
+                    });
+                    match room.free_player() {
+                        None => {
+                            ServerMessage::Rejected {
+                                reason: format!("Room {} is full", name)
+                            }
+                        },
+                        Some(player) => {
+                            println!("{} joined room {} as player {}", from, name, player + 1);
+                            room.clients.push(Client {
+                                address: from,
+                                player: Some(player),
+                                input: PlayerInput::default(),
+                                sequence: None,
+                                ack: None,
+                                last_heard: Instant::now()
+                            });
+                            ServerMessage::Joined {
+                                player: player as u32
+                            }
+                        }
+                    }
+                }
+            };
+            let _ = socket.send_to(&reply.encode(), from);
+        },
+        ClientMessage::Watch { room: name } => {
+            let reply = match (client_room(rooms, from), rooms.get_mut(&name)) {
+                (Some((room, _)), _) if room != name => {
+                    ServerMessage::Rejected {
+                        reason: format!("Already in room {}, leave it first", room)
+                    }
+                },
+                //Watching an empty room would open it, leaving the AI playing itself for nobody
+                (_, None) => {
+                    ServerMessage::Rejected {
+                        reason: format!("Nobody is playing in room {}", name)
+                    }
+                },
+                (_, Some(room)) => {
+                    if !room.clients.iter().any(|client| client.address == from) {
+                        println!("{} is watching room {}", from, name);
+                        room.clients.push(Client {
+                            address: from,
+                            player: None,
+                            input: PlayerInput::default(),
+                            sequence: None,
+                            ack: None,
+                            last_heard: Instant::now()
+                        });
+                    }
+                    ServerMessage::Watching
+                }
+            };
+            let _ = socket.send_to(&reply.encode(), from);
+        },
+        ClientMessage::Ack { ack } => {
+            if let Some(client) = rooms.values_mut().flat_map(|room| room.clients.iter_mut()).find(|client| client.address == from) {
+                client.last_heard = Instant::now();
+                if ack.is_some() {
+                    client.ack = ack;
+                }
+            }
+        },
+        ClientMessage::Input { sequence, ack, input } => {
+            let client = match rooms.values_mut().flat_map(|room| room.clients.iter_mut()).find(|client| client.address == from) {
+                None => {
+                    return;
+                },
+                Some(client) => {
+                    client
+                }
+            };
+            client.last_heard = Instant::now();
+            if client.sequence.is_some_and(|last| sequence <= last) {
+                return;
+            }
+            client.sequence = Some(sequence);
+            client.input = input;
+            if ack.is_some() {
+                client.ack = ack;
+            }
+        },
+        ClientMessage::Leave => {
+            for (name, room) in rooms.iter_mut() {
+                if room.clients.iter().any(|client| client.address == from) {
+                    println!("{} left room {}", from, name);
+                }
+                room.clients.retain(|client| client.address != from);
+            }
+        }
+    }
+}
+
+fn main() {
+    let (port, name) = parse_args();
+    let socket = match UdpSocket::bind(("0.0.0.0", port)).and_then(|socket| socket.set_nonblocking(true).map(|_| socket)) {
+        Err(e) => {
+            println!("Failed to listen on port {}: {}", port, e);
+            std::process::exit(1);
+        },
+        Ok(socket) => {
+            socket
+        }
+    };
+    println!("Listening on port {}", port);
+    //The server still works without it, players just have to type the address
+    let announcer = match Announcer::start(Beacon::new(&name, GameMode::Server, protocol::PROTOCOL_VERSION, port, 0, 0)) {
+        Err(e) => {
+            println!("Failed to announce on the local network: {}", e);
+            None
+        },
+        Ok(announcer) => {
+            Some(announcer)
+        }
+    };
+
+    //Rooms share one pool, rather than a thread per core for each room
+    let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(num_cpus::get()).build().unwrap());
+    let mut rooms: HashMap<String, Room> = HashMap::new();
+    let tick_rate = Duration::from_secs_f32(TICK_RATE);
+    let mut next_tick = Instant::now();
+    let mut buffer = [0; MAX_PACKET_SIZE];
+
+    loop {
+        while let Ok((size, from)) = socket.recv_from(&mut buffer) {
+            if let Some(message) = ClientMessage::decode(&buffer[..size]) {
+                handle_message(&socket, &mut rooms, &pool, from, message);
+            }
+        }
+
+        //Catches up if ticks were missed, but never runs rooms faster than real time
+        while Instant::now() >= next_tick {
+            for room in rooms.values_mut() {
+                room.step(&socket);
+            }
+            next_tick += tick_rate;
+        }
+
+        for (name, room) in rooms.iter_mut() {
+            room.clients.retain(|client| {
+                let timed_out = client.last_heard.elapsed() > CLIENT_TIMEOUT;
+                if timed_out {
+                    println!("{} timed out in room {}", client.address, name);
+                }
+                !timed_out
+            });
+        }
+        //Spectators alone don't keep a room open
+        rooms.retain(|name, room| {
+            let playing = room.clients.iter().any(|client| client.player.is_some());
+            if !playing {
+                println!("Closed room {}", name);
+            }
+            playing
+        });
+        if let Some(announcer) = announcer.as_ref() {
+            let players = rooms.values().flat_map(|room| room.clients.iter()).filter(|client| client.player.is_some()).count();
+            announcer.set_players(players as u16);
+        }
+
+        //Wakes up at least once a tick, packets that arrive in between wait until then
+        std::thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+    }
+}
@@ -0,0 +1,36 @@
+//GLSL to SPIR-V compilation, shared between build.rs and runtime shader hot reloading
+use naga::back::spv;
+use naga::front::glsl;
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::ShaderStage;
+
+//The stage is taken from the file extension, e.g. shader.vert or shader.frag
+pub fn compile_glsl(file_name: &str, source: &str) -> Result<Vec<u8>, String> {
+    let stage = if file_name.ends_with(".vert") {
+        ShaderStage::Vertex
+    } else if file_name.ends_with(".frag") {
+        ShaderStage::Fragment
+    } else {
+        return Err(format!("{}: unknown shader stage", file_name));
+    };
+
+    let module = glsl::Frontend::default().parse(&glsl::Options::from(stage), source).map_err(|errors| {
+        let messages: Vec<String> = errors.iter().map(|e| {
+            let location = e.meta.location(source);
+            format!("{}:{}:{}: {}", file_name, location.line_number, location.line_position, e.kind)
+        }).collect();
+        messages.join("\n")
+    })?;
+
+    let info = Validator::new(ValidationFlags::all(), Capabilities::PUSH_CONSTANT).validate(&module)
+        .map_err(|e| e.emit_to_string_with_path(source, file_name))?;
+
+    let pipeline_options = spv::PipelineOptions {
+        shader_stage: stage,
+        entry_point: "main".to_string()
+    };
+    let words = spv::write_vec(&module, &info, &spv::Options::default(), Some(&pipeline_options))
+        .map_err(|e| format!("{}: {}", file_name, e))?;
+
+    Ok(words.iter().flat_map(|word| word.to_ne_bytes().to_vec()).collect())
+}
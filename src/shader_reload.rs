@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, Instant};
+
+use crate::render::ShaderCode;
+use crate::shader_compiler::compile_glsl;
+
+//Checking every frame would mean hitting the filesystem hundreds of times a second
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+//Watches the shader sources in the repository and recompiles them when they change
+pub struct ShaderWatcher {
+    dir: PathBuf,
+    last_modified: SystemTime,
+    last_poll: Instant
+}
+
+impl ShaderWatcher {
+    pub fn new() -> ShaderWatcher {
+        let dir = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders"));
+        println!("Watching {} for shader changes", dir.display());
+        let last_modified = ShaderWatcher::newest_modification(&dir);
+        ShaderWatcher {
+            dir,
+            last_modified,
+            last_poll: Instant::now()
+        }
+    }
+
+    fn newest_modification(dir: &PathBuf) -> SystemTime {
+        let entries = match std::fs::read_dir(dir) {
+            Err(_) => {
+                return SystemTime::UNIX_EPOCH;
+            },
+            Ok(entries) => {
+                entries
+            }
+        };
+        entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.metadata().and_then(|metadata| metadata.modified()).ok())
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    //Returns freshly compiled shaders if any source changed since the last successful reload
+    //Compile errors are printed and the current shaders are kept
+    pub fn poll(&mut self) -> Option<ShaderCode> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let modified = ShaderWatcher::newest_modification(&self.dir);
+        if modified <= self.last_modified {
+            return None;
+        }
+        self.last_modified = modified;
+
+        let mut code = ShaderCode::embedded();
+        for (name, spv) in code.stages.iter_mut() {
+            let path = self.dir.join(*name);
+            let source = match std::fs::read_to_string(&path) {
+                Err(e) => {
+                    println!("Failed to read {}: {}", path.display(), e);
+                    return None;
+                },
+                Ok(source) => {
+                    source
+                }
+            };
+            match compile_glsl(name, &source) {
+                Err(e) => {
+                    println!("{}", e);
+                    return None;
+                },
+                Ok(compiled) => {
+                    *spv = compiled;
+                }
+            }
+        }
+
+        println!("Reloaded shaders");
+        Some(code)
+    }
+}
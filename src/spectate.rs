@@ -0,0 +1,105 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{ClientMessage, ServerMessage, MatchState, StateHistory, MAX_PACKET_SIZE, STATE_INTERVAL};
+use crate::rollback::Rollback;
+
+//Spectators that send nothing for this long are forgotten
+const SPECTATOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Spectator {
+    address: SocketAddr,
+    ack: Option<u32>,
+    last_heard: Instant
+}
+
+//Lets people watch a netplay match through the host, using the same states pong-server sends
+//Spectators get their own socket and only see ticks both players' inputs are known for, so they can't affect the match
+pub struct SpectatorHost {
+    socket: UdpSocket,
+    spectators: Vec<Spectator>,
+    history: StateHistory
+}
+
+impl SpectatorHost {
+    pub fn new(port: u16) -> Result<SpectatorHost, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", port)).map_err(|e| format!("Failed to listen for spectators on port {}: {}", port, e))?;
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        println!("Spectators can watch on port {}", port);
+        Ok(SpectatorHost {
+            socket,
+            spectators: Vec::new(),
+            history: StateHistory::new()
+        })
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.len()
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while let Ok((size, from)) = self.socket.recv_from(&mut buffer) {
+            match ClientMessage::decode(&buffer[..size]) {
+                //Any room name works, the host only has the one match
+                Some(ClientMessage::Watch { .. }) => {
+                    if !self.spectators.iter().any(|spectator| spectator.address == from) {
+                        println!("{} is watching", from);
+                        self.spectators.push(Spectator {
+                            address: from,
+                            ack: None,
+                            last_heard: Instant::now()
+                        });
+                    }
+                    let _ = self.socket.send_to(&ServerMessage::Watching.encode(), from);
+                },
+                Some(ClientMessage::Ack { ack }) => {
+                    if let Some(spectator) = self.spectators.iter_mut().find(|spectator| spectator.address == from) {
+                        spectator.last_heard = Instant::now();
+                        if ack.is_some() {
+                            spectator.ack = ack;
+                        }
+                    }
+                },
+                Some(ClientMessage::Join { .. }) => {
+                    let reply = ServerMessage::Rejected {
+                        reason: "This match is already full, it can only be watched".to_string()
+                    };
+                    let _ = self.socket.send_to(&reply.encode(), from);
+                },
+                Some(ClientMessage::Leave) => {
+                    self.spectators.retain(|spectator| spectator.address != from);
+                },
+                _ => {}
+            }
+        }
+        self.spectators.retain(|spectator| spectator.last_heard.elapsed() < SPECTATOR_TIMEOUT);
+    }
+
+    //Called every frame, sends spectators the newest confirmed tick every STATE_INTERVAL ticks
+    pub fn update(&mut self, rollback: &mut Rollback) {
+        self.receive();
+        //Starts collecting the first time, there's nothing confirmed before the first update
+        self.history.add_events(rollback.confirmed_events.get_or_insert_with(Vec::new).drain(..));
+        let (tick, snapshot) = match rollback.confirmed_state() {
+            None => {
+                return;
+            },
+            Some(confirmed) => {
+                confirmed
+            }
+        };
+        if self.history.newest_tick().is_some_and(|newest| tick < newest + STATE_INTERVAL) {
+            return;
+        }
+
+        self.history.push(tick, MatchState::from_snapshot(snapshot));
+        for spectator in self.spectators.iter() {
+            if let Some(message) = self.history.message(spectator.ack, self.spectators.len()) {
+                let _ = self.socket.send_to(&message.encode(), spectator.address);
+            }
+        }
+        //Events are sent with every state until the spectators acknowledge them
+        self.history.forget_events(self.spectators.iter().map(|spectator| spectator.ack));
+    }
+}
@@ -0,0 +1,46 @@
+use specs::{Component, VecStorage};
+use specs_derive::{Component};
+
+//Index into the textures owned by the RenderContext
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TextureHandle(pub usize);
+
+//Textures the entity's RenderComponent, the texture is multiplied by the component's color
+#[derive(Component)]
+#[storage(VecStorage)]
+pub struct Sprite {
+    pub texture: TextureHandle
+}
+
+//Decodes a PNG file into tightly packed RGBA8 pixels, returning (width, height, pixels)
+pub fn load_png(path: &str) -> Result<(u32, u32, Vec<u8>), String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let decoder = png::Decoder::new(file);
+    let (info, mut reader) = decoder.read_info().map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| format!("Failed to decode {}: {}", path, e))?;
+
+    //The decoder expands palettes and strips 16 bit channels, so only 8 bit formats need handling here
+    let pixels = match info.color_type {
+        png::ColorType::RGBA => data,
+        png::ColorType::RGB => data.chunks(3).flat_map(|p| vec![p[0], p[1], p[2], 0xff]).collect(),
+        png::ColorType::GrayscaleAlpha => data.chunks(2).flat_map(|p| vec![p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => data.iter().flat_map(|p| vec![*p, *p, *p, 0xff]).collect(),
+        png::ColorType::Indexed => {
+            return Err(format!("Unexpected indexed color data in {}", path));
+        }
+    };
+
+    Ok((info.width, info.height, pixels))
+}
+
+//Encodes tightly packed RGBA8 pixels into a PNG file
+pub fn save_png(path: &str, width: u32, height: u32, pixels: &[u8]) -> Result<(), String> {
+    use png::HasParameters;
+
+    let file = std::fs::File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    writer.write_image_data(pixels).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
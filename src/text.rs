@@ -0,0 +1,168 @@
+use crate::fy_math::{Vec2, Color};
+use crate::font::{GLYPHS, GLYPH_WIDTH, GLYPH_HEIGHT, FIRST_CHAR, LAST_CHAR};
+
+//Glyphs are laid out in a 16x6 grid, with the last cell left solid for drawing rectangles
+const ATLAS_COLUMNS: u32 = 16;
+const ATLAS_ROWS: u32 = 6;
+const SOLID_CELL: u32 = ATLAS_COLUMNS * ATLAS_ROWS - 1;
+
+pub const ATLAS_WIDTH: u32 = ATLAS_COLUMNS * GLYPH_WIDTH;
+pub const ATLAS_HEIGHT: u32 = ATLAS_ROWS * GLYPH_HEIGHT;
+
+//Maximum number of glyphs and rectangles that can be drawn in one frame
+pub const MAX_QUADS: usize = 4096;
+pub const VERTICES_PER_QUAD: usize = 6;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TextVertex {
+    pub position: Vec2,
+    pub uv: Vec2,
+    pub color: Color
+}
+
+enum QueuedItem {
+    Text {
+        text: String,
+        position: Vec2,
+        scale: f32,
+        color: Color
+    },
+    Rect {
+        position: Vec2,
+        size: Vec2,
+        color: Color
+    }
+}
+
+//Screen space text and rectangles to draw on top of the scene this frame
+//Positions are in pixels from the top left corner of the window
+#[derive(Default)]
+pub struct TextQueue {
+    items: Vec<QueuedItem>
+}
+
+impl TextQueue {
+    pub fn text(&mut self, text: &str, position: Vec2, scale: f32, color: Color) {
+        self.items.push(QueuedItem::Text {
+            text: text.to_string(),
+            position,
+            scale,
+            color
+        });
+    }
+
+    //Queues text horizontally centered around position.x
+    pub fn text_centered(&mut self, text: &str, position: Vec2, scale: f32, color: Color) {
+        let x = position.x - text_width(text, scale) / 2.0;
+        self.text(text, Vec2::new(x, position.y), scale, color);
+    }
+
+    pub fn rect(&mut self, position: Vec2, size: Vec2, color: Color) {
+        self.items.push(QueuedItem::Rect {
+            position,
+            size,
+            color
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    //Converts the queued items into clip space triangles, anything past MAX_QUADS is dropped
+    pub fn build_vertices(&self, screen_width: f32, screen_height: f32, vertices: &mut Vec<TextVertex>) {
+        let to_clip = |p: Vec2| Vec2::new(p.x / screen_width * 2.0 - 1.0, p.y / screen_height * 2.0 - 1.0);
+        let push_quad = |vertices: &mut Vec<TextVertex>, position: Vec2, size: Vec2, cell: u32, color: Color| {
+            if vertices.len() >= MAX_QUADS * VERTICES_PER_QUAD {
+                return;
+            }
+            let (uv_min, uv_max) = cell_uvs(cell);
+            let top_left = to_clip(position);
+            let bot_right = to_clip(Vec2::new(position.x + size.x, position.y + size.y));
+            let corners = [(top_left, uv_min),
+                           (Vec2::new(bot_right.x, top_left.y), Vec2::new(uv_max.x, uv_min.y)),
+                           (bot_right, uv_max),
+                           (top_left, uv_min),
+                           (bot_right, uv_max),
+                           (Vec2::new(top_left.x, bot_right.y), Vec2::new(uv_min.x, uv_max.y))];
+            for (position, uv) in corners.iter() {
+                vertices.push(TextVertex {
+                    position: *position,
+                    uv: *uv,
+                    color
+                });
+            }
+        };
+
+        for item in self.items.iter() {
+            match item {
+                QueuedItem::Text { text, position, scale, color } => {
+                    let glyph_size = Vec2::new(GLYPH_WIDTH as f32 * scale, GLYPH_HEIGHT as f32 * scale);
+                    let mut cursor = *position;
+                    for c in text.chars() {
+                        if c == '\n' {
+                            cursor.x = position.x;
+                            cursor.y += glyph_size.y;
+                            continue;
+                        }
+                        if c != ' ' {
+                            push_quad(vertices, cursor, glyph_size, glyph_cell(c), *color);
+                        }
+                        cursor.x += glyph_size.x;
+                    }
+                },
+                QueuedItem::Rect { position, size, color } => {
+                    push_quad(vertices, *position, *size, SOLID_CELL, *color);
+                }
+            }
+        }
+    }
+}
+
+//Width in pixels of the longest line of text
+pub fn text_width(text: &str, scale: f32) -> f32 {
+    let longest = text.lines().map(|line| line.chars().count()).max().unwrap_or(0);
+    longest as f32 * GLYPH_WIDTH as f32 * scale
+}
+
+fn glyph_cell(c: char) -> u32 {
+    let c = if c.is_ascii() && c as u8 >= FIRST_CHAR && c as u8 <= LAST_CHAR {
+        c as u8
+    } else {
+        b'?'
+    };
+    (c - FIRST_CHAR) as u32
+}
+
+fn cell_uvs(cell: u32) -> (Vec2, Vec2) {
+    let x = (cell % ATLAS_COLUMNS) as f32 / ATLAS_COLUMNS as f32;
+    let y = (cell / ATLAS_COLUMNS) as f32 / ATLAS_ROWS as f32;
+    let min = Vec2::new(x, y);
+    let max = Vec2::new(x + 1.0 / ATLAS_COLUMNS as f32, y + 1.0 / ATLAS_ROWS as f32);
+    (min, max)
+}
+
+//Expands the 1-bit font into a single channel atlas image
+pub fn build_atlas() -> Vec<u8> {
+    let mut pixels = vec![0u8; (ATLAS_WIDTH * ATLAS_HEIGHT) as usize];
+    let mut set_cell = |cell: u32, rows: &[u8; 16]| {
+        let cell_x = (cell % ATLAS_COLUMNS) * GLYPH_WIDTH;
+        let cell_y = (cell / ATLAS_COLUMNS) * GLYPH_HEIGHT;
+        for (y, row) in rows.iter().enumerate() {
+            for x in 0..GLYPH_WIDTH {
+                if row & (0x80 >> x) != 0 {
+                    let idx = (cell_y + y as u32) * ATLAS_WIDTH + cell_x + x;
+                    pixels[idx as usize] = 0xff;
+                }
+            }
+        }
+    };
+
+    for (i, glyph) in GLYPHS.iter().enumerate() {
+        set_cell(i as u32, glyph);
+    }
+    set_cell(SOLID_CELL, &[0xff; 16]);
+
+    pixels
+}
@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant};
+
+//Sleeping is imprecise, so the last bit of each frame is spent spinning
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+//Real time taken by the last frame, as opposed to the fixed simulation step
+#[derive(Default)]
+pub struct FrameTime(pub f32);
+
+pub struct FrameLimiter {
+    frame_time: Option<Duration>,
+    next_frame: Instant
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: Option<u32>) -> FrameLimiter {
+        let frame_time = match max_fps {
+            None | Some(0) => None,
+            Some(fps) => Some(Duration::from_secs(1) / fps)
+        };
+        FrameLimiter {
+            frame_time,
+            next_frame: Instant::now()
+        }
+    }
+
+    //Blocks until the next frame is due, does nothing if no limit is set
+    pub fn wait(&mut self) {
+        let frame_time = match self.frame_time {
+            None => {
+                return;
+            },
+            Some(frame_time) => {
+                frame_time
+            }
+        };
+
+        let now = Instant::now();
+        if now < self.next_frame {
+            let remaining = self.next_frame - now;
+            if remaining > SPIN_THRESHOLD {
+                std::thread::sleep(remaining - SPIN_THRESHOLD);
+            }
+            while Instant::now() < self.next_frame {
+                std::thread::yield_now();
+            }
+            self.next_frame += frame_time;
+        } else {
+            //Running behind, don't try to catch up on missed frames
+            self.next_frame = now + frame_time;
+        }
+    }
+}
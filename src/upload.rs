@@ -0,0 +1,289 @@
+use ash::vk;
+use ash::version::DeviceV1_0;
+use std::collections::VecDeque;
+
+//16MB staging ring shared by every upload
+const STAGING_SIZE: u64 = 16 * 1024 * 1024;
+
+//Uploads larger than this are split into several copies, so a single upload can never need the whole ring
+const MAX_CHUNK_SIZE: u64 = STAGING_SIZE / 4;
+
+//Buffer to image copies need offsets aligned to the texel size, 16 covers every format in use
+const STAGING_ALIGNMENT: u64 = 16;
+
+//Copies recorded into one command buffer and submitted together
+struct Batch {
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    //Bytes of the ring used by this batch, including any skipped when wrapping around
+    size: u64
+}
+
+//Collects uploads into batches that are submitted once per frame and retired with fences
+//The staging buffer is used as a ring, so new uploads can be written while older ones are still in flight
+pub struct UploadQueue {
+    staging_buffer: vk::Buffer,
+    staging_allocation: vk_mem::Allocation,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    head: u64,
+    used: u64,
+    recording: Option<Batch>,
+    in_flight: VecDeque<Batch>,
+    free: Vec<(vk::CommandBuffer, vk::Fence)>
+}
+
+impl UploadQueue {
+    pub fn new(device: &ash::Device, allocator: &vk_mem::Allocator, queue: vk::Queue, queue_family_index: u32) -> UploadQueue {
+        let (staging_buffer, staging_allocation, _) = {
+            let buf_create = vk::BufferCreateInfo::builder()
+                .size(STAGING_SIZE)
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                .build();
+
+            let alloc_create = vk_mem::AllocationCreateInfo {
+                usage: vk_mem::MemoryUsage::CpuOnly,
+                ..Default::default()
+            };
+
+            allocator.create_buffer(&buf_create, &alloc_create).unwrap()
+        };
+
+        let command_pool = {
+            let create_info = vk::CommandPoolCreateInfo::builder()
+                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER | vk::CommandPoolCreateFlags::TRANSIENT)
+                .queue_family_index(queue_family_index);
+            unsafe { device.create_command_pool(&create_info, None).unwrap() }
+        };
+
+        UploadQueue {
+            staging_buffer,
+            staging_allocation,
+            queue,
+            command_pool,
+            head: 0,
+            used: 0,
+            recording: None,
+            in_flight: VecDeque::new(),
+            free: Vec::new()
+        }
+    }
+
+    //Queues a copy of data into the start of dst
+    pub fn upload_buffer(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator, dst: vk::Buffer, data: &[u8]) {
+        let mut dst_offset = 0;
+        for chunk in data.chunks(MAX_CHUNK_SIZE as usize) {
+            let src_offset = self.write_staging(device, allocator, chunk);
+            let regions = [vk::BufferCopy::builder()
+                .src_offset(src_offset)
+                .dst_offset(dst_offset)
+                .size(chunk.len() as u64)
+                .build()];
+            unsafe { device.cmd_copy_buffer(self.command_buffer(), self.staging_buffer, dst, &regions) };
+            dst_offset += chunk.len() as u64;
+        }
+    }
+
+    //Queues a copy of tightly packed pixels into the whole of a 2D image, leaving it ready to be sampled
+    //texel_size is the bytes in each pixel, large images are copied a band of rows at a time
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload_image(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator, image: vk::Image, width: u32, height: u32, texel_size: u32, pixels: &[u8]) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let row_size = width as usize * texel_size as usize;
+        assert!(pixels.len() == row_size * height as usize, "Image is {}x{} but has {} bytes of pixels!", width, height, pixels.len());
+        assert!(row_size as u64 <= MAX_CHUNK_SIZE, "Image rows too large for upload!");
+        let rows_per_chunk = (MAX_CHUNK_SIZE as usize / row_size) as u32;
+
+        let subresource_range = vk::ImageSubresourceRange::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1)
+            .build();
+
+        //The image has to be in TRANSFER_DST layout for the copy, then SHADER_READ_ONLY for sampling
+        let to_transfer = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .build()];
+        unsafe { device.cmd_pipeline_barrier(self.command_buffer_for(device), vk::PipelineStageFlags::TOP_OF_PIPE, vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[], &[], &to_transfer) };
+
+        let mut first_row = 0;
+        while first_row < height {
+            let rows = rows_per_chunk.min(height - first_row);
+            let start = first_row as usize * row_size;
+            let end = start + rows as usize * row_size;
+            let src_offset = self.write_staging(device, allocator, &pixels[start..end]);
+
+            let copy_region = [vk::BufferImageCopy::builder()
+                .buffer_offset(src_offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(vk::ImageSubresourceLayers::builder()
+                                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                                    .mip_level(0)
+                                    .base_array_layer(0)
+                                    .layer_count(1)
+                                    .build())
+                .image_offset(vk::Offset3D::builder().x(0).y(first_row as i32).z(0).build())
+                .image_extent(vk::Extent3D::builder().width(width).height(rows).depth(1).build())
+                .build()];
+            unsafe { device.cmd_copy_buffer_to_image(self.command_buffer(), self.staging_buffer, image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &copy_region) };
+            first_row += rows;
+        }
+
+        let to_shader_read = [vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(subresource_range)
+            .build()];
+        unsafe { device.cmd_pipeline_barrier(self.command_buffer(), vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::FRAGMENT_SHADER, vk::DependencyFlags::empty(), &[], &[], &to_shader_read) };
+    }
+
+    //Submits everything queued since the last flush, anything submitted to the queue afterwards sees the uploaded data
+    pub fn flush(&mut self, device: &ash::Device) {
+        let batch = match self.recording.take() {
+            None => {
+                return;
+            },
+            Some(batch) => {
+                batch
+            }
+        };
+
+        //Make the copies visible to every later vertex, index and shader read on the queue
+        let barrier = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ | vk::AccessFlags::SHADER_READ)
+            .build()];
+
+        let cmd_bfrs = [batch.command_buffer];
+        let submits = [vk::SubmitInfo::builder()
+            .command_buffers(&cmd_bfrs)
+            .build()];
+
+        unsafe {
+            device.cmd_pipeline_barrier(batch.command_buffer, vk::PipelineStageFlags::TRANSFER, vk::PipelineStageFlags::VERTEX_INPUT | vk::PipelineStageFlags::FRAGMENT_SHADER, vk::DependencyFlags::empty(), &barrier, &[], &[]);
+            device.end_command_buffer(batch.command_buffer).unwrap();
+            device.queue_submit(self.queue, &submits, batch.fence).unwrap();
+        }
+        self.in_flight.push_back(batch);
+    }
+
+    //Frees the staging space of every batch the GPU has finished with, never blocks
+    pub fn collect(&mut self, device: &ash::Device) {
+        while let Some(batch) = self.in_flight.front() {
+            if unsafe { device.get_fence_status(batch.fence) }.is_err() {
+                break;
+            }
+            self.retire_oldest(device);
+        }
+    }
+
+    fn retire_oldest(&mut self, device: &ash::Device) {
+        let batch = self.in_flight.pop_front().unwrap();
+        unsafe {
+            device.wait_for_fences(&[batch.fence], true, u64::MAX).unwrap();
+            device.reset_fences(&[batch.fence]).unwrap();
+            device.reset_command_buffer(batch.command_buffer, vk::CommandBufferResetFlags::empty()).unwrap();
+        }
+        self.used -= batch.size;
+        self.free.push((batch.command_buffer, batch.fence));
+    }
+
+    //Copies data into the ring and returns its offset in the staging buffer
+    //Only waits on the GPU if the ring is full of uploads that haven't finished yet
+    fn write_staging(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator, data: &[u8]) -> vk::DeviceSize {
+        let size = (data.len() as u64).div_ceil(STAGING_ALIGNMENT) * STAGING_ALIGNMENT;
+        if self.used == 0 {
+            self.head = 0;
+        }
+        loop {
+            //An upload can't wrap around the end of the ring, so the space left at the end is skipped
+            let skipped = if self.head + size > STAGING_SIZE { STAGING_SIZE - self.head } else { 0 };
+            if self.used + skipped + size <= STAGING_SIZE {
+                let offset = (self.head + skipped) % STAGING_SIZE;
+                self.head = offset + size;
+                self.used += skipped + size;
+                self.command_buffer_for(device);
+                self.recording.as_mut().unwrap().size += skipped + size;
+
+                let data_ptr = allocator.map_memory(&self.staging_allocation).unwrap();
+                let dest = unsafe { core::slice::from_raw_parts_mut(data_ptr.offset(offset as isize), data.len()) };
+                dest.copy_from_slice(data);
+                allocator.unmap_memory(&self.staging_allocation).unwrap();
+                return offset;
+            }
+
+            if self.in_flight.is_empty() {
+                self.flush(device);
+            }
+            self.retire_oldest(device);
+        }
+    }
+
+    //The command buffer currently being recorded, starting a new batch if needed
+    fn command_buffer_for(&mut self, device: &ash::Device) -> vk::CommandBuffer {
+        if self.recording.is_none() {
+            let (command_buffer, fence) = match self.free.pop() {
+                Some(free) => free,
+                None => {
+                    let alloc_info = vk::CommandBufferAllocateInfo::builder()
+                        .command_pool(self.command_pool)
+                        .level(vk::CommandBufferLevel::PRIMARY)
+                        .command_buffer_count(1);
+                    let command_buffer = unsafe { device.allocate_command_buffers(&alloc_info).unwrap()[0] };
+                    let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::builder().build(), None).unwrap() };
+                    (command_buffer, fence)
+                }
+            };
+
+            let begin_info = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT)
+                .build();
+            unsafe { device.begin_command_buffer(command_buffer, &begin_info).unwrap() };
+
+            self.recording = Some(Batch {
+                command_buffer,
+                fence,
+                size: 0
+            });
+        }
+        self.command_buffer()
+    }
+
+    //Waits for every upload in flight, then frees the staging buffer, command buffers and fences
+    pub fn destroy(&mut self, device: &ash::Device, allocator: &vk_mem::Allocator) {
+        //Recorded but never submitted, so there's nothing to wait for
+        if let Some(batch) = self.recording.take() {
+            self.free.push((batch.command_buffer, batch.fence));
+        }
+        while !self.in_flight.is_empty() {
+            self.retire_oldest(device);
+        }
+        for (_, fence) in self.free.drain(..) {
+            unsafe { device.destroy_fence(fence, None) };
+        }
+        //Frees its command buffers too
+        unsafe { device.destroy_command_pool(self.command_pool, None) };
+        allocator.destroy_buffer(self.staging_buffer, &self.staging_allocation).unwrap();
+    }
+
+    fn command_buffer(&self) -> vk::CommandBuffer {
+        self.recording.as_ref().unwrap().command_buffer
+    }
+}